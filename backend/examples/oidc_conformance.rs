@@ -0,0 +1,353 @@
+//! Conformance-style checks of our OpenID Connect provider, run as a client would.
+//!
+//! It reads discovery, runs the authorization code flow with PKCE and a nonce,
+//! verifies the ID token against the JWKS and calls userinfo. The consent step
+//! is approved through the API with the access token of a signed-in user.
+//!
+//!     OIDC_CLIENT_ID=wiki OIDC_CLIENT_SECRET=... OIDC_USER_TOKEN=... \
+//!         cargo run --example oidc_conformance
+//!
+//! The client must be allowed the `openid` and `email` scopes, and the backend
+//! must sign with an RS256 or EdDSA key. Set `OIDC_ISSUER` (default
+//! `http://localhost:8000`) to the backend's `JWT_ISSUER`, which should be its
+//! `PUBLIC_URL`, and `OIDC_REDIRECT_URI` if the client has several.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use reqwest::{header, redirect::Policy, Client, StatusCode, Url};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn check(&mut self, name: &str, ok: bool) {
+        if ok {
+            println!("PASS  {}", name);
+        } else {
+            println!("FAIL  {}", name);
+            self.failed += 1;
+        }
+    }
+}
+
+struct Settings {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: Option<String>,
+    user_token: String,
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Walk the browser through authorize and the consent API. Returns the
+/// redirect back to the client.
+async fn authorize(
+    http: &Client,
+    discovery: &Value,
+    settings: &Settings,
+    scope: &str,
+    state: &str,
+    nonce: Option<&str>,
+    code_verifier: &str,
+) -> anyhow::Result<Url> {
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    let mut url = Url::parse(discovery["authorization_endpoint"].as_str().unwrap_or_default())?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &settings.client_id)
+        .append_pair("scope", scope)
+        .append_pair("state", state)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+    if let Some(redirect_uri) = &settings.redirect_uri {
+        url.query_pairs_mut().append_pair("redirect_uri", redirect_uri);
+    }
+    if let Some(nonce) = nonce {
+        url.query_pairs_mut().append_pair("nonce", nonce);
+    }
+
+    let response = http.get(url).send().await?;
+    let consent_url = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("authorize did not redirect ({})", response.status()))?;
+    let request_id = query_param(&Url::parse(consent_url)?, "request_id")
+        .ok_or_else(|| anyhow::anyhow!("authorize redirected to {}", consent_url))?;
+
+    let decision: Value = http
+        .post(format!("{}/api/oauth/requests/{}", settings.issuer, request_id))
+        .bearer_auth(&settings.user_token)
+        .json(&serde_json::json!({ "approve": true }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(Url::parse(decision["redirect_to"].as_str().unwrap_or_default())?)
+}
+
+async fn token_request(
+    http: &Client,
+    discovery: &Value,
+    settings: &Settings,
+    form: &[(&str, &str)],
+) -> anyhow::Result<(StatusCode, Value)> {
+    let mut form: Vec<(&str, &str)> = form.to_vec();
+    form.push(("client_id", &settings.client_id));
+    if let Some(secret) = &settings.client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let response = http
+        .post(discovery["token_endpoint"].as_str().unwrap_or_default())
+        .form(&form)
+        .send()
+        .await?;
+
+    Ok((response.status(), response.json().await?))
+}
+
+/// Exchange the code from a redirect back to the client
+async fn redeem(
+    http: &Client,
+    discovery: &Value,
+    settings: &Settings,
+    redirect: &Url,
+    code_verifier: &str,
+) -> anyhow::Result<(StatusCode, Value)> {
+    let code = query_param(redirect, "code").unwrap_or_default();
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(redirect_uri) = &settings.redirect_uri {
+        form.push(("redirect_uri", redirect_uri));
+    }
+
+    token_request(http, discovery, settings, &form).await
+}
+
+fn verify_id_token(
+    id_token: &str,
+    jwks: &JwkSet,
+    settings: &Settings,
+) -> anyhow::Result<serde_json::Map<String, Value>> {
+    let header = decode_header(id_token)?;
+    let kid = header.kid.ok_or_else(|| anyhow::anyhow!("ID token has no kid"))?;
+    let jwk = jwks
+        .find(&kid)
+        .ok_or_else(|| anyhow::anyhow!("kid {} is not in the JWKS", kid))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&settings.issuer]);
+    validation.set_audience(&[&settings.client_id]);
+    validation.set_required_spec_claims(&["iss", "sub", "aud", "exp", "iat"]);
+
+    Ok(decode(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims)
+}
+
+async fn userinfo(http: &Client, discovery: &Value, access_token: Option<&str>) -> anyhow::Result<reqwest::Response> {
+    let mut request = http.get(discovery["userinfo_endpoint"].as_str().unwrap_or_default());
+    if let Some(token) = access_token {
+        request = request.bearer_auth(token);
+    }
+    Ok(request.send().await?)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let settings = Settings {
+        issuer: std::env::var("OIDC_ISSUER").unwrap_or_else(|_| "http://localhost:8000".to_string()),
+        client_id: std::env::var("OIDC_CLIENT_ID")?,
+        client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+        redirect_uri: std::env::var("OIDC_REDIRECT_URI").ok(),
+        user_token: std::env::var("OIDC_USER_TOKEN")?,
+    };
+    let http = Client::builder().redirect(Policy::none()).build()?;
+    let mut checks = Checks { failed: 0 };
+
+    // Discovery
+    let discovery: Value = http
+        .get(format!("{}/.well-known/openid-configuration", settings.issuer))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let listed = |field: &str, value: &str| {
+        discovery[field]
+            .as_array()
+            .is_some_and(|values| values.iter().any(|v| v == value))
+    };
+
+    checks.check("discovery issuer is the issuer URL", discovery["issuer"] == settings.issuer.as_str());
+    for field in [
+        "authorization_endpoint",
+        "token_endpoint",
+        "userinfo_endpoint",
+        "jwks_uri",
+    ] {
+        checks.check(&format!("discovery has {}", field), discovery[field].is_string());
+    }
+    checks.check("discovery lists the openid scope", listed("scopes_supported", "openid"));
+    checks.check("discovery lists response_type code", listed("response_types_supported", "code"));
+    checks.check("discovery lists subject type public", listed("subject_types_supported", "public"));
+    checks.check("discovery lists PKCE S256", listed("code_challenge_methods_supported", "S256"));
+    let algorithms: Vec<&str> = discovery["id_token_signing_alg_values_supported"]
+        .as_array()
+        .map(|values| values.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    checks.check(
+        "ID token algorithms are asymmetric",
+        !algorithms.is_empty()
+            && algorithms
+                .iter()
+                .all(|alg| Algorithm::from_str(alg).is_ok_and(|alg| alg != Algorithm::HS256)),
+    );
+
+    let jwks: JwkSet = http
+        .get(discovery["jwks_uri"].as_str().unwrap_or_default())
+        .send()
+        .await?
+        .json()
+        .await?;
+    checks.check(
+        "JWKS keys all have a kid",
+        !jwks.keys.is_empty() && jwks.keys.iter().all(|key| key.common.key_id.is_some()),
+    );
+
+    // Authorization code flow with a nonce
+    let state = random_token();
+    let nonce = random_token();
+    let code_verifier = random_token();
+    let redirect = authorize(
+        &http,
+        &discovery,
+        &settings,
+        "openid email",
+        &state,
+        Some(&nonce),
+        &code_verifier,
+    )
+    .await?;
+    checks.check("redirect carries our state", query_param(&redirect, "state").as_deref() == Some(&state));
+    checks.check(
+        "redirect carries the issuer",
+        query_param(&redirect, "iss").as_deref() == Some(&settings.issuer),
+    );
+    let (status, tokens) = redeem(&http, &discovery, &settings, &redirect, &code_verifier).await?;
+    checks.check("code exchange succeeds", status == StatusCode::OK);
+    checks.check("token type is Bearer", tokens["token_type"] == "Bearer");
+
+    let id_token = tokens["id_token"].as_str().unwrap_or_default();
+    let claims = verify_id_token(id_token, &jwks, &settings);
+    checks.check("ID token verifies against the JWKS", claims.is_ok());
+    let claims = claims.unwrap_or_default();
+    checks.check("ID token echoes the nonce", claims.get("nonce") == Some(&Value::from(nonce)));
+    let iat = claims.get("iat").and_then(Value::as_i64).unwrap_or_default();
+    let auth_time = claims.get("auth_time").and_then(Value::as_i64);
+    checks.check(
+        "auth_time is not after iat",
+        auth_time.is_some_and(|auth_time| auth_time <= iat),
+    );
+    checks.check("iat is not in the future", iat <= Utc::now().timestamp() + 60);
+    checks.check("ID token has email", claims.get("email").is_some_and(Value::is_string));
+    checks.check(
+        "ID token has a boolean email_verified",
+        claims.get("email_verified").is_some_and(Value::is_boolean),
+    );
+
+    let (status, replay) = redeem(&http, &discovery, &settings, &redirect, &code_verifier).await?;
+    checks.check(
+        "a code can't be redeemed twice",
+        status == StatusCode::BAD_REQUEST && replay["error"] == "invalid_grant",
+    );
+
+    // Userinfo
+    let access_token = tokens["access_token"].as_str().unwrap_or_default();
+    let response = userinfo(&http, &discovery, Some(access_token)).await?;
+    checks.check("userinfo accepts the access token", response.status() == StatusCode::OK);
+    let info: Value = response.json().await?;
+    checks.check("userinfo sub matches the ID token", claims.get("sub") == Some(&info["sub"]));
+    checks.check("userinfo has email", info["email"] == claims.get("email").cloned().unwrap_or_default());
+
+    let response = http
+        .post(discovery["userinfo_endpoint"].as_str().unwrap_or_default())
+        .bearer_auth(access_token)
+        .send()
+        .await?;
+    checks.check("userinfo answers POST", response.status() == StatusCode::OK);
+
+    let response = userinfo(&http, &discovery, None).await?;
+    checks.check(
+        "userinfo without a token is 401 with WWW-Authenticate",
+        response.status() == StatusCode::UNAUTHORIZED
+            && response.headers().contains_key(header::WWW_AUTHENTICATE),
+    );
+    let response = userinfo(&http, &discovery, Some(id_token)).await?;
+    checks.check("userinfo refuses an ID token", response.status() == StatusCode::UNAUTHORIZED);
+
+    // Refresh keeps the session usable
+    let refresh_token = tokens["refresh_token"].as_str().unwrap_or_default();
+    let (status, refreshed) = token_request(
+        &http,
+        &discovery,
+        &settings,
+        &[("grant_type", "refresh_token"), ("refresh_token", refresh_token)],
+    )
+    .await?;
+    checks.check("refresh succeeds", status == StatusCode::OK);
+    let response = userinfo(&http, &discovery, refreshed["access_token"].as_str()).await?;
+    checks.check("userinfo accepts the refreshed token", response.status() == StatusCode::OK);
+
+    // Without a nonce or the email scope
+    let code_verifier = random_token();
+    let redirect = authorize(&http, &discovery, &settings, "openid", &state, None, &code_verifier).await?;
+    let (_, tokens) = redeem(&http, &discovery, &settings, &redirect, &code_verifier).await?;
+    let claims = verify_id_token(tokens["id_token"].as_str().unwrap_or_default(), &jwks, &settings)
+        .unwrap_or_default();
+    checks.check("no nonce requested, none returned", !claims.contains_key("nonce"));
+    checks.check("no email scope, no email claim", !claims.contains_key("email"));
+
+    let info: Value = userinfo(&http, &discovery, tokens["access_token"].as_str())
+        .await?
+        .json()
+        .await?;
+    checks.check("userinfo without email scope has only sub", info.as_object().is_some_and(|o| o.len() == 1));
+
+    // A plain OAuth grant gets no ID token and can't use userinfo
+    let code_verifier = random_token();
+    let redirect = authorize(&http, &discovery, &settings, "email", &state, None, &code_verifier).await?;
+    let (_, tokens) = redeem(&http, &discovery, &settings, &redirect, &code_verifier).await?;
+    checks.check("no openid scope, no ID token", tokens.get("id_token").is_none());
+    let response = userinfo(&http, &discovery, tokens["access_token"].as_str()).await?;
+    checks.check("userinfo needs the openid scope", response.status() == StatusCode::FORBIDDEN);
+
+    if checks.failed > 0 {
+        anyhow::bail!("{} check(s) failed", checks.failed);
+    }
+    println!("All checks passed");
+
+    Ok(())
+}
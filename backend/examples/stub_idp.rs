@@ -0,0 +1,40 @@
+//! Stub OpenID Connect provider for exercising `/auth/oauth/{provider}/*` locally.
+//!
+//! It serves discovery, a JWKS and Ed25519-signed ID tokens, and checks PKCE
+//! and the redirect URI the way a real IdP does. There is no login page: every
+//! authorization signs in as `STUB_IDP_EMAIL`.
+//!
+//!     cargo run --example stub_idp
+//!
+//! Then register it with the backend:
+//!
+//!     OAUTH_PROVIDERS=stub
+//!     OAUTH_STUB_CLIENT_ID=stub-client
+//!     OAUTH_STUB_ISSUER=http://127.0.0.1:8089
+//!
+//! Set `STUB_IDP_EMAIL_VERIFIED=false` to check that unverified emails are refused.
+
+#[path = "../src/stub_idp.rs"]
+mod stub_idp;
+
+use stub_idp::Stub;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let port = std::env::var("STUB_IDP_PORT").unwrap_or_else(|_| "8089".to_string());
+
+    let stub = Stub::new(
+        format!("http://127.0.0.1:{}", port),
+        std::env::var("STUB_IDP_CLIENT_ID").unwrap_or_else(|_| "stub-client".to_string()),
+        std::env::var("STUB_IDP_EMAIL").unwrap_or_else(|_| "stub.user@example.com".to_string()),
+        std::env::var("STUB_IDP_EMAIL_VERIFIED")
+            .map(|v| v != "false")
+            .unwrap_or(true),
+    )?;
+
+    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+    println!("Stub IdP listening on http://127.0.0.1:{}", port);
+    axum::serve(listener, stub_idp::router(stub)).await?;
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+-- Create JWT signing key rotation state, shared by every instance.
+-- status: 'active' (signs and verifies), 'verify' (verifies only), 'retired'
+CREATE TABLE IF NOT EXISTS jwt_signing_keys (
+    kid VARCHAR(255) PRIMARY KEY,
+    algorithm VARCHAR(10) NOT NULL,
+    private_key_path TEXT, -- NULL means the shared JWT_SECRET (HS256)
+    status VARCHAR(20) NOT NULL DEFAULT 'verify',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    activated_at TIMESTAMPTZ,
+    deactivated_at TIMESTAMPTZ,
+    retired_at TIMESTAMPTZ
+);
+
+-- Only one key may sign at a time
+CREATE UNIQUE INDEX IF NOT EXISTS idx_jwt_signing_keys_single_active
+    ON jwt_signing_keys(status) WHERE status = 'active';
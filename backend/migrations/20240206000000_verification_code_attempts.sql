@@ -0,0 +1,21 @@
+-- Store verification codes hashed instead of in plaintext. The app keys the hash with
+-- JWT_SECRET (HMAC-SHA256, hex), which SQL can't reproduce: existing codes are hashed here
+-- only to get rid of the plaintext, and stop matching (they expire within minutes anyway).
+-- The table predates our migrations, so make sure the column fits a 64-character hash.
+ALTER TABLE verification_code ALTER COLUMN code TYPE VARCHAR(64);
+
+-- Hashes are exactly 64 characters and codes never are, so re-running this leaves hashed rows alone
+UPDATE verification_code SET code = encode(sha256(convert_to(code, 'UTF8')), 'hex')
+WHERE length(code) <> 64;
+
+CREATE INDEX IF NOT EXISTS idx_verification_code_lookup
+    ON verification_code(user_id, code_type, code);
+
+-- Create verification attempts table (wrong guesses per user and code type)
+CREATE TABLE IF NOT EXISTS verification_attempts (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_type VARCHAR(50) NOT NULL,
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    last_failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, code_type)
+);
@@ -0,0 +1,26 @@
+-- Create OAuth clients table (apps that delegate sign-in to this service)
+CREATE TABLE IF NOT EXISTS oauth_clients (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    client_id VARCHAR(100) UNIQUE NOT NULL,
+    -- Argon2 hash of the secret; NULL for public clients, which rely on PKCE alone
+    client_secret_hash VARCHAR(255),
+    name VARCHAR(255) NOT NULL,
+    redirect_uris TEXT[] NOT NULL,
+    allowed_scopes TEXT[] NOT NULL,
+    is_active BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Create OAuth consents table (scopes a user has granted a client)
+CREATE TABLE IF NOT EXISTS oauth_consents (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    client_id VARCHAR(100) NOT NULL REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+    scopes TEXT[] NOT NULL,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, client_id)
+);
+
+-- Refresh tokens issued to a client carry the client and the granted scope
+ALTER TABLE refresh_tokens
+    ADD COLUMN IF NOT EXISTS client_id VARCHAR(100) REFERENCES oauth_clients(client_id) ON DELETE CASCADE,
+    ADD COLUMN IF NOT EXISTS scope TEXT;
@@ -0,0 +1,4 @@
+-- Grants each OAuth client may use; service clients get client_credentials only
+ALTER TABLE oauth_clients
+    ADD COLUMN IF NOT EXISTS grant_types TEXT[] NOT NULL
+        DEFAULT ARRAY['authorization_code', 'refresh_token'];
@@ -0,0 +1,8 @@
+-- Let admins impersonate users
+INSERT INTO permissions (name, description) VALUES
+    ('users:impersonate', 'Act as another user for a short while')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT id, 'users:impersonate' FROM roles WHERE name = 'admin'
+ON CONFLICT DO NOTHING;
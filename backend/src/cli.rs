@@ -0,0 +1,244 @@
+use crate::{
+    config::Config,
+    services::{
+        jwt::JwtService, oauth_clients::OAuthClientStore, oauth_server::DEVICE_CODE_GRANT,
+        roles::RoleService, security_events::SecurityEventService,
+        signing_keys::{SigningKeyStore, KEY_REFRESH_INTERVAL_SECS}, token::TokenService,
+        users::UserService,
+    },
+};
+use redis::aio::ConnectionManager;
+use jsonwebtoken::Algorithm;
+use sqlx::postgres::PgPoolOptions;
+use std::str::FromStr;
+
+const USAGE: &str = "\
+Usage:
+  backend keys list
+  backend keys add <RS256|EdDSA> <private-key-path> [kid]
+  backend keys promote <kid>
+  backend keys retire
+  backend clients list
+  backend clients add <client-id> <name> <redirect-uris> <scopes> [--public]
+  backend clients add-service <client-id> <name> <scopes>
+  backend clients add-device <client-id> <name> <scopes>
+  backend clients remove <client-id>
+  backend roles list
+  backend roles grant <email> <role>
+  backend roles revoke <email> <role>
+
+Redirect URIs and scopes are comma-separated. Service clients use the
+client credentials grant only; device clients (CLI tools) are public and
+use the device flow.";
+
+fn comma_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn print_secret(client_id: &str, secret: Option<String>) {
+    println!("Registered client {}", client_id);
+    match secret {
+        Some(secret) => {
+            println!("Client secret: {}", secret);
+            println!("It is not stored and cannot be shown again");
+        }
+        None => println!("Public client - it has no secret"),
+    }
+}
+
+/// Run an admin command instead of the server
+pub async fn run(config: &Config, args: &[String]) -> anyhow::Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let db_pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&config.database_url)
+        .await?;
+    let store = SigningKeyStore::new(db_pool.clone());
+    let clients = OAuthClientStore::new(db_pool.clone());
+    let roles = RoleService::new(db_pool.clone());
+    let users = UserService::new(db_pool.clone());
+
+    match args.as_slice() {
+        ["keys", "list"] => {
+            let format = |at: Option<chrono::DateTime<chrono::Utc>>| {
+                at.map(|at| at.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            };
+
+            println!(
+                "{:<45} {:<6} {:<8} {:<19}  {:<19}  {:<19}  {:<19}",
+                "KID", "ALG", "STATUS", "CREATED", "ACTIVATED", "DEACTIVATED", "RETIRED"
+            );
+            for key in store.list_keys().await? {
+                println!(
+                    "{:<45} {:<6} {:<8} {:<19}  {:<19}  {:<19}  {:<19}",
+                    key.kid,
+                    key.algorithm,
+                    key.status,
+                    format(Some(key.created_at)),
+                    format(key.activated_at),
+                    format(key.deactivated_at),
+                    format(key.retired_at)
+                );
+            }
+        }
+        ["keys", "add", algorithm, path, rest @ ..] if rest.len() <= 1 => {
+            let algorithm = match Algorithm::from_str(algorithm) {
+                Ok(alg @ (Algorithm::RS256 | Algorithm::EdDSA)) => alg,
+                _ => anyhow::bail!("Algorithm must be RS256 or EdDSA"),
+            };
+            let path = std::fs::canonicalize(path)?.to_string_lossy().into_owned();
+
+            let kid = JwtService::inspect_key_file(
+                algorithm,
+                &path,
+                rest.first().map(|kid| kid.to_string()),
+            )?;
+            store
+                .add_key(&kid, &format!("{:?}", algorithm), &path)
+                .await?;
+
+            println!("Added verification key {}", kid);
+            println!("The key file must exist at {} on every instance before promoting it", path);
+            println!(
+                "It can be promoted after {} seconds, once every instance has loaded it",
+                KEY_REFRESH_INTERVAL_SECS
+            );
+        }
+        ["keys", "promote", kid] => {
+            store.promote_key(kid).await?;
+            println!("Promoted {} - instances switch over within a minute", kid);
+        }
+        ["keys", "retire"] => {
+            // Anything a key signed has expired once the longest-lived token has
+            let max_token_lifetime = config
+                .access_token_expiry
+                .max(config.refresh_token_expiry)
+                .max(config.magic_link_expiry);
+
+            let retired = store.retire_expired_keys(max_token_lifetime).await?;
+            if retired.is_empty() {
+                println!("No keys are ready to retire");
+            }
+            for kid in retired {
+                println!("Retired {}", kid);
+            }
+        }
+        ["clients", "list"] => {
+            println!(
+                "{:<24} {:<24} {:<12} {:<6} {:<19}  {:<30}  REDIRECT URIS",
+                "CLIENT ID", "NAME", "TYPE", "ACTIVE", "CREATED", "SCOPES"
+            );
+            for client in clients.list_clients().await? {
+                let kind = if client.allows_grant("client_credentials") {
+                    "service"
+                } else if client.allows_grant(DEVICE_CODE_GRANT) {
+                    "device"
+                } else if client.client_secret_hash.is_some() {
+                    "confidential"
+                } else {
+                    "public"
+                };
+                println!(
+                    "{:<24} {:<24} {:<12} {:<6} {:<19}  {:<30}  {}",
+                    client.client_id,
+                    client.name,
+                    kind,
+                    client.is_active,
+                    client.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    client.allowed_scopes.join(" "),
+                    client.redirect_uris.join(" ")
+                );
+            }
+        }
+        ["clients", "add", client_id, name, redirect_uris, scopes, rest @ ..]
+            if rest.is_empty() || rest == ["--public"] =>
+        {
+            let redirect_uris = comma_list(redirect_uris);
+            let grant_types = ["authorization_code".to_string(), "refresh_token".to_string()];
+
+            let (client, secret) = clients
+                .create_client(
+                    client_id,
+                    name,
+                    &redirect_uris,
+                    &comma_list(scopes),
+                    &grant_types,
+                    rest.is_empty(),
+                )
+                .await?;
+
+            print_secret(&client.client_id, secret);
+        }
+        ["clients", "add-service", client_id, name, scopes] => {
+            let (client, secret) = clients
+                .create_client(
+                    client_id,
+                    name,
+                    &[],
+                    &comma_list(scopes),
+                    &["client_credentials".to_string()],
+                    true,
+                )
+                .await?;
+
+            print_secret(&client.client_id, secret);
+        }
+        ["clients", "add-device", client_id, name, scopes] => {
+            let grant_types = [DEVICE_CODE_GRANT.to_string(), "refresh_token".to_string()];
+
+            let (client, secret) = clients
+                .create_client(client_id, name, &[], &comma_list(scopes), &grant_types, false)
+                .await?;
+
+            print_secret(&client.client_id, secret);
+        }
+        ["clients", "remove", client_id] => {
+            clients.delete_client(client_id).await?;
+            println!("Removed {} along with its consents and refresh tokens", client_id);
+        }
+        ["roles", "list"] => {
+            println!("{:<20} {:<40} PERMISSIONS", "ROLE", "DESCRIPTION");
+            for role in roles.list_roles().await? {
+                println!(
+                    "{:<20} {:<40} {}",
+                    role.name,
+                    role.description,
+                    role.permissions.join(" ")
+                );
+            }
+        }
+        ["roles", "grant", email, role] => {
+            let user = users.get_user_by_email(email).await?;
+            roles.grant_role(user.id, role).await?;
+            println!("Granted {} to {} - it applies from their next token refresh", role, email);
+        }
+        ["roles", "revoke", email, role] => {
+            let user = users.get_user_by_email(email).await?;
+            if !roles.revoke_role(user.id, role).await? {
+                println!("{} doesn't have {}", email, role);
+                return Ok(());
+            }
+
+            // Access tokens carry roles - end the sessions so none keeps this one
+            let redis = ConnectionManager::new(redis::Client::open(config.redis_url.clone())?).await?;
+            let tokens = TokenService::new(
+                db_pool.clone(),
+                redis,
+                config.clone(),
+                SecurityEventService::new(db_pool.clone()),
+            );
+            let sessions = tokens.revoke_all_sessions(user.id).await?;
+            println!("Revoked {} from {} and ended {} sessions", role, email, sessions);
+        }
+        _ => anyhow::bail!("Unknown command\n\n{}", USAGE),
+    }
+
+    Ok(())
+}
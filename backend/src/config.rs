@@ -8,16 +8,22 @@ pub struct Config {
 
     // JWT configuration
     pub jwt_secret: String,
+    pub jwt_algorithm: jsonwebtoken::Algorithm,
+    pub jwt_private_key_path: Option<String>,
+    pub jwt_key_id: Option<String>,
     pub jwt_issuer: String,
     pub jwt_audience: String,
     pub access_token_expiry: i64,
     pub refresh_token_expiry: i64,
+    pub impersonation_token_expiry: i64, // in seconds
 
     // Server
     pub host: String,
     pub port: u16,
     pub environment: Environment,
     pub frontend_url: String,
+    pub public_url: String, // where clients reach this backend, for OpenID Connect discovery
+    pub trust_proxy_headers: bool, // take the client IP from X-Forwarded-For
 
     // SMTP / Email configuration
     pub smtp_host: String,
@@ -29,6 +35,94 @@ pub struct Config {
 
     // Email verification
     pub verification_code_expiry: i64, // in seconds
+    pub magic_link_expiry: i64,        // in seconds
+    pub invitation_expiry: i64,        // in seconds
+    pub max_verification_attempts: i32, // wrong codes before all codes are invalidated
+
+    // Login lockout
+    pub max_failed_logins: i64,
+    pub max_failed_logins_per_ip: i64,
+    pub login_lockout_duration: i64, // in seconds
+
+    // Two-factor authentication
+    pub mfa_encryption_key: String, // base64-encoded 32-byte AES-256 key
+    pub totp_issuer: String,
+    pub mfa_challenge_expiry: i64, // in seconds
+
+    // WebAuthn (passkeys)
+    pub webauthn_rp_id: String,
+    pub webauthn_rp_name: String,
+    pub webauthn_origin: String,
+
+    // Social login (OAuth 2.0 / OpenID Connect providers)
+    pub oauth_providers: Vec<OAuthProviderConfig>,
+
+    // Rate limiting
+    pub rate_limit_backend: RateLimitBackend,
+    pub rate_limits: RateLimits,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    Redis,
+    Memory, // per process - for tests and single-node development
+}
+
+/// A request budget per window, written as `<requests>/<period>` where the
+/// period is `second`, `minute`, `hour`, `day` or a number of seconds (`3/900`)
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    pub requests: u64,
+    pub window_secs: u64,
+}
+
+/// A sign-in provider. OpenID Connect providers only need an `issuer` - the
+/// endpoints and signing keys come from its discovery document. Plain OAuth 2.0
+/// providers (GitHub) set the endpoints and map claims from the userinfo response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OAuthProviderConfig {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub issuer: Option<String>,
+    pub auth_url: Option<String>,
+    pub token_url: Option<String>,
+    pub userinfo_url: Option<String>,
+    pub emails_url: Option<String>, // the account's addresses with primary/verified flags (GitHub)
+    // Claim mapping
+    pub subject_claim: String,
+    pub email_claim: String,
+    pub email_verified_claim: Option<String>, // None: the provider only hands out verified emails
+}
+
+/// Per-route limits for the public `/auth` routes
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimits {
+    pub register: RateLimit,           // per IP
+    pub verify_email: RateLimit,       // per IP
+    pub resend_code: RateLimit,        // per email
+    pub login: RateLimit,              // per IP
+    pub login_mfa: RateLimit,          // per IP
+    pub unlock: RateLimit,             // per IP
+    pub passkey_login: RateLimit,      // per IP
+    pub refresh: RateLimit,            // per IP
+    pub logout: RateLimit,             // per IP
+    pub forgot_password: RateLimit,    // per email
+    pub reset_password: RateLimit,     // per IP
+    pub magic_link: RateLimit,         // per email
+    pub magic_link_consume: RateLimit, // per IP
+    pub invitation: RateLimit,         // per IP
+    pub oauth: RateLimit,              // per IP
+    pub oauth_authorize: RateLimit,    // per IP
+    pub oauth_token: RateLimit,        // per IP
+    pub oauth_userinfo: RateLimit,     // per IP
+    pub oauth_introspect: RateLimit,   // per IP
+    pub oauth_revoke: RateLimit,       // per IP
+    pub oauth_device: RateLimit,       // per IP
+    pub oauth_device_verify: RateLimit, // per user
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq)]
@@ -69,6 +163,12 @@ impl Config {
             // JWT
             jwt_secret: env::var("JWT_SECRET")
                 .map_err(|_| anyhow::anyhow!("Missing JWT_SECRET"))?,
+            jwt_algorithm: env::var("JWT_ALGORITHM")
+                .unwrap_or_else(|_| "HS256".to_string())
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid JWT_ALGORITHM (use HS256, RS256 or EdDSA)"))?,
+            jwt_private_key_path: env::var("JWT_PRIVATE_KEY_PATH").ok(),
+            jwt_key_id: env::var("JWT_KEY_ID").ok(),
             jwt_issuer: env::var("JWT_ISSUER").unwrap_or_else(|_| "auth-backend".to_string()),
             jwt_audience: env::var("JWT_AUDIENCE").unwrap_or_else(|_| "auth-client".to_string()),
             access_token_expiry: env::var("ACCESS_TOKEN_EXPIRY")
@@ -77,6 +177,9 @@ impl Config {
             refresh_token_expiry: env::var("REFRESH_TOKEN_EXPIRY")
                 .unwrap_or_else(|_| "604800".to_string()) // 7 days
                 .parse()?,
+            impersonation_token_expiry: env::var("IMPERSONATION_TOKEN_EXPIRY")
+                .unwrap_or_else(|_| "600".to_string()) // 10 mins
+                .parse()?,
 
             // Server
             host: env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
@@ -89,6 +192,11 @@ impl Config {
 
             frontend_url: env::var("FRONTEND_URL")
                 .unwrap_or_else(|_| "http://localhost:3000".to_string()),
+            public_url: env::var("PUBLIC_URL")
+                .unwrap_or_else(|_| "http://localhost:8000".to_string()),
+            trust_proxy_headers: env::var("TRUST_PROXY_HEADERS")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()?,
 
             // SMTP config — Mailtrap-friendly defaults
             smtp_host: env::var("SMTP_HOST")
@@ -109,6 +217,76 @@ impl Config {
             verification_code_expiry: env::var("VERIFICATION_CODE_EXPIRY")
                 .unwrap_or_else(|_| "900".to_string()) // 15 minutes
                 .parse()?,
+            magic_link_expiry: env::var("MAGIC_LINK_EXPIRY")
+                .unwrap_or_else(|_| "900".to_string()) // 15 minutes
+                .parse()?,
+            invitation_expiry: env::var("INVITATION_EXPIRY")
+                .unwrap_or_else(|_| "604800".to_string()) // 7 days
+                .parse()?,
+            max_verification_attempts: env::var("MAX_VERIFICATION_ATTEMPTS")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()?,
+
+            // Login lockout
+            max_failed_logins: env::var("MAX_FAILED_LOGINS")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()?,
+            max_failed_logins_per_ip: env::var("MAX_FAILED_LOGINS_PER_IP")
+                .unwrap_or_else(|_| "100".to_string())
+                .parse()?,
+            login_lockout_duration: env::var("LOGIN_LOCKOUT_DURATION")
+                .unwrap_or_else(|_| "900".to_string()) // 15 minutes
+                .parse()?,
+
+            // Two-factor authentication
+            mfa_encryption_key: env::var("MFA_ENCRYPTION_KEY")
+                .map_err(|_| anyhow::anyhow!("Missing MFA_ENCRYPTION_KEY"))?,
+            totp_issuer: env::var("TOTP_ISSUER")
+                .unwrap_or_else(|_| "NeuraCreations".to_string()),
+            mfa_challenge_expiry: env::var("MFA_CHALLENGE_EXPIRY")
+                .unwrap_or_else(|_| "300".to_string()) // 5 minutes
+                .parse()?,
+
+            // WebAuthn - the RP ID must be the frontend's registrable domain
+            webauthn_rp_id: env::var("WEBAUTHN_RP_ID")
+                .unwrap_or_else(|_| "localhost".to_string()),
+            webauthn_rp_name: env::var("WEBAUTHN_RP_NAME")
+                .unwrap_or_else(|_| "NeuraCreations".to_string()),
+            webauthn_origin: env::var("WEBAUTHN_ORIGIN")
+                .or_else(|_| env::var("FRONTEND_URL"))
+                .unwrap_or_else(|_| "http://localhost:3000".to_string()),
+
+            // Social login - OAUTH_PROVIDERS=google,github,... plus OAUTH_<NAME>_* per provider
+            oauth_providers: oauth_providers()?,
+
+            // Rate limiting - each limit can be overridden with RATE_LIMIT_<ROUTE>
+            rate_limit_backend: env::var("RATE_LIMIT_BACKEND")
+                .unwrap_or_else(|_| "redis".to_string())
+                .parse()?,
+            rate_limits: RateLimits {
+                register: rate_limit("RATE_LIMIT_REGISTER", "5/hour")?,
+                verify_email: rate_limit("RATE_LIMIT_VERIFY_EMAIL", "10/minute")?,
+                resend_code: rate_limit("RATE_LIMIT_RESEND_CODE", "1/minute")?,
+                login: rate_limit("RATE_LIMIT_LOGIN", "10/minute")?,
+                login_mfa: rate_limit("RATE_LIMIT_LOGIN_MFA", "10/minute")?,
+                unlock: rate_limit("RATE_LIMIT_UNLOCK", "10/minute")?,
+                passkey_login: rate_limit("RATE_LIMIT_PASSKEY_LOGIN", "20/minute")?,
+                refresh: rate_limit("RATE_LIMIT_REFRESH", "30/minute")?,
+                logout: rate_limit("RATE_LIMIT_LOGOUT", "30/minute")?,
+                forgot_password: rate_limit("RATE_LIMIT_FORGOT_PASSWORD", "3/hour")?,
+                reset_password: rate_limit("RATE_LIMIT_RESET_PASSWORD", "10/minute")?,
+                magic_link: rate_limit("RATE_LIMIT_MAGIC_LINK", "5/hour")?,
+                magic_link_consume: rate_limit("RATE_LIMIT_MAGIC_LINK_CONSUME", "10/minute")?,
+                invitation: rate_limit("RATE_LIMIT_INVITATION", "10/minute")?,
+                oauth: rate_limit("RATE_LIMIT_OAUTH", "20/minute")?,
+                oauth_authorize: rate_limit("RATE_LIMIT_OAUTH_AUTHORIZE", "30/minute")?,
+                oauth_token: rate_limit("RATE_LIMIT_OAUTH_TOKEN", "60/minute")?,
+                oauth_userinfo: rate_limit("RATE_LIMIT_OAUTH_USERINFO", "60/minute")?,
+                oauth_introspect: rate_limit("RATE_LIMIT_OAUTH_INTROSPECT", "600/minute")?,
+                oauth_revoke: rate_limit("RATE_LIMIT_OAUTH_REVOKE", "60/minute")?,
+                oauth_device: rate_limit("RATE_LIMIT_OAUTH_DEVICE", "10/minute")?,
+                oauth_device_verify: rate_limit("RATE_LIMIT_OAUTH_DEVICE_VERIFY", "10/minute")?,
+            },
         })
     }
 
@@ -148,8 +326,281 @@ impl std::str::FromStr for Environment {
     }
 }
 
+impl std::str::FromStr for RateLimitBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "redis" => Ok(RateLimitBackend::Redis),
+            "memory" => Ok(RateLimitBackend::Memory),
+            _ => Err(anyhow::anyhow!("Invalid rate limit backend: {}", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid rate limit '{}' (expected e.g. 10/minute)", s);
+
+        let (requests, period) = s.split_once('/').ok_or_else(invalid)?;
+        let requests: u64 = requests.trim().parse().map_err(|_| invalid())?;
+        let window_secs = match period.trim().to_lowercase().as_str() {
+            "second" | "sec" | "s" => 1,
+            "minute" | "min" | "m" => 60,
+            "hour" | "h" => 3600,
+            "day" | "d" => 86400,
+            secs => secs.parse().map_err(|_| invalid())?,
+        };
+
+        if requests == 0 || window_secs == 0 {
+            return Err(invalid());
+        }
+
+        Ok(RateLimit {
+            requests,
+            window_secs,
+        })
+    }
+}
+
+/// Read a rate limit from the environment, falling back to `default`
+fn rate_limit(var: &str, default: &str) -> Result<RateLimit, anyhow::Error> {
+    env::var(var)
+        .unwrap_or_else(|_| default.to_string())
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{}: {}", var, e))
+}
+
+impl OAuthProviderConfig {
+    /// Defaults for `google`, `github` and `microsoft`; other names get the
+    /// standard OpenID Connect claims and must be given an issuer or endpoints
+    pub(crate) fn preset(name: &str, tenant: Option<String>) -> Self {
+        let mut provider = OAuthProviderConfig {
+            name: name.to_string(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_uri: String::new(),
+            scopes: "openid email profile".to_string(),
+            issuer: None,
+            auth_url: None,
+            token_url: None,
+            userinfo_url: None,
+            emails_url: None,
+            subject_claim: "sub".to_string(),
+            email_claim: "email".to_string(),
+            email_verified_claim: Some("email_verified".to_string()),
+        };
+
+        match name {
+            "google" => {
+                provider.issuer = Some("https://accounts.google.com".to_string());
+            }
+            "github" => {
+                provider.auth_url = Some("https://github.com/login/oauth/authorize".to_string());
+                provider.token_url = Some("https://github.com/login/oauth/access_token".to_string());
+                provider.userinfo_url = Some("https://api.github.com/user".to_string());
+                provider.scopes = "read:user user:email".to_string();
+                provider.emails_url = Some("https://api.github.com/user/emails".to_string());
+                provider.subject_claim = "id".to_string();
+            }
+            "microsoft" => {
+                provider.issuer = Some(format!(
+                    "https://login.microsoftonline.com/{}/v2.0",
+                    tenant.unwrap_or_else(|| "common".to_string())
+                ));
+                // Entra ID emails are unverified unless the tenant owns the domain
+                provider.email_verified_claim = Some("xms_edov".to_string());
+            }
+            _ => {}
+        }
+
+        provider
+    }
+}
+
+/// Load the providers listed in OAUTH_PROVIDERS, each configured through
+/// OAUTH_<NAME>_* variables on top of its preset
+fn oauth_providers() -> Result<Vec<OAuthProviderConfig>, anyhow::Error> {
+    let names = env::var("OAUTH_PROVIDERS").unwrap_or_default();
+    let redirect_base = env::var("OAUTH_REDIRECT_BASE_URL")
+        .or_else(|_| env::var("PUBLIC_URL"))
+        .unwrap_or_else(|_| "http://localhost:8000".to_string());
+
+    names
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let prefix = format!("OAUTH_{}", name.to_uppercase().replace('-', "_"));
+            let var = |key: &str| env::var(format!("{}_{}", prefix, key)).ok();
+
+            let mut provider = OAuthProviderConfig::preset(&name, var("TENANT"));
+            provider.client_id = var("CLIENT_ID")
+                .ok_or_else(|| anyhow::anyhow!("Missing {}_CLIENT_ID", prefix))?;
+            provider.client_secret = var("CLIENT_SECRET").unwrap_or_default();
+            provider.redirect_uri = var("REDIRECT_URI")
+                .unwrap_or_else(|| format!("{}/auth/oauth/{}/callback", redirect_base, name));
+
+            if let Some(scopes) = var("SCOPES") {
+                provider.scopes = scopes;
+            }
+            provider.issuer = var("ISSUER").or(provider.issuer);
+            provider.auth_url = var("AUTH_URL").or(provider.auth_url);
+            provider.token_url = var("TOKEN_URL").or(provider.token_url);
+            provider.userinfo_url = var("USERINFO_URL").or(provider.userinfo_url);
+            provider.emails_url = var("EMAILS_URL").or(provider.emails_url);
+
+            // Claim mapping
+            if let Some(claim) = var("SUBJECT_CLAIM") {
+                provider.subject_claim = claim;
+            }
+            if let Some(claim) = var("EMAIL_CLAIM") {
+                provider.email_claim = claim;
+            }
+            match var("EMAIL_VERIFIED_CLAIM").as_deref() {
+                Some("none") => provider.email_verified_claim = None,
+                Some(claim) => provider.email_verified_claim = Some(claim.to_string()),
+                None => {}
+            }
+
+            let has_endpoints = provider.auth_url.is_some()
+                && provider.token_url.is_some()
+                && provider.userinfo_url.is_some();
+            if provider.issuer.is_none() && !has_endpoints {
+                anyhow::bail!(
+                    "{} needs {}_ISSUER, or {}_AUTH_URL, {}_TOKEN_URL and {}_USERINFO_URL",
+                    name,
+                    prefix,
+                    prefix,
+                    prefix,
+                    prefix
+                );
+            }
+
+            Ok(provider)
+        })
+        .collect()
+}
+
 impl std::fmt::Display for Environment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.as_str())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+impl Config {
+    /// Fixed settings for unit tests - nothing here points at a real service
+    pub(crate) fn for_tests() -> Self {
+        let limit = RateLimit {
+            requests: 100,
+            window_secs: 60,
+        };
+
+        Config {
+            database_url: "postgres://localhost/auth_test".to_string(),
+            redis_url: "redis://127.0.0.1/".to_string(),
+            jwt_secret: "test-secret".to_string(),
+            jwt_algorithm: jsonwebtoken::Algorithm::HS256,
+            jwt_private_key_path: None,
+            jwt_key_id: None,
+            jwt_issuer: "http://localhost:8000".to_string(),
+            jwt_audience: "auth-client".to_string(),
+            access_token_expiry: 900,
+            refresh_token_expiry: 604800,
+            impersonation_token_expiry: 600,
+            host: "127.0.0.1".to_string(),
+            port: 8000,
+            environment: Environment::Development,
+            frontend_url: "http://localhost:3000".to_string(),
+            public_url: "http://localhost:8000".to_string(),
+            trust_proxy_headers: false,
+            smtp_host: "localhost".to_string(),
+            smtp_port: 2525,
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+            smtp_from_email: "noreply@example.com".to_string(),
+            smtp_from_name: "Auth".to_string(),
+            verification_code_expiry: 900,
+            magic_link_expiry: 900,
+            invitation_expiry: 604800,
+            max_verification_attempts: 5,
+            max_failed_logins: 10,
+            max_failed_logins_per_ip: 100,
+            login_lockout_duration: 900,
+            mfa_encryption_key: String::new(),
+            totp_issuer: "Auth".to_string(),
+            mfa_challenge_expiry: 300,
+            webauthn_rp_id: "localhost".to_string(),
+            webauthn_rp_name: "Auth".to_string(),
+            webauthn_origin: "http://localhost:3000".to_string(),
+            oauth_providers: Vec::new(),
+            rate_limit_backend: RateLimitBackend::Memory,
+            rate_limits: RateLimits {
+                register: limit,
+                verify_email: limit,
+                resend_code: limit,
+                login: limit,
+                login_mfa: limit,
+                unlock: limit,
+                passkey_login: limit,
+                refresh: limit,
+                logout: limit,
+                forgot_password: limit,
+                reset_password: limit,
+                magic_link: limit,
+                magic_link_consume: limit,
+                invitation: limit,
+                oauth: limit,
+                oauth_authorize: limit,
+                oauth_token: limit,
+                oauth_userinfo: limit,
+                oauth_introspect: limit,
+                oauth_revoke: limit,
+                oauth_device: limit,
+                oauth_device_verify: limit,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> (u64, u64) {
+        let limit: RateLimit = s.parse().unwrap();
+        (limit.requests, limit.window_secs)
+    }
+
+    #[test]
+    fn rate_limits_parse_named_periods_and_seconds() {
+        assert_eq!(parse("5/hour"), (5, 3600));
+        assert_eq!(parse("10/minute"), (10, 60));
+        assert_eq!(parse("1/min"), (1, 60));
+        assert_eq!(parse("2/s"), (2, 1));
+        assert_eq!(parse("100/day"), (100, 86400));
+        assert_eq!(parse(" 3 / 900 "), (3, 900));
+        assert_eq!(parse("7/Hour"), (7, 3600));
+    }
+
+    #[test]
+    fn rate_limits_reject_malformed_or_empty_budgets() {
+        for bad in [
+            "",
+            "10",
+            "ten/minute",
+            "10/fortnight",
+            "0/minute",
+            "10/0",
+            "-1/minute",
+        ] {
+            assert!(
+                bad.parse::<RateLimit>().is_err(),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+}
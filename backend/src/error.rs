@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -22,6 +22,9 @@ pub enum AppError {
     #[error("Unauthorized")]
     Unauthorized,
 
+    #[error("Forbidden")]
+    Forbidden,
+
     #[error("Invalid token")]
     InvalidToken,
 
@@ -34,6 +37,9 @@ pub enum AppError {
     #[error("Missing refresh token")]
     MissingRefreshToken,
 
+    #[error("Account temporarily locked")]
+    AccountLocked { retry_after: u64 },
+
     // ===== User errors =====
     #[error("User already exists")]
     UserAlreadyExists,
@@ -41,6 +47,9 @@ pub enum AppError {
     #[error("User not found")]
     UserNotFound,
 
+    #[error("Session not found")]
+    SessionNotFound,
+
     // ===== Email verification errors =====
     #[error("Invalid verification code")]
     InvalidVerificationCode,
@@ -51,6 +60,9 @@ pub enum AppError {
     #[error("Verification code already used")]
     VerificationCodeAlreadyUsed,
 
+    #[error("Too many incorrect verification codes")]
+    TooManyVerificationAttempts,
+
     #[error("Email not verified")]
     EmailNotVerified,
 
@@ -60,7 +72,84 @@ pub enum AppError {
     #[error("Failed to send email")]
     EmailSendFailed,
 
+    // ===== Two-factor authentication errors =====
+    #[error("Invalid two-factor code")]
+    InvalidMfaCode,
+
+    #[error("Two-factor authentication already enabled")]
+    MfaAlreadyEnabled,
+
+    #[error("Two-factor authentication not enabled")]
+    MfaNotEnabled,
+
+    // ===== Passkey (WebAuthn) errors =====
+    #[error("Passkey verification failed: {0}")]
+    PasskeyVerificationFailed(String),
+
+    #[error("Passkey not found")]
+    PasskeyNotFound,
+
+    // ===== Social login errors =====
+    #[error("Unknown sign-in provider")]
+    OAuthProviderNotFound,
+
+    #[error("External sign-in failed: {0}")]
+    ExternalAuthFailed(String),
+
+    #[error("Linked account not found")]
+    IdentityNotFound,
+
+    #[error("External account already linked")]
+    IdentityAlreadyLinked,
+
+    #[error("Cannot remove the last way to sign in")]
+    LastLoginMethod,
+
+    // ===== Role errors =====
+    #[error("Role not found")]
+    RoleNotFound,
+
+    #[error("Cannot revoke the last admin")]
+    LastAdmin,
+
+    // ===== Organization errors =====
+    #[error("Organization not found")]
+    OrganizationNotFound,
+
+    #[error("Organization slug already taken")]
+    OrganizationSlugTaken,
+
+    #[error("No active organization")]
+    NoActiveOrganization,
+
+    #[error("Already a member of the organization")]
+    AlreadyOrganizationMember,
+
+    #[error("Invitation not found")]
+    InvitationNotFound,
+
+    #[error("Invalid or expired invitation")]
+    InvalidInvitation,
+
+    #[error("Sign in to accept invitation")]
+    InvitationSignInRequired,
+
+    // ===== Personal access token errors =====
+    #[error("Personal access token not found")]
+    PersonalTokenNotFound,
+
+    // ===== OAuth authorization server errors =====
+    /// An error in RFC 6749 form, e.g. `invalid_grant`
+    #[error("OAuth error {error}: {description}")]
+    OAuth {
+        error: &'static str,
+        description: String,
+    },
+
     // ===== Validation & Request errors =====
+    #[error("Too many requests")]
+    RateLimited { retry_after: u64 },
+
     #[error("Validation error: {0}")]
     Validation(String),
 
@@ -78,6 +167,15 @@ pub enum AppError {
     JwtError(String),
 }
 
+impl AppError {
+    pub fn oauth(error: &'static str, description: impl Into<String>) -> Self {
+        AppError::OAuth {
+            error,
+            description: description.into(),
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
@@ -94,24 +192,112 @@ impl IntoResponse for AppError {
             // ===== Authentication & Authorization errors =====
             AppError::InvalidCredentials => (StatusCode::UNAUTHORIZED, "Invalid credentials"),
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
             AppError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token"),
             AppError::TokenExpired => (StatusCode::UNAUTHORIZED, "Token expired"),
             AppError::TokenRevoked => (StatusCode::UNAUTHORIZED, "Token revoked"),
             AppError::MissingRefreshToken => (StatusCode::UNAUTHORIZED, "Missing refresh token"),
+            AppError::AccountLocked { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many failed login attempts. Try again later.",
+            ),
 
             // ===== User errors =====
             AppError::UserAlreadyExists => (StatusCode::CONFLICT, "User already exists"),
             AppError::UserNotFound => (StatusCode::NOT_FOUND, "User not found"),
+            AppError::SessionNotFound => (StatusCode::NOT_FOUND, "Session not found"),
 
             // ===== Email verification errors =====
             AppError::InvalidVerificationCode => (StatusCode::BAD_REQUEST, "Invalid verification code"),
             AppError::VerificationCodeExpired => (StatusCode::BAD_REQUEST, "Verification code has expired"),
             AppError::VerificationCodeAlreadyUsed => (StatusCode::BAD_REQUEST, "Verification code already used"),
+            AppError::TooManyVerificationAttempts => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many incorrect codes. Please request a new code.",
+            ),
             AppError::EmailNotVerified => (StatusCode::FORBIDDEN, "Email not verified"),
             AppError::EmailAlreadyVerified => (StatusCode::BAD_REQUEST, "Email already verified"),
             AppError::EmailSendFailed => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to send email"),
 
+            // ===== Two-factor authentication errors =====
+            AppError::InvalidMfaCode => (StatusCode::UNAUTHORIZED, "Invalid two-factor code"),
+            AppError::MfaAlreadyEnabled => (StatusCode::CONFLICT, "Two-factor authentication already enabled"),
+            AppError::MfaNotEnabled => (StatusCode::BAD_REQUEST, "Two-factor authentication not enabled"),
+
+            // ===== Passkey (WebAuthn) errors =====
+            AppError::PasskeyVerificationFailed(ref reason) => {
+                tracing::warn!("Passkey verification failed: {}", reason);
+                (StatusCode::UNAUTHORIZED, "Passkey verification failed")
+            }
+            AppError::PasskeyNotFound => (StatusCode::NOT_FOUND, "Passkey not found"),
+
+            // ===== Social login errors =====
+            AppError::OAuthProviderNotFound => (StatusCode::NOT_FOUND, "Unknown sign-in provider"),
+            AppError::ExternalAuthFailed(ref reason) => {
+                tracing::warn!("External sign-in failed: {}", reason);
+                (StatusCode::UNAUTHORIZED, "External sign-in failed")
+            }
+            AppError::IdentityNotFound => (StatusCode::NOT_FOUND, "Linked account not found"),
+            AppError::IdentityAlreadyLinked => (
+                StatusCode::CONFLICT,
+                "This account is already linked to a user",
+            ),
+            AppError::LastLoginMethod => (
+                StatusCode::CONFLICT,
+                "Set a password or add a passkey before removing your last sign-in method",
+            ),
+
+            // ===== Role errors =====
+            AppError::RoleNotFound => (StatusCode::NOT_FOUND, "Role not found"),
+            AppError::LastAdmin => (
+                StatusCode::CONFLICT,
+                "Grant admin to someone else before revoking it from the last admin",
+            ),
+
+            // ===== Organization errors =====
+            AppError::OrganizationNotFound => (StatusCode::NOT_FOUND, "Organization not found"),
+            AppError::OrganizationSlugTaken => {
+                (StatusCode::CONFLICT, "This organization slug is already taken")
+            }
+            AppError::NoActiveOrganization => (
+                StatusCode::BAD_REQUEST,
+                "Switch to an organization first",
+            ),
+            AppError::AlreadyOrganizationMember => {
+                (StatusCode::CONFLICT, "This person is already a member")
+            }
+            AppError::InvitationNotFound => (StatusCode::NOT_FOUND, "Invitation not found"),
+            AppError::InvalidInvitation => (
+                StatusCode::BAD_REQUEST,
+                "This invitation is invalid, expired or already used",
+            ),
+            AppError::InvitationSignInRequired => (
+                StatusCode::UNAUTHORIZED,
+                "An account already uses this email - sign in to it to accept the invitation",
+            ),
+
+            // ===== Personal access token errors =====
+            AppError::PersonalTokenNotFound => {
+                (StatusCode::NOT_FOUND, "Personal access token not found")
+            }
+
+            // ===== OAuth authorization server errors =====
+            AppError::OAuth { error: "invalid_client", .. } => {
+                (StatusCode::UNAUTHORIZED, "invalid_client")
+            }
+            AppError::OAuth { error: "invalid_token", .. } => {
+                (StatusCode::UNAUTHORIZED, "invalid_token")
+            }
+            AppError::OAuth { error: "insufficient_scope", .. } => {
+                (StatusCode::FORBIDDEN, "insufficient_scope")
+            }
+            AppError::OAuth { error, .. } => (StatusCode::BAD_REQUEST, error),
+
             // ===== Validation & Request errors =====
+            AppError::RateLimited { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests. Please slow down.",
+            ),
             AppError::Validation(ref msg) => (StatusCode::BAD_REQUEST, msg.as_str()),
             AppError::BadRequest(ref msg) => (StatusCode::BAD_REQUEST, msg.as_str()),
 
@@ -129,8 +315,31 @@ impl IntoResponse for AppError {
             }
         };
 
-        let body = Json(json!({ "error": error_message }));
-        (status, body).into_response()
+        // OAuth clients expect `error` to be a code, with the text in `error_description`
+        let body = match &self {
+            AppError::OAuth { description, .. } => {
+                Json(json!({ "error": error_message, "error_description": description }))
+            }
+            _ => Json(json!({ "error": error_message })),
+        };
+        let mut response = (status, body).into_response();
+
+        if let AppError::AccountLocked { retry_after } | AppError::RateLimited { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+
+        // Bearer token errors also go in WWW-Authenticate (RFC 6750 section 3)
+        if let AppError::OAuth { error: error @ ("invalid_token" | "insufficient_scope"), .. } = self {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_str(&format!("Bearer error=\"{}\"", error))
+                    .expect("error codes are valid header values"),
+            );
+        }
+
+        response
     }
 }
 
@@ -0,0 +1,275 @@
+use crate::{
+    error::{AppError, Result},
+    middleware::{ClientIp, Principal},
+    models::{
+        AdminSessionsResponse, AdminUserResponse, AdminUsersQuery, AdminUsersResponse,
+        ImpersonateRequest, ImpersonationResponse, MessageResponse, UserResponse,
+    },
+    services::{security_events::SecurityEventType, verification::CodeType},
+    state::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+/// Users per page when the query doesn't say
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+/// Admins can't deactivate or delete their own account and lock themselves out
+fn ensure_not_self(principal: &Principal, user_id: Uuid) -> Result<()> {
+    if matches!(principal, Principal::User(current_user) if *current_user == user_id) {
+        return Err(AppError::BadRequest(
+            "You can't do this to your own account".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// List users a page at a time, optionally searching by email
+pub async fn list_users(
+    State(state): State<AppState>,
+    Query(query): Query<AdminUsersQuery>,
+) -> Result<Json<AdminUsersResponse>> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let search = query
+        .search
+        .as_deref()
+        .map(str::trim)
+        .filter(|term| !term.is_empty());
+
+    let (users, total) = state
+        .user_service
+        .list_users(search, per_page, (page - 1) * per_page)
+        .await?;
+
+    Ok(Json(AdminUsersResponse {
+        users: users.into_iter().map(AdminUserResponse::from).collect(),
+        total,
+        page,
+        per_page,
+    }))
+}
+
+pub async fn get_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<AdminUserResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    Ok(Json(AdminUserResponse::from(user)))
+}
+
+/// Deactivate a user and end their sessions
+pub async fn deactivate_user(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    ensure_not_self(&principal, user_id)?;
+
+    state.user_service.set_active(user_id, false).await?;
+    let sessions = state.token_service.revoke_all_sessions(user_id).await?;
+
+    tracing::info!(
+        "{:?} deactivated user {} and ended {} sessions",
+        principal,
+        user_id,
+        sessions
+    );
+
+    Ok(Json(MessageResponse {
+        message: "User deactivated.".to_string(),
+    }))
+}
+
+pub async fn reactivate_user(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    state.user_service.set_active(user_id, true).await?;
+
+    tracing::info!("{:?} reactivated user {}", principal, user_id);
+
+    Ok(Json(MessageResponse {
+        message: "User reactivated.".to_string(),
+    }))
+}
+
+/// Mark a user's email as verified without a code
+pub async fn verify_email(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    state.user_service.mark_email_verified(user.id).await?;
+
+    tracing::info!("{:?} verified the email of user {}", principal, user_id);
+
+    Ok(Json(MessageResponse {
+        message: "Email verified.".to_string(),
+    }))
+}
+
+/// Email the user a password reset code, as if they had asked for one
+pub async fn send_password_reset(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    // Same rule as `forgot_password`: codes only go to verified addresses
+    if !user.email_verified {
+        return Err(AppError::BadRequest(
+            "The user's email is not verified".to_string(),
+        ));
+    }
+
+    let code = state
+        .verification_service
+        .create_verification_code(user.id, CodeType::PasswordReset)
+        .await?;
+
+    state
+        .email_service
+        .send_password_reset_email(&user.email, &code)
+        .await?;
+
+    tracing::info!("{:?} sent a password reset to user {}", principal, user_id);
+
+    Ok(Json(MessageResponse {
+        message: "Password reset email sent.".to_string(),
+    }))
+}
+
+/// Delete a user and everything that belongs to them
+pub async fn delete_user(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    ensure_not_self(&principal, user_id)?;
+
+    // Blacklist their access tokens before the refresh tokens naming them go
+    state.token_service.revoke_all_sessions(user_id).await?;
+    state.user_service.delete_user(user_id).await?;
+
+    tracing::info!("{:?} deleted user {}", principal, user_id);
+
+    Ok(Json(MessageResponse {
+        message: "User deleted.".to_string(),
+    }))
+}
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<AdminSessionsResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    let sessions = state
+        .token_service
+        .get_active_sessions(user.id, None)
+        .await?;
+
+    Ok(Json(AdminSessionsResponse { sessions }))
+}
+
+/// End all of a user's sessions
+pub async fn revoke_sessions(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    let sessions = state.token_service.revoke_all_sessions(user.id).await?;
+
+    tracing::info!("{:?} ended {} sessions of user {}", principal, sessions, user_id);
+
+    Ok(Json(MessageResponse {
+        message: format!("Ended {} sessions.", sessions),
+    }))
+}
+
+/// End one of a user's sessions
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path((user_id, session_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<MessageResponse>> {
+    state
+        .token_service
+        .revoke_session(user_id, session_id)
+        .await?;
+
+    tracing::info!("{:?} ended session {} of user {}", principal, session_id, user_id);
+
+    Ok(Json(MessageResponse {
+        message: "Session ended.".to_string(),
+    }))
+}
+
+/// Act as a user for a short while to reproduce a problem. The token names
+/// the admin in its `act` claim, can't be refreshed, and shows up in the
+/// user's security history.
+pub async fn impersonate_user(
+    State(state): State<AppState>,
+    principal: Principal,
+    ClientIp(ip_address): ClientIp,
+    Path(user_id): Path<Uuid>,
+    Json(payload): Json<ImpersonateRequest>,
+) -> Result<Json<ImpersonationResponse>> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    // `act` names a person, so services can't impersonate
+    let Principal::User(admin_id) = principal else {
+        return Err(AppError::Forbidden);
+    };
+    ensure_not_self(&principal, user_id)?;
+
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    if !user.is_active {
+        return Err(AppError::BadRequest("The user is deactivated".to_string()));
+    }
+
+    let token_id = Uuid::new_v4();
+    let access_token = state
+        .jwt_service
+        .generate_impersonation_token(&user, admin_id, token_id)?;
+    let expires_in = state.config.impersonation_token_expiry;
+
+    state
+        .security_event_service
+        .record(
+            user.id,
+            SecurityEventType::Impersonation,
+            Some(ip_address),
+            json!({
+                "actor_id": admin_id,
+                "reason": payload.reason,
+                "token_id": token_id,
+                "expires_in": expires_in,
+            }),
+        )
+        .await?;
+
+    Ok(Json(ImpersonationResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        user: UserResponse::from(user),
+    }))
+}
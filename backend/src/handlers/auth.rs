@@ -1,18 +1,22 @@
 use crate::{
     error::{AppError, Result},
-    middleware::RequestExt,
+    middleware::{ClientIp, Principal, RequestExt},
     models::{
-        ActiveSessionsResponse, AuthResponse, LoginRequest, LogoutRequest, LogoutResponse,
-        RegisterRequest, UserResponse,
+        ActiveSessionsResponse, AuthResponse, LoginMfaRequest, LoginRequest, LogoutRequest,
+        LogoutResponse, MfaChallengeResponse, RegisterRequest, SecurityEventsResponse, User,
+        UserResponse,
+    },
+    services::{
+        login_attempts::LoginFailure, mfa::MfaMethod, password::PasswordService,
+        verification::CodeType,
     },
-    services::{password::PasswordService, verification::CodeType},
     state::AppState,
 };
 use axum::{
     Json,
-    extract::{Request, State},
+    extract::{Path, Request, State},
     http::{StatusCode, header},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use axum_extra::extract::cookie::{Cookie, SameSite};
 use time::Duration;
@@ -20,7 +24,7 @@ use uuid::Uuid;
 use validator::Validate;
 
 /// Helper function to create secure HttpOnly cookie
-fn create_auth_cookie(
+pub(crate) fn create_auth_cookie(
     name: String,
     value: String,
     max_age_seconds: i64,
@@ -121,56 +125,72 @@ pub async fn resend_verification_code(
     }))
 }
 
-/// Login user (only if verified) - Sets HttpOnly cookies
-pub async fn login(
-    State(state): State<AppState>,
-    Json(payload): Json<LoginRequest>,
-) -> Result<impl IntoResponse> {
-    payload
-        .validate()
-        .map_err(|e| AppError::Validation(e.to_string()))?;
-
-    let user = state.user_service.get_user_by_email(&payload.email).await?;
-
-    if !user.email_verified {
-        return Err(AppError::EmailNotVerified);
-    }
-
-    let is_valid = PasswordService::verify_password(&payload.password, &user.password_hash)?;
-    if !is_valid {
-        return Err(AppError::InvalidCredentials);
-    }
-
+/// Start a new session for `user`: store its refresh token and build the
+/// access/refresh HttpOnly cookies
+pub(crate) async fn create_session_cookies(
+    state: &AppState,
+    user: &User,
+) -> Result<(Cookie<'static>, Cookie<'static>)> {
+    // The first token of a session also names its rotation family
     let refresh_token_id = Uuid::new_v4();
-    let access_token = state
-        .jwt_service
-        .generate_access_token(&user, refresh_token_id)?;
+    let access = state.role_service.access_for(user.id).await?;
+    let organization_id = state
+        .organization_service
+        .default_organization(user.id)
+        .await?;
+    let access_token = state.jwt_service.generate_access_token(
+        user,
+        refresh_token_id,
+        &access,
+        organization_id,
+    )?;
     let refresh_token = state
         .jwt_service
-        .generate_refresh_token(user.id, refresh_token_id)?;
+        .generate_refresh_token(user.id, refresh_token_id, refresh_token_id)?;
 
     state
         .token_service
-        .store_refresh_token(refresh_token_id, user.id, &refresh_token, None, None)
+        .store_refresh_token(
+            refresh_token_id,
+            refresh_token_id,
+            user.id,
+            &refresh_token,
+            None,
+            None,
+        )
         .await?;
 
+    if organization_id.is_some() {
+        state
+            .token_service
+            .set_session_organization(refresh_token_id, organization_id)
+            .await?;
+    }
+
     let is_secure = state.config.environment.is_production();
 
     // Create secure HttpOnly cookies
     let access_cookie = create_auth_cookie(
         "accessToken".to_string(),
-        access_token.clone(),
+        access_token,
         state.config.access_token_expiry,
         is_secure,
     );
 
     let refresh_cookie = create_auth_cookie(
         "refreshToken".to_string(),
-        refresh_token.clone(),
+        refresh_token,
         state.config.refresh_token_expiry,
         is_secure,
     );
 
+    Ok((access_cookie, refresh_cookie))
+}
+
+/// Issue a new access/refresh token pair for `user` and set both as HttpOnly cookies
+pub(crate) async fn issue_session(state: &AppState, user: &User) -> Result<Response> {
+    let (access_cookie, refresh_cookie) = create_session_cookies(state, user).await?;
+
     // Build response with cookies
     let mut response = Json(AuthResponse {
         access_token: "set_in_cookie".into(),
@@ -192,6 +212,212 @@ pub async fn login(
     Ok(response)
 }
 
+/// Verify an account's email for someone who just proved they own the address.
+/// If it wasn't verified yet, whoever registered it may not be the owner, so
+/// the password they chose stops working and their sessions end.
+pub(crate) async fn claim_unverified_account(state: &AppState, user: &User) -> Result<()> {
+    if user.email_verified {
+        return Ok(());
+    }
+
+    state
+        .user_service
+        .update_password(user.id, &PasswordService::unusable_password_hash())
+        .await?;
+    state.token_service.revoke_all_user_tokens(user.id).await?;
+    state.user_service.mark_email_verified(user.id).await
+}
+
+/// Complete a single-factor sign-in (password or magic link): returns an MFA
+/// challenge when the account has 2FA enabled, otherwise issues the session
+pub(crate) async fn finish_first_factor(state: &AppState, user: &User) -> Result<Response> {
+    if state.mfa_service.is_totp_enabled(user.id).await? {
+        let mfa_token = state.mfa_service.create_login_challenge(user.id).await?;
+
+        return Ok(Json(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+            expires_in: state.config.mfa_challenge_expiry,
+        })
+        .into_response());
+    }
+
+    issue_session(state, user).await
+}
+
+/// Login user (only if verified) - Sets HttpOnly cookies, or returns an
+/// MFA challenge when the account has two-factor authentication enabled
+pub async fn login(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<LoginRequest>,
+) -> Result<Response> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    state.login_attempt_service.check(&payload.email, &ip).await?;
+
+    let user = match state.user_service.get_user_by_email(&payload.email).await {
+        Ok(user) => user,
+        Err(e) => {
+            // Guessing at unknown accounts still counts against the IP
+            state
+                .login_attempt_service
+                .record_failure(&payload.email, &ip)
+                .await?;
+            return Err(e);
+        }
+    };
+
+    if !user.email_verified {
+        return Err(AppError::EmailNotVerified);
+    }
+
+    let is_valid = PasswordService::verify_password(&payload.password, &user.password_hash)?;
+    if !is_valid {
+        let failure = state
+            .login_attempt_service
+            .record_failure(&payload.email, &ip)
+            .await?;
+
+        if let LoginFailure::AccountLocked { retry_after } = failure {
+            send_unlock_code(&state, &user, retry_after).await;
+            return Err(AppError::AccountLocked { retry_after });
+        }
+
+        return Err(AppError::InvalidCredentials);
+    }
+
+    // Checked only once the password is right, so it doesn't tell anyone
+    // which accounts are deactivated
+    if !user.is_active {
+        return Err(AppError::Unauthorized);
+    }
+
+    // With 2FA on, the failure counter is only cleared once the second factor
+    // passes, so wrong codes keep counting towards the lockout
+    if !state.mfa_service.is_totp_enabled(user.id).await? {
+        state
+            .login_attempt_service
+            .record_success(&payload.email)
+            .await?;
+    }
+
+    finish_first_factor(&state, &user).await
+}
+
+/// Count a wrong TOTP or recovery code against the account and pass the error
+/// on, or the lockout if this attempt triggered it
+pub(crate) async fn second_factor_failed(state: &AppState, user: &User, error: AppError) -> AppError {
+    if !matches!(error, AppError::InvalidMfaCode) {
+        return error;
+    }
+
+    match state
+        .login_attempt_service
+        .record_second_factor_failure(&user.email)
+        .await
+    {
+        Ok(LoginFailure::Counted) => error,
+        Ok(LoginFailure::AccountLocked { retry_after }) => {
+            send_unlock_code(state, user, retry_after).await;
+            AppError::AccountLocked { retry_after }
+        }
+        Err(e) => e,
+    }
+}
+
+/// Email a freshly locked-out user a code to unlock their account early.
+/// Failures are only logged - the lockout stands either way.
+async fn send_unlock_code(state: &AppState, user: &User, lockout_secs: u64) {
+    let result = async {
+        let code = state
+            .verification_service
+            .create_verification_code(user.id, CodeType::AccountUnlock)
+            .await?;
+
+        state
+            .email_service
+            .send_account_locked_email(&user.email, &code, (lockout_secs as i64 + 59) / 60)
+            .await
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::error!("Failed to send account unlock code: {:?}", e);
+    }
+}
+
+/// Unlock an account locked after failed logins, using the code from the lockout email
+pub async fn unlock_account(
+    State(state): State<AppState>,
+    Json(payload): Json<crate::models::UnlockAccountRequest>,
+) -> Result<impl IntoResponse> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    // An unknown email gets the same answer as a wrong code, so this can't be
+    // used to find out who has an account
+    let user = match state.user_service.get_user_by_email(&payload.email).await {
+        Ok(user) => user,
+        Err(AppError::UserNotFound) => return Err(AppError::InvalidVerificationCode),
+        Err(e) => return Err(e),
+    };
+
+    state
+        .verification_service
+        .verify_code(user.id, &payload.code, CodeType::AccountUnlock)
+        .await?;
+
+    state.login_attempt_service.unlock_account(&user.email).await?;
+
+    Ok(Json(crate::models::MessageResponse {
+        message: "Account unlocked. You can now log in.".to_string(),
+    }))
+}
+
+/// Finish a login that was answered with an MFA challenge, using either a TOTP
+/// code or a recovery code - Sets HttpOnly cookies
+pub async fn login_mfa(
+    State(state): State<AppState>,
+    Json(payload): Json<LoginMfaRequest>,
+) -> Result<Response> {
+    let user_id = state.mfa_service.challenge_user(&payload.mfa_token).await?;
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    if !user.is_active {
+        return Err(AppError::Unauthorized);
+    }
+
+    state.login_attempt_service.check_account(&user.email).await?;
+
+    let (_, method) = match state
+        .mfa_service
+        .complete_login_challenge(&payload.mfa_token, &payload.code)
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return Err(second_factor_failed(&state, &user, e).await),
+    };
+
+    state.login_attempt_service.record_success(&user.email).await?;
+
+    if let MfaMethod::RecoveryCode { remaining } = method {
+        // The code is already spent, so a mail failure must not block the login
+        if let Err(e) = state
+            .email_service
+            .send_recovery_code_used_email(&user.email, remaining)
+            .await
+        {
+            tracing::error!("Failed to send recovery code notification: {:?}", e);
+        }
+    }
+
+    issue_session(&state, &user).await
+}
+
 /// Refresh access token using a valid refresh token from cookie
 pub async fn refresh(State(state): State<AppState>, req: Request) -> Result<impl IntoResponse> {
     let cookies = req
@@ -209,29 +435,60 @@ pub async fn refresh(State(state): State<AppState>, req: Request) -> Result<impl
         .ok_or(AppError::MissingRefreshToken)?;
 
     let claims = state.jwt_service.verify_refresh_token(&refresh_token)?;
-    let _refresh_record = state
+    let refresh_record = state
         .token_service
         .verify_refresh_token(&refresh_token)
         .await?;
 
+    // The family travels in the token; older tokens fall back to the stored row
+    let family_id = match &claims.family_id {
+        Some(id) => Uuid::parse_str(id).map_err(|_| AppError::InvalidToken)?,
+        None => refresh_record.family_id,
+    };
+    if family_id != refresh_record.family_id {
+        return Err(AppError::InvalidToken);
+    }
+
+    // Tokens issued to OAuth clients are refreshed at `/oauth/token`
+    if refresh_record.client_id.is_some() {
+        return Err(AppError::InvalidToken);
+    }
+
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidToken)?;
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    // A deactivated user keeps their cookie but can't trade it for new tokens
+    if !user.is_active {
+        return Err(AppError::Unauthorized);
+    }
+
     let new_token_id = Uuid::new_v4();
-    let new_refresh_token = state.jwt_service.generate_refresh_token(
-        Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidToken)?,
-        new_token_id,
-    )?;
+    let new_refresh_token = state
+        .jwt_service
+        .generate_refresh_token(user.id, new_token_id, family_id)?;
 
     state
         .token_service
         .rotate_refresh_token(&refresh_token, new_token_id, &new_refresh_token, None, None)
         .await?;
 
-    let user = state
-        .user_service
-        .get_user_by_id(Uuid::parse_str(&claims.sub).unwrap())
-        .await?;
-    let new_access_token = state
-        .jwt_service
-        .generate_access_token(&user, new_token_id)?;
+    let access = state.role_service.access_for(user.id).await?;
+
+    // The session stays in its organization while the user still belongs to it
+    let organization_id = match refresh_record.organization_id {
+        Some(organization_id) => state
+            .organization_service
+            .member_role(organization_id, user.id)
+            .await?
+            .map(|_| organization_id),
+        None => None,
+    };
+    let new_access_token = state.jwt_service.generate_access_token(
+        &user,
+        new_token_id,
+        &access,
+        organization_id,
+    )?;
 
     let is_secure = state.config.environment.is_production();
 
@@ -295,7 +552,7 @@ pub async fn get_active_sessions(
 
     let sessions = state
         .token_service
-        .get_active_sessions(user_id, current_token_id)
+        .get_active_sessions(user_id, Some(current_token_id))
         .await?;
 
     Ok(Json(ActiveSessionsResponse {
@@ -305,6 +562,18 @@ pub async fn get_active_sessions(
     }))
 }
 
+/// The current user's security history, e.g. refresh token reuse and impersonations
+pub async fn get_security_events(
+    State(state): State<AppState>,
+    req: Request,
+) -> Result<Json<SecurityEventsResponse>> {
+    let user_id = req.user_id()?;
+
+    let events = state.security_event_service.list_for_user(user_id).await?;
+
+    Ok(Json(SecurityEventsResponse { events }))
+}
+
 /// Logout user with option to logout from all devices - Clears cookies
 pub async fn logout(State(state): State<AppState>, req: Request) -> Result<impl IntoResponse> {
     let cookies = req
@@ -397,6 +666,29 @@ pub async fn me(State(state): State<AppState>, req: Request) -> Result<Json<User
     Ok(Json(UserResponse::from(user)))
 }
 
+/// Look up a user by id. Services need the `users:read` scope; users may
+/// only look up themselves.
+pub async fn get_user(
+    State(state): State<AppState>,
+    principal: Principal,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<UserResponse>> {
+    match &principal {
+        Principal::User(current_user) | Principal::PersonalToken(current_user)
+            if *current_user != user_id =>
+        {
+            return Err(AppError::Forbidden)
+        }
+        Principal::User(_) | Principal::PersonalToken(_) => {}
+        Principal::Service { client_id, .. } => {
+            principal.require_service_scope("users:read")?;
+            tracing::info!("Service {} looked up user {}", client_id, user_id);
+        }
+    }
+
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    Ok(Json(UserResponse::from(user)))
+}
 
 // Add these handlers to your auth.rs file
 
@@ -476,7 +768,75 @@ pub async fn reset_password(
     // Revoke all existing sessions for security
     state.token_service.revoke_all_user_tokens(user.id).await?;
 
+    // Proving control of the mailbox also lifts a login lockout
+    state.login_attempt_service.unlock_account(&user.email).await?;
+
     Ok(Json(crate::models::MessageResponse {
         message: "Password reset successfully. Please log in with your new password.".to_string(),
     }))
-}
\ No newline at end of file
+}
+/// Request a one-time login link by email
+pub async fn request_magic_link(
+    State(state): State<AppState>,
+    Json(payload): Json<crate::models::MagicLinkRequest>,
+) -> Result<impl IntoResponse> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    // Same response whether or not the account exists, like forgot_password
+    let response = Json(crate::models::MessageResponse {
+        message: "If an account exists with this email, a sign-in link has been sent.".to_string(),
+    });
+
+    let user = match state.user_service.get_user_by_email(&payload.email).await {
+        Ok(user) if user.is_active => user,
+        _ => return Ok(response),
+    };
+
+    let code = state
+        .verification_service
+        .create_verification_code(user.id, CodeType::MagicLink)
+        .await?;
+
+    let token = state.jwt_service.generate_magic_link_token(user.id, &code)?;
+    let link = format!(
+        "{}/auth/magic-link?token={}",
+        state.config.frontend_url.trim_end_matches('/'),
+        token
+    );
+
+    state
+        .email_service
+        .send_magic_link_email(&user.email, &link, state.config.magic_link_expiry)
+        .await?;
+
+    Ok(response)
+}
+
+/// Sign in with a magic link token - Sets HttpOnly cookies, or returns an MFA
+/// challenge when the account has 2FA enabled
+pub async fn consume_magic_link(
+    State(state): State<AppState>,
+    Json(payload): Json<crate::models::ConsumeMagicLinkRequest>,
+) -> Result<Response> {
+    let claims = state.jwt_service.verify_magic_link_token(&payload.token)?;
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidToken)?;
+
+    // The stored code makes the link single-use
+    state
+        .verification_service
+        .verify_code(user_id, &claims.jti, CodeType::MagicLink)
+        .await?;
+
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    if !user.is_active {
+        return Err(AppError::Unauthorized);
+    }
+
+    // Following the link proves the user owns the address
+    claim_unverified_account(&state, &user).await?;
+
+    finish_first_factor(&state, &user).await
+}
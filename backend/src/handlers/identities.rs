@@ -0,0 +1,75 @@
+use crate::{
+    error::Result,
+    handlers::oauth::state_cookie,
+    models::{
+        ConfirmIdentityLinkRequest, IdentitiesResponse, LinkIdentityResponse, MessageResponse,
+    },
+    services::oauth::OAuthIntent,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use axum_extra::extract::cookie::CookieJar;
+use uuid::Uuid;
+
+/// List the external accounts linked to the current user
+pub async fn list_identities(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<IdentitiesResponse>> {
+    let identities = state.identity_service.list(user_id).await?;
+
+    Ok(Json(IdentitiesResponse { identities }))
+}
+
+/// Start linking a provider account to the current user. Returns the URL to
+/// send the browser to; the provider's callback then links the account.
+pub async fn start_link(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(provider): Path<String>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Json<LinkIdentityResponse>)> {
+    let start = state
+        .oauth_service
+        .begin_login(&provider, OAuthIntent::Link { user_id })
+        .await?;
+
+    Ok((
+        jar.add(state_cookie(&state, start.state)),
+        Json(LinkIdentityResponse {
+            auth_url: start.auth_url,
+        }),
+    ))
+}
+
+/// Confirm linking a provider sign-in that matched the current user's email
+pub async fn confirm_link(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<ConfirmIdentityLinkRequest>,
+) -> Result<impl IntoResponse> {
+    let identity = state
+        .identity_service
+        .confirm_pending_link(user_id, &payload.link_token)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(identity)))
+}
+
+/// Unlink one of the current user's external accounts
+pub async fn unlink_identity(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(identity_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    state.identity_service.unlink(user_id, identity_id).await?;
+
+    Ok(Json(MessageResponse {
+        message: "Account unlinked.".to_string(),
+    }))
+}
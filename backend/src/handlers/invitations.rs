@@ -0,0 +1,206 @@
+use crate::{
+    error::{AppError, Result},
+    handlers::auth::issue_session,
+    middleware::OrgMembership,
+    models::{
+        AcceptInvitationRequest, CreateInvitationRequest, InvitationDetailsResponse,
+        InvitationQuery, InvitationsResponse, JoinInvitationRequest, MessageResponse, OrgRole,
+    },
+    services::password::PasswordService,
+    state::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Invite someone into the current organization by email - admins and owners only
+pub async fn create_invitation(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    membership: OrgMembership,
+    Json(payload): Json<CreateInvitationRequest>,
+) -> Result<impl IntoResponse> {
+    membership.require_role(OrgRole::Admin)?;
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    // Admins can bring in admins, but only owners can hand out ownership
+    if payload.role > membership.role {
+        return Err(AppError::Forbidden);
+    }
+
+    let organization = state
+        .organization_service
+        .find_for_member(membership.organization_id, user_id)
+        .await?
+        .ok_or(AppError::OrganizationNotFound)?;
+
+    let expires_in = state.config.invitation_expiry;
+    let (token, invitation) = state
+        .invitation_service
+        .create(
+            organization.id,
+            &payload.email,
+            payload.role,
+            user_id,
+            expires_in,
+        )
+        .await?;
+
+    let link = format!(
+        "{}/invitations/accept?token={}",
+        state.config.frontend_url.trim_end_matches('/'),
+        token
+    );
+
+    state
+        .email_service
+        .send_invitation_email(
+            &invitation.email,
+            &organization.name,
+            invitation.role.as_str(),
+            &link,
+            expires_in,
+        )
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(invitation)))
+}
+
+/// The current organization's open invitations - admins and owners only
+pub async fn list_invitations(
+    State(state): State<AppState>,
+    membership: OrgMembership,
+) -> Result<Json<InvitationsResponse>> {
+    membership.require_role(OrgRole::Admin)?;
+
+    let invitations = state
+        .invitation_service
+        .list_pending(membership.organization_id)
+        .await?;
+
+    Ok(Json(InvitationsResponse { invitations }))
+}
+
+/// Withdraw an invitation before it's accepted - admins and owners only
+pub async fn revoke_invitation(
+    State(state): State<AppState>,
+    membership: OrgMembership,
+    Path(invitation_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    membership.require_role(OrgRole::Admin)?;
+
+    state
+        .invitation_service
+        .revoke(membership.organization_id, invitation_id)
+        .await?;
+
+    Ok(Json(MessageResponse {
+        message: "Invitation revoked.".to_string(),
+    }))
+}
+
+/// Show an invitation to the person holding its link, so the page can ask for
+/// a password only when they don't have an account yet
+pub async fn get_invitation(
+    State(state): State<AppState>,
+    Query(query): Query<InvitationQuery>,
+) -> Result<Json<InvitationDetailsResponse>> {
+    let invitation = state.invitation_service.find_pending(&query.token).await?;
+    let account_exists = match state.user_service.get_user_by_email(&invitation.email).await {
+        Ok(user) => user.email_verified,
+        Err(AppError::UserNotFound) => false,
+        Err(e) => return Err(e),
+    };
+
+    Ok(Json(InvitationDetailsResponse {
+        organization_name: invitation.organization_name,
+        email: invitation.email,
+        role: invitation.role,
+        expires_at: invitation.expires_at,
+        account_exists,
+    }))
+}
+
+/// Accept an invitation without signing in. The invitee sets a password and
+/// is signed in - to a new account, or to an unverified one, which whoever
+/// registered it never proved they owned. A verified account has to sign in
+/// and use `join_invitation`, so the link alone can't add it anywhere.
+pub async fn accept_invitation(
+    State(state): State<AppState>,
+    Json(payload): Json<AcceptInvitationRequest>,
+) -> Result<Response> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let invitation = state.invitation_service.find_pending(&payload.token).await?;
+    let password_hash = || {
+        let password = payload.password.as_deref().ok_or_else(|| {
+            AppError::Validation("A password is required to set up your account".to_string())
+        })?;
+        PasswordService::hash_password(password)
+    };
+
+    match state.user_service.get_user_by_email(&invitation.email).await {
+        Ok(user) if !user.is_active => Err(AppError::Unauthorized),
+        Ok(user) if user.email_verified => Err(AppError::InvitationSignInRequired),
+        Ok(user) => {
+            let password_hash = password_hash()?;
+
+            // End the registrant's sessions before the account changes hands
+            state.token_service.revoke_all_user_tokens(user.id).await?;
+            state
+                .invitation_service
+                .accept_reclaiming(&invitation, user.id, &password_hash)
+                .await?;
+
+            let user = state.user_service.get_user_by_id(user.id).await?;
+            issue_session(&state, &user).await
+        }
+        Err(AppError::UserNotFound) => {
+            let password_hash = password_hash()?;
+            let user = state
+                .invitation_service
+                .accept_as_new_user(&invitation, &password_hash)
+                .await?;
+
+            // Log in as if they'd just verified - a new account has no second factor yet
+            let response = issue_session(&state, &user).await?;
+
+            Ok((StatusCode::CREATED, response).into_response())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Accept an invitation with the signed-in account. It must be the account
+/// the invitation was sent to.
+pub async fn join_invitation(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<JoinInvitationRequest>,
+) -> Result<Json<MessageResponse>> {
+    let invitation = state.invitation_service.find_pending(&payload.token).await?;
+
+    let invitee = match state.user_service.get_user_by_email(&invitation.email).await {
+        Ok(user) => user,
+        Err(AppError::UserNotFound) => return Err(AppError::Forbidden),
+        Err(e) => return Err(e),
+    };
+    if invitee.id != user_id || !invitee.email_verified {
+        return Err(AppError::Forbidden);
+    }
+
+    state.invitation_service.accept(&invitation, user_id).await?;
+
+    Ok(Json(MessageResponse {
+        message: format!("You've joined {}.", invitation.organization_name),
+    }))
+}
@@ -0,0 +1,115 @@
+use crate::{
+    error::Result,
+    handlers::auth::second_factor_failed,
+    models::{
+        MessageResponse, MfaStatusResponse, RecoveryCodesResponse, RecoveryCodesStatusResponse,
+        TotpCodeRequest, TotpSetupResponse,
+    },
+    state::AppState,
+};
+use axum::{extract::State, Extension, Json};
+use uuid::Uuid;
+
+/// Get the current user's two-factor status
+pub async fn mfa_status(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<MfaStatusResponse>> {
+    let totp_enabled = state.mfa_service.is_totp_enabled(user_id).await?;
+    let recovery_codes_remaining = state.mfa_service.remaining_recovery_codes(user_id).await?;
+
+    Ok(Json(MfaStatusResponse {
+        totp_enabled,
+        recovery_codes_remaining,
+    }))
+}
+
+/// Start TOTP enrollment - returns the secret and an otpauth:// URI for QR codes
+pub async fn setup_totp(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<TotpSetupResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    let enrollment = state
+        .mfa_service
+        .begin_totp_enrollment(user.id, &user.email)
+        .await?;
+
+    Ok(Json(TotpSetupResponse {
+        secret: enrollment.secret,
+        otpauth_uri: enrollment.otpauth_uri,
+    }))
+}
+
+/// Confirm TOTP enrollment with the first code from the authenticator app.
+/// Returns the initial set of recovery codes - they are only shown once.
+pub async fn confirm_totp(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>> {
+    state
+        .mfa_service
+        .confirm_totp_enrollment(user_id, &payload.code)
+        .await?;
+
+    let recovery_codes = state.mfa_service.regenerate_recovery_codes(user_id).await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Disable TOTP - requires a current code
+pub async fn disable_totp(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<MessageResponse>> {
+    // A stolen session must not get unlimited guesses at the code
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    state.login_attempt_service.check_account(&user.email).await?;
+
+    if let Err(e) = state
+        .mfa_service
+        .disable_totp(user_id, &payload.code)
+        .await
+    {
+        return Err(second_factor_failed(&state, &user, e).await);
+    }
+
+    Ok(Json(MessageResponse {
+        message: "Two-factor authentication disabled.".to_string(),
+    }))
+}
+
+/// Get how many unused recovery codes the current user has left
+pub async fn recovery_codes_status(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<RecoveryCodesStatusResponse>> {
+    let remaining = state.mfa_service.remaining_recovery_codes(user_id).await?;
+
+    Ok(Json(RecoveryCodesStatusResponse { remaining }))
+}
+
+/// Replace all recovery codes with a new set - requires a current TOTP code
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    state.login_attempt_service.check_account(&user.email).await?;
+
+    if let Err(e) = state
+        .mfa_service
+        .verify_totp(user_id, &payload.code)
+        .await
+    {
+        return Err(second_factor_failed(&state, &user, e).await);
+    }
+
+    let recovery_codes = state.mfa_service.regenerate_recovery_codes(user_id).await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
@@ -0,0 +1,196 @@
+use crate::{
+    error::{AppError, Result},
+    handlers::auth::create_session_cookies,
+    models::{OAuthCallbackQuery, User},
+    services::{
+        oauth::{ExternalIdentity, OAuthIntent, OAUTH_STATE_EXPIRY},
+        password::PasswordService,
+    },
+    state::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use time::Duration;
+
+/// Carries the `state` between `/start` and `/callback` in the same browser
+const STATE_COOKIE: &str = "oauthState";
+
+/// Start sign-in with an external provider - redirects the browser to it
+pub async fn start(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Redirect)> {
+    let start = state
+        .oauth_service
+        .begin_login(&provider, OAuthIntent::SignIn)
+        .await?;
+
+    Ok((
+        jar.add(state_cookie(&state, start.state)),
+        Redirect::to(&start.auth_url),
+    ))
+}
+
+/// The cookie that ties a provider callback to the browser that started the flow
+pub(crate) fn state_cookie(state: &AppState, value: String) -> Cookie<'static> {
+    // Lax, not Strict: the cookie has to come back on the redirect from the provider
+    Cookie::build((STATE_COOKIE, value))
+        .path("/")
+        .max_age(Duration::seconds(OAUTH_STATE_EXPIRY))
+        .same_site(SameSite::Lax)
+        .http_only(true)
+        .secure(state.config.is_production())
+        .build()
+}
+
+/// The provider redirects back here. Signs the user in (creating the account
+/// on first use), sets the same session cookies as `login` and sends the
+/// browser on to the frontend. Accounts with 2FA get an MFA challenge instead.
+/// Flows started from `/api/identities/link/:provider` link the account instead.
+pub async fn callback(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+    jar: CookieJar,
+    Query(query): Query<OAuthCallbackQuery>,
+) -> Result<Response> {
+    let state_cookie = jar.get(STATE_COOKIE).map(|c| c.value().to_string());
+    let jar = jar.remove(Cookie::build(STATE_COOKIE).path("/"));
+
+    if let Some(error) = query.error {
+        tracing::info!("{} sign-in was not completed: {}", provider, error);
+        let url = format!("{}/auth/login?error=oauth_cancelled", state.config.frontend_url);
+        return Ok((jar, Redirect::to(&url)).into_response());
+    }
+
+    let (code, returned_state) = query
+        .code
+        .zip(query.state)
+        .ok_or_else(|| AppError::BadRequest("Missing code or state".to_string()))?;
+
+    // The flow must finish in the browser that started it
+    if state_cookie.as_deref() != Some(returned_state.as_str()) {
+        return Err(AppError::InvalidToken);
+    }
+
+    let (identity, intent) = state
+        .oauth_service
+        .complete_login(&provider, &code, &returned_state)
+        .await?;
+
+    if let OAuthIntent::Link { user_id } = intent {
+        state.identity_service.link(user_id, &identity).await?;
+
+        let url = format!(
+            "{}/dashboard?linked={}",
+            state.config.frontend_url,
+            urlencoding::encode(&identity.provider)
+        );
+        return Ok((jar, Redirect::to(&url)).into_response());
+    }
+
+    let user = match state.identity_service.find_linked_user(&identity).await? {
+        Some(user_id) => state.user_service.get_user_by_id(user_id).await?,
+        None => match find_or_create_user(&state, &identity).await? {
+            FirstSignIn::Linked(user) => user,
+            FirstSignIn::NeedsConfirmation(user) => {
+                // Never merged silently: the account's owner has to sign in and confirm
+                let link_token = state
+                    .identity_service
+                    .create_pending_link(user.id, &identity)
+                    .await?;
+
+                let url = format!(
+                    "{}/auth/login?link={}#link_token={}",
+                    state.config.frontend_url,
+                    urlencoding::encode(&identity.provider),
+                    urlencoding::encode(&link_token)
+                );
+                return Ok((jar, Redirect::to(&url)).into_response());
+            }
+        },
+    };
+
+    if !user.is_active {
+        return Err(AppError::Unauthorized);
+    }
+
+    tracing::info!(
+        "{} sign-in for user {} (subject {})",
+        identity.provider,
+        user.id,
+        identity.subject
+    );
+
+    if state.mfa_service.is_totp_enabled(user.id).await? {
+        let mfa_token = state.mfa_service.create_login_challenge(user.id).await?;
+
+        // In the fragment so it never reaches a server log
+        let url = format!(
+            "{}/auth/login#mfa_token={}",
+            state.config.frontend_url,
+            urlencoding::encode(&mfa_token)
+        );
+        return Ok((jar, continue_to(&url)).into_response());
+    }
+
+    let (access_cookie, refresh_cookie) = create_session_cookies(&state, &user).await?;
+    let url = format!("{}/dashboard", state.config.frontend_url);
+
+    Ok((jar.add(access_cookie).add(refresh_cookie), continue_to(&url)).into_response())
+}
+
+/// Outcome of the first sign-in with a provider account
+enum FirstSignIn {
+    /// The account was created (or taken over) and the provider account linked to it
+    Linked(User),
+    /// A verified account already owns the email and has to confirm the link
+    NeedsConfirmation(User),
+}
+
+/// Find or create the account for a provider account that isn't linked yet.
+/// An unverified local account with the email is taken over: the provider has
+/// just proven who owns the address, so whoever registered it loses the password.
+async fn find_or_create_user(state: &AppState, identity: &ExternalIdentity) -> Result<FirstSignIn> {
+    if !identity.email_verified {
+        return Err(AppError::EmailNotVerified);
+    }
+
+    let user = match state.user_service.get_user_by_email(&identity.email).await {
+        Ok(user) if user.email_verified => return Ok(FirstSignIn::NeedsConfirmation(user)),
+        Ok(user) => {
+            state
+                .user_service
+                .update_password(user.id, &PasswordService::unusable_password_hash())
+                .await?;
+            state.token_service.revoke_all_user_tokens(user.id).await?;
+            user
+        }
+        Err(AppError::UserNotFound) => {
+            state
+                .user_service
+                .create_user(&identity.email, &PasswordService::unusable_password_hash())
+                .await?
+        }
+        Err(e) => return Err(e),
+    };
+
+    state.user_service.mark_email_verified(user.id).await?;
+    state.identity_service.link(user.id, identity).await?;
+    state.user_service.get_user_by_id(user.id).await.map(FirstSignIn::Linked)
+}
+
+/// Navigate to `url` from a page of our own. A plain redirect would keep the
+/// request chain cross-site (it started at the provider), and the browser would hold
+/// back the SameSite=Strict session cookies we just set.
+fn continue_to(url: &str) -> Html<String> {
+    let url = url.replace('&', "&amp;").replace('"', "&quot;");
+
+    Html(format!(
+        r#"<!DOCTYPE html><html><head><meta http-equiv="refresh" content="0;url={0}"></head><body><a href="{0}">Continue</a></body></html>"#,
+        url
+    ))
+}
@@ -0,0 +1,712 @@
+use crate::{
+    error::{AppError, Result},
+    models::{
+        AccessTokenClaims, ApiAccessTokenClaims, AuthorizationDecisionRequest,
+        AuthorizationDecisionResponse, AuthorizationRequestResponse, AuthorizeQuery,
+        DeviceAuthorizationRequest, DeviceAuthorizationResponse, DeviceRequestResponse,
+        IntrospectionRequest, MessageResponse, IntrospectionResponse, OAuthClient, RevocationRequest, TokenRequest,
+        TokenResponse, UserInfoResponse,
+    },
+    services::oauth_server::{AuthorizationGrant, OAuthServerService, DEVICE_CODE_GRANT},
+    state::AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Form, Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use uuid::Uuid;
+
+/// Authorization endpoint. Checks the request, then hands over to the
+/// frontend's consent page, which signs the user in first if needed.
+pub async fn authorize(
+    State(state): State<AppState>,
+    Query(query): Query<AuthorizeQuery>,
+) -> Result<Response> {
+    // A bad client or redirect URI is shown to the user, not redirected
+    let (client, redirect_uri) = state.oauth_server_service.resolve_redirect(&query).await?;
+
+    match state
+        .oauth_server_service
+        .create_authorization_request(&client, &redirect_uri, &query)
+        .await
+    {
+        Ok(request_id) => {
+            let url = format!(
+                "{}/oauth/consent?request_id={}",
+                state.config.frontend_url, request_id
+            );
+            Ok(Redirect::to(&url).into_response())
+        }
+        Err(AppError::OAuth { error, description }) => {
+            let url = OAuthServerService::error_redirect(
+                &redirect_uri,
+                error,
+                &description,
+                query.state.as_deref(),
+            );
+            Ok(Redirect::to(&url).into_response())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The pending authorization request, for the consent page
+pub async fn get_authorization_request(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(request_id): Path<String>,
+) -> Result<Json<AuthorizationRequestResponse>> {
+    let request = state
+        .oauth_server_service
+        .describe_request(user_id, &request_id)
+        .await?;
+
+    Ok(Json(request))
+}
+
+/// Approve or deny a pending authorization request. The frontend sends the
+/// browser on to `redirect_to`.
+pub async fn decide_authorization_request(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(claims): Extension<AccessTokenClaims>,
+    Path(request_id): Path<String>,
+    Json(payload): Json<AuthorizationDecisionRequest>,
+) -> Result<Json<AuthorizationDecisionResponse>> {
+    // The ID token's `auth_time` is when this session signed in, not when it last refreshed
+    let token_id = Uuid::parse_str(&claims.jti).map_err(|_| AppError::InvalidToken)?;
+    let auth_time = state
+        .token_service
+        .session_started_at(token_id)
+        .await?
+        .map_or(claims.iat, |started_at| started_at.timestamp());
+
+    let redirect_to = state
+        .oauth_server_service
+        .decide(user_id, auth_time, &request_id, payload.approve)
+        .await?;
+
+    Ok(Json(AuthorizationDecisionResponse { redirect_to }))
+}
+
+/// Device authorization endpoint (RFC 8628): the device shows the user code
+/// and polls the token endpoint while the user approves it elsewhere
+pub async fn device_authorization(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<DeviceAuthorizationRequest>,
+) -> Result<Response> {
+    let (client_id, client_secret) = client_credentials(
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+    let client = state
+        .oauth_server_service
+        .authenticate_client(&client_id, client_secret.as_deref())
+        .await?;
+
+    let codes = state
+        .oauth_server_service
+        .create_device_authorization(&client, request.scope.as_deref())
+        .await?;
+
+    let verification_uri = format!("{}/device", state.config.frontend_url);
+    let response = DeviceAuthorizationResponse {
+        verification_uri_complete: format!(
+            "{}?user_code={}",
+            verification_uri,
+            urlencoding::encode(&codes.user_code)
+        ),
+        verification_uri,
+        device_code: codes.device_code,
+        user_code: codes.user_code,
+        expires_in: codes.expires_in,
+        interval: codes.interval,
+    };
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store"), (header::PRAGMA, "no-cache")],
+        Json(response),
+    )
+        .into_response())
+}
+
+/// The device a user code belongs to, for the page where the user enters it
+pub async fn get_device_request(
+    State(state): State<AppState>,
+    Path(user_code): Path<String>,
+) -> Result<Json<DeviceRequestResponse>> {
+    let request = state
+        .oauth_server_service
+        .describe_device_request(&user_code)
+        .await?;
+
+    Ok(Json(request))
+}
+
+/// Approve or deny a device by its user code. The device's next poll picks it up.
+pub async fn decide_device_request(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(user_code): Path<String>,
+    Json(payload): Json<AuthorizationDecisionRequest>,
+) -> Result<Json<MessageResponse>> {
+    state
+        .oauth_server_service
+        .decide_device_request(user_id, &user_code, payload.approve)
+        .await?;
+
+    let message = if payload.approve {
+        "Device approved. You can return to it now."
+    } else {
+        "Device denied."
+    };
+    Ok(Json(MessageResponse {
+        message: message.to_string(),
+    }))
+}
+
+/// Token endpoint: `authorization_code` (with PKCE), `refresh_token`,
+/// `client_credentials` and device code grants
+pub async fn token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<TokenRequest>,
+) -> Result<Response> {
+    let (client_id, client_secret) = client_credentials(
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+    let client = state
+        .oauth_server_service
+        .authenticate_client(&client_id, client_secret.as_deref())
+        .await?;
+
+    let known_grant = matches!(
+        request.grant_type.as_str(),
+        "authorization_code" | "refresh_token" | "client_credentials" | DEVICE_CODE_GRANT
+    );
+    if known_grant && !client.allows_grant(&request.grant_type) {
+        return Err(AppError::oauth(
+            "unauthorized_client",
+            format!("This client may not use the {} grant", request.grant_type),
+        ));
+    }
+
+    let response = match request.grant_type.as_str() {
+        "authorization_code" => {
+            let code = request
+                .code
+                .as_deref()
+                .ok_or_else(|| AppError::oauth("invalid_request", "Missing code"))?;
+            let grant = state
+                .oauth_server_service
+                .redeem_code(
+                    &client,
+                    code,
+                    request.redirect_uri.as_deref(),
+                    request.code_verifier.as_deref(),
+                )
+                .await?;
+
+            let scope = grant.scopes.join(" ");
+            issue_tokens(&state, &client, grant.user_id, &scope, None, Some(&grant)).await?
+        }
+        "refresh_token" => refresh_tokens(&state, &client, &request).await?,
+        "client_credentials" => service_token(&state, &client, &request)?,
+        DEVICE_CODE_GRANT => {
+            let device_code = request
+                .device_code
+                .as_deref()
+                .ok_or_else(|| AppError::oauth("invalid_request", "Missing device_code"))?;
+            let grant = state
+                .oauth_server_service
+                .poll_device_code(&client, device_code)
+                .await?;
+
+            let scope = grant.scopes.join(" ");
+            issue_tokens(&state, &client, grant.user_id, &scope, None, None).await?
+        }
+        _ => {
+            return Err(AppError::oauth(
+                "unsupported_grant_type",
+                "Supported grants are authorization_code, refresh_token, client_credentials \
+                 and device_code",
+            ))
+        }
+    };
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store"), (header::PRAGMA, "no-cache")],
+        Json(response),
+    )
+        .into_response())
+}
+
+/// Rotate a client's refresh token, optionally narrowing the scope of the new access token
+async fn refresh_tokens(
+    state: &AppState,
+    client: &OAuthClient,
+    request: &TokenRequest,
+) -> Result<TokenResponse> {
+    let refresh_token = request
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| AppError::oauth("invalid_request", "Missing refresh_token"))?;
+
+    let claims = state
+        .jwt_service
+        .verify_refresh_token(refresh_token)
+        .map_err(invalid_grant)?;
+    let record = state
+        .token_service
+        .verify_refresh_token(refresh_token)
+        .await
+        .map_err(invalid_grant)?;
+
+    if record.client_id.as_deref() != Some(client.client_id.as_str()) {
+        return Err(AppError::oauth(
+            "invalid_grant",
+            "Refresh token was issued to another client",
+        ));
+    }
+    if claims.sub != record.user_id.to_string() {
+        return Err(AppError::oauth("invalid_grant", "Invalid refresh token"));
+    }
+
+    // A narrower scope may be asked for, never a wider one
+    let granted: Vec<&str> = record.scope.as_deref().unwrap_or("").split_whitespace().collect();
+    let scope = match request.scope.as_deref() {
+        Some(scope) => {
+            if let Some(extra) = scope.split_whitespace().find(|s| !granted.contains(s)) {
+                return Err(AppError::oauth(
+                    "invalid_scope",
+                    format!("Scope {} was not granted", extra),
+                ));
+            }
+            scope.split_whitespace().collect::<Vec<_>>().join(" ")
+        }
+        None => granted.join(" "),
+    };
+
+    issue_tokens(
+        state,
+        client,
+        record.user_id,
+        &scope,
+        Some((refresh_token, record.family_id)),
+        None,
+    )
+    .await
+}
+
+/// Client credentials grant: an access token for the service itself, with
+/// no user and no refresh token - the service just asks again
+fn service_token(
+    state: &AppState,
+    client: &OAuthClient,
+    request: &TokenRequest,
+) -> Result<TokenResponse> {
+    let scope = OAuthServerService::resolve_scopes(client, request.scope.as_deref())?.join(" ");
+    let access_token = state.jwt_service.generate_service_access_token(
+        &client.client_id,
+        Uuid::new_v4(),
+        &scope,
+    )?;
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: state.config.access_token_expiry,
+        refresh_token: None,
+        scope,
+        id_token: None,
+    })
+}
+
+/// Issue an access token and a refresh token to a client. With `rotate`, the
+/// presented refresh token is replaced within its family; otherwise a new
+/// family starts. A redeemed `grant` that includes `openid` adds an ID token.
+async fn issue_tokens(
+    state: &AppState,
+    client: &OAuthClient,
+    user_id: Uuid,
+    scope: &str,
+    rotate: Option<(&str, Uuid)>,
+    grant: Option<&AuthorizationGrant>,
+) -> Result<TokenResponse> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    if !user.is_active {
+        return Err(AppError::oauth("invalid_grant", "The user account is disabled"));
+    }
+
+    let token_id = Uuid::new_v4();
+    let family_id = rotate.map_or(token_id, |(_, family_id)| family_id);
+    let refresh_token = state
+        .jwt_service
+        .generate_refresh_token(user.id, token_id, family_id)?;
+
+    match rotate {
+        Some((old_token, _)) => state
+            .token_service
+            .rotate_refresh_token(old_token, token_id, &refresh_token, None, None)
+            .await
+            .map_err(invalid_grant)?,
+        None => {
+            state
+                .token_service
+                .store_client_refresh_token(
+                    token_id,
+                    family_id,
+                    user.id,
+                    &client.client_id,
+                    scope,
+                    &refresh_token,
+                )
+                .await?
+        }
+    }
+
+    let access_token = state.jwt_service.generate_client_access_token(
+        user.id,
+        token_id,
+        &client.client_id,
+        scope,
+    )?;
+
+    let id_token = match grant {
+        Some(grant) if grant.scopes.iter().any(|s| s == "openid") => {
+            Some(state.jwt_service.generate_id_token(
+                &user,
+                &client.client_id,
+                grant.auth_time,
+                grant.nonce.clone(),
+                grant.scopes.iter().any(|s| s == "email"),
+            )?)
+        }
+        _ => None,
+    };
+
+    Ok(TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: state.config.access_token_expiry,
+        refresh_token: Some(refresh_token),
+        scope: scope.to_string(),
+        id_token,
+    })
+}
+
+/// OpenID Connect userinfo endpoint, for access tokens issued with the `openid` scope
+pub async fn userinfo(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<UserInfoResponse>> {
+    let invalid_token = || AppError::oauth("invalid_token", "Invalid or expired access token");
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(invalid_token)?;
+
+    let claims = state
+        .jwt_service
+        .verify_client_access_token(token)
+        .map_err(|_| invalid_token())?;
+    if state.token_service.is_token_blacklisted(token).await?
+        || state.token_service.is_token_id_blacklisted(&claims.jti).await?
+    {
+        return Err(invalid_token());
+    }
+
+    let scopes: Vec<&str> = claims.scope.split_whitespace().collect();
+    if !scopes.contains(&"openid") {
+        return Err(AppError::oauth(
+            "insufficient_scope",
+            "The access token was not granted the openid scope",
+        ));
+    }
+
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| invalid_token())?;
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    if !user.is_active {
+        return Err(invalid_token());
+    }
+
+    Ok(Json(UserInfoResponse::new(user.into(), &scopes)))
+}
+
+/// Token introspection (RFC 7662) for confidential clients such as gateways.
+/// A token that doesn't check out for any reason is just `{"active": false}`.
+pub async fn introspect(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<IntrospectionRequest>,
+) -> Result<Response> {
+    let (client_id, client_secret) = client_credentials(
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+    let client = state
+        .oauth_server_service
+        .authenticate_client(&client_id, client_secret.as_deref())
+        .await?;
+    if client.client_secret_hash.is_none() {
+        return Err(AppError::oauth(
+            "invalid_client",
+            "Only confidential clients may introspect tokens",
+        ));
+    }
+
+    // The hint only decides which kind to try first
+    let token = request.token.as_str();
+    let response = if request.token_type_hint.as_deref() == Some("refresh_token") {
+        match introspect_refresh_token(&state, token).await? {
+            Some(response) => Some(response),
+            None => introspect_access_token(&state, token).await?,
+        }
+    } else {
+        match introspect_access_token(&state, token).await? {
+            Some(response) => Some(response),
+            None => introspect_refresh_token(&state, token).await?,
+        }
+    };
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store"), (header::PRAGMA, "no-cache")],
+        Json(response.unwrap_or_default()),
+    )
+        .into_response())
+}
+
+/// Introspect any of our access tokens: a user's, a service's or one issued
+/// to a client for a user. `None` when `token` isn't a valid access token.
+async fn introspect_access_token(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<IntrospectionResponse>> {
+    let (response, user_id): (IntrospectionResponse, _) =
+        match state.jwt_service.verify_api_access_token(token) {
+            Ok(ApiAccessTokenClaims::User(claims)) => {
+                let user_id = Uuid::parse_str(&claims.sub).ok();
+                (claims.into(), user_id)
+            }
+            Ok(ApiAccessTokenClaims::Service(claims)) => {
+                let allowed = state
+                    .oauth_server_service
+                    .find_client(&claims.client_id)
+                    .await?
+                    .is_some_and(|client| client.allows_grant("client_credentials"));
+                if !allowed {
+                    return Ok(Some(IntrospectionResponse::default()));
+                }
+                (claims.into(), None)
+            }
+            Err(_) => match state.jwt_service.verify_client_access_token(token) {
+                Ok(claims) => {
+                    let client = state.oauth_server_service.find_client(&claims.client_id).await?;
+                    if client.is_none() {
+                        return Ok(Some(IntrospectionResponse::default()));
+                    }
+                    let user_id = Uuid::parse_str(&claims.sub).ok();
+                    (claims.into(), user_id)
+                }
+                Err(_) => return Ok(None),
+            },
+        };
+
+    let jti = response.jti.as_deref().unwrap_or_default();
+    let revoked = state.token_service.is_token_blacklisted(token).await?
+        || state.token_service.is_token_id_blacklisted(jti).await?
+        || match Uuid::parse_str(jti) {
+            Ok(token_id) => state.token_service.is_session_revoked(token_id).await?,
+            Err(_) => false,
+        };
+    let user_active = match user_id {
+        Some(user_id) => state.user_service.is_user_active(user_id).await?,
+        None => true,
+    };
+    if revoked || !user_active {
+        return Ok(Some(IntrospectionResponse::default()));
+    }
+
+    Ok(Some(IntrospectionResponse {
+        active: true,
+        token_type: Some("access_token".to_string()),
+        ..response
+    }))
+}
+
+/// Introspect a refresh token against its stored revocation state. `None`
+/// when `token` isn't a valid refresh token.
+async fn introspect_refresh_token(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<IntrospectionResponse>> {
+    let Ok(claims) = state.jwt_service.verify_refresh_token(token) else {
+        return Ok(None);
+    };
+
+    // Read-only: introspecting a rotated token must not count as reuse
+    let Some(record) = state.token_service.find_refresh_token(token).await? else {
+        return Ok(Some(IntrospectionResponse::default()));
+    };
+    let active = record.revoked_at.is_none()
+        && record.replaced_by_token.is_none()
+        && record.expires_at > Utc::now()
+        && state.user_service.is_user_active(record.user_id).await?;
+    if !active {
+        return Ok(Some(IntrospectionResponse::default()));
+    }
+
+    Ok(Some(IntrospectionResponse {
+        active: true,
+        scope: record.scope,
+        client_id: record.client_id,
+        token_type: Some("refresh_token".to_string()),
+        exp: Some(claims.exp),
+        iat: Some(claims.iat),
+        sub: Some(claims.sub),
+        aud: Some(claims.aud),
+        iss: Some(claims.iss),
+        jti: Some(claims.jti),
+        ..Default::default()
+    }))
+}
+
+/// Token revocation (RFC 7009). Clients revoke the access and refresh tokens
+/// issued to them; a token we don't recognise gets a 200 all the same.
+pub async fn revoke(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<RevocationRequest>,
+) -> Result<StatusCode> {
+    let (client_id, client_secret) = client_credentials(
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+    let client = state
+        .oauth_server_service
+        .authenticate_client(&client_id, client_secret.as_deref())
+        .await?;
+
+    // The hint only decides which kind to try first
+    let token = request.token.as_str();
+    if request.token_type_hint.as_deref() == Some("access_token") {
+        if !revoke_access_token(&state, &client, token).await? {
+            revoke_refresh_token(&state, &client, token).await?;
+        }
+    } else if !revoke_refresh_token(&state, &client, token).await? {
+        revoke_access_token(&state, &client, token).await?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+fn not_issued_to(client: &OAuthClient) -> AppError {
+    AppError::oauth(
+        "unauthorized_client",
+        format!("The token was not issued to {}", client.client_id),
+    )
+}
+
+/// Blacklist an access token for the rest of its lifetime. False when
+/// `token` isn't a valid access token.
+async fn revoke_access_token(state: &AppState, client: &OAuthClient, token: &str) -> Result<bool> {
+    let (client_id, exp) = match state.jwt_service.verify_client_access_token(token) {
+        Ok(claims) => (claims.client_id, claims.exp),
+        Err(_) => match state.jwt_service.verify_api_access_token(token) {
+            Ok(ApiAccessTokenClaims::Service(claims)) => (claims.client_id, claims.exp),
+            Ok(ApiAccessTokenClaims::User(_)) => return Err(not_issued_to(client)),
+            Err(_) => return Ok(false),
+        },
+    };
+    if client_id != client.client_id {
+        return Err(not_issued_to(client));
+    }
+
+    let remaining = (exp - Utc::now().timestamp()).max(1);
+    state.token_service.blacklist_access_token(token, remaining).await?;
+
+    Ok(true)
+}
+
+/// Revoke a refresh token along with the access tokens issued with it.
+/// False when we never issued `token`.
+async fn revoke_refresh_token(state: &AppState, client: &OAuthClient, token: &str) -> Result<bool> {
+    let Some(record) = state.token_service.find_refresh_token(token).await? else {
+        return Ok(false);
+    };
+    if record.client_id.as_deref() != Some(client.client_id.as_str()) {
+        return Err(not_issued_to(client));
+    }
+
+    if record.revoked_at.is_none() {
+        state.token_service.revoke_token(token).await?;
+        // Access tokens carry their refresh token's id as `jti`
+        state
+            .token_service
+            .blacklist_token_id(record.id, state.config.access_token_expiry)
+            .await?;
+    }
+
+    Ok(true)
+}
+
+/// Client credentials from HTTP Basic auth or the form body (not both)
+fn client_credentials(
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<(String, Option<String>)> {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "));
+
+    match basic {
+        Some(encoded) => {
+            if client_secret.is_some() {
+                return Err(AppError::oauth(
+                    "invalid_request",
+                    "Use one client authentication method",
+                ));
+            }
+
+            let invalid_client = || AppError::oauth("invalid_client", "Malformed Basic credentials");
+            let decoded = STANDARD.decode(encoded).map_err(|_| invalid_client())?;
+            let decoded = String::from_utf8(decoded).map_err(|_| invalid_client())?;
+            let (id, secret) = decoded.split_once(':').ok_or_else(invalid_client)?;
+
+            // Both parts are form-encoded before being joined (RFC 6749 section 2.3.1)
+            let id = urlencoding::decode(id).map_err(|_| invalid_client())?;
+            let secret = urlencoding::decode(secret).map_err(|_| invalid_client())?;
+
+            Ok((id.into_owned(), Some(secret.into_owned())))
+        }
+        None => {
+            let client_id =
+                client_id.ok_or_else(|| AppError::oauth("invalid_client", "Missing client_id"))?;
+            Ok((client_id.to_string(), client_secret.map(str::to_string)))
+        }
+    }
+}
+
+/// Token errors become `invalid_grant`, as the spec asks; anything else stays a server error
+fn invalid_grant(e: AppError) -> AppError {
+    match e {
+        AppError::InvalidToken | AppError::TokenExpired | AppError::TokenRevoked => {
+            AppError::oauth("invalid_grant", "Invalid or expired refresh token")
+        }
+        e => e,
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{
+    error::{AppError, Result},
+    handlers::auth::create_auth_cookie,
+    middleware::OrgMembership,
+    models::{
+        AccessTokenClaims, AuthResponse, CreateOrganizationRequest, MessageResponse, OrgRole,
+        OrganizationResponse, OrganizationsResponse, UpdateOrganizationRequest,
+    },
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Create an organization owned by the current user
+pub async fn create_organization(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<CreateOrganizationRequest>,
+) -> Result<impl IntoResponse> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let organization = state
+        .organization_service
+        .create(user_id, &payload.name, &payload.slug)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(organization)))
+}
+
+/// List the organizations the current user belongs to, and which one they're working in
+pub async fn list_organizations(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    claims: Option<Extension<AccessTokenClaims>>,
+) -> Result<Json<OrganizationsResponse>> {
+    let organizations = state.organization_service.list_for_user(user_id).await?;
+    let active_organization_id = claims
+        .and_then(|Extension(claims)| claims.org_id)
+        .and_then(|id| Uuid::parse_str(&id).ok());
+
+    Ok(Json(OrganizationsResponse {
+        organizations,
+        active_organization_id,
+    }))
+}
+
+/// The organization the current user is working in
+pub async fn current_organization(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    membership: OrgMembership,
+) -> Result<Json<OrganizationResponse>> {
+    let organization = state
+        .organization_service
+        .find_for_member(membership.organization_id, user_id)
+        .await?
+        .ok_or(AppError::OrganizationNotFound)?;
+
+    Ok(Json(organization))
+}
+
+/// Rename the current organization - admins and owners only
+pub async fn update_organization(
+    State(state): State<AppState>,
+    membership: OrgMembership,
+    Json(payload): Json<UpdateOrganizationRequest>,
+) -> Result<Json<MessageResponse>> {
+    membership.require_role(OrgRole::Admin)?;
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    state
+        .organization_service
+        .rename(membership.organization_id, &payload.name)
+        .await?;
+
+    Ok(Json(MessageResponse {
+        message: "Organization updated.".to_string(),
+    }))
+}
+
+/// Work in another of the user's organizations. The session moves there and a
+/// new access token naming it is set as an HttpOnly cookie.
+pub async fn switch_organization(
+    State(state): State<AppState>,
+    Extension(claims): Extension<AccessTokenClaims>,
+    Path(organization_id): Path<Uuid>,
+) -> Result<Response> {
+    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidToken)?;
+    let token_id = Uuid::parse_str(&claims.jti).map_err(|_| AppError::InvalidToken)?;
+
+    state
+        .organization_service
+        .member_role(organization_id, user_id)
+        .await?
+        .ok_or(AppError::OrganizationNotFound)?;
+
+    // Access tokens carry their session's current refresh token id as `jti`
+    state
+        .token_service
+        .set_session_organization(token_id, Some(organization_id))
+        .await?;
+
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    let access = state.role_service.access_for(user.id).await?;
+    let access_token = state.jwt_service.generate_access_token(
+        &user,
+        token_id,
+        &access,
+        Some(organization_id),
+    )?;
+
+    let access_cookie = create_auth_cookie(
+        "accessToken".to_string(),
+        access_token,
+        state.config.access_token_expiry,
+        state.config.environment.is_production(),
+    );
+
+    let mut response = Json(AuthResponse {
+        access_token: "set_in_cookie".into(),
+        refresh_token: "set_in_cookie".into(),
+        token_type: "Bearer".into(),
+        expires_in: state.config.access_token_expiry,
+    })
+    .into_response();
+
+    response.headers_mut().append(
+        header::SET_COOKIE,
+        access_cookie.to_string().parse().unwrap(),
+    );
+
+    Ok(response)
+}
@@ -0,0 +1,128 @@
+use crate::{
+    error::{AppError, Result},
+    handlers::auth::issue_session,
+    models::{
+        FinishPasskeyLoginRequest, FinishPasskeyRegistrationRequest, MessageResponse,
+        PasskeyCreationOptionsResponse, PasskeyRequestOptionsResponse, PasskeyResponse,
+        PasskeysResponse, RenamePasskeyRequest,
+    },
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Start registering a passkey for the current user
+pub async fn start_registration(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<PasskeyCreationOptionsResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    let public_key = state
+        .webauthn_service
+        .start_registration(user.id, &user.email)
+        .await?;
+
+    Ok(Json(PasskeyCreationOptionsResponse { public_key }))
+}
+
+/// Finish registering a passkey with the authenticator's attestation response
+pub async fn finish_registration(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<FinishPasskeyRegistrationRequest>,
+) -> Result<impl IntoResponse> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let passkey = state
+        .webauthn_service
+        .finish_registration(user_id, payload.name, &payload.credential)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(passkey)))
+}
+
+/// List the current user's passkeys
+pub async fn list_passkeys(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<PasskeysResponse>> {
+    let passkeys = state.webauthn_service.list_credentials(user_id).await?;
+
+    Ok(Json(PasskeysResponse { passkeys }))
+}
+
+/// Rename one of the current user's passkeys
+pub async fn rename_passkey(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(passkey_id): Path<Uuid>,
+    Json(payload): Json<RenamePasskeyRequest>,
+) -> Result<Json<PasskeyResponse>> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let passkey = state
+        .webauthn_service
+        .rename_credential(user_id, passkey_id, &payload.name)
+        .await?;
+
+    Ok(Json(passkey))
+}
+
+/// Delete one of the current user's passkeys
+pub async fn delete_passkey(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(passkey_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    state
+        .webauthn_service
+        .delete_credential(user_id, passkey_id)
+        .await?;
+
+    Ok(Json(MessageResponse {
+        message: "Passkey deleted.".to_string(),
+    }))
+}
+
+/// Start a passkey login ceremony
+pub async fn start_login(
+    State(state): State<AppState>,
+) -> Result<Json<PasskeyRequestOptionsResponse>> {
+    let public_key = state.webauthn_service.start_authentication().await?;
+
+    Ok(Json(PasskeyRequestOptionsResponse { public_key }))
+}
+
+/// Finish a passkey login ceremony - Sets HttpOnly cookies like `login`
+pub async fn finish_login(
+    State(state): State<AppState>,
+    Json(payload): Json<FinishPasskeyLoginRequest>,
+) -> Result<Response> {
+    let user_id = state
+        .webauthn_service
+        .finish_authentication(&payload.credential)
+        .await?;
+
+    let user = state.user_service.get_user_by_id(user_id).await?;
+
+    if !user.is_active {
+        return Err(AppError::Unauthorized);
+    }
+
+    if !user.email_verified {
+        return Err(AppError::EmailNotVerified);
+    }
+
+    issue_session(&state, &user).await
+}
@@ -0,0 +1,68 @@
+use crate::{
+    error::{AppError, Result},
+    models::{
+        CreatePersonalTokenRequest, CreatedPersonalTokenResponse, MessageResponse,
+        PersonalTokensResponse,
+    },
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use uuid::Uuid;
+use validator::Validate;
+
+/// List the current user's personal access tokens
+pub async fn list_tokens(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<PersonalTokensResponse>> {
+    let tokens = state.personal_token_service.list(user_id).await?;
+
+    Ok(Json(PersonalTokensResponse { tokens }))
+}
+
+/// Create a personal access token. The response is the only time the token is shown.
+pub async fn create_token(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<CreatePersonalTokenRequest>,
+) -> Result<impl IntoResponse> {
+    payload
+        .validate()
+        .map_err(|e| AppError::Validation(e.to_string()))?;
+
+    let (token, details) = state
+        .personal_token_service
+        .create(
+            user_id,
+            &payload.name,
+            &payload.scopes,
+            payload.expires_in_days,
+        )
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedPersonalTokenResponse { token, details }),
+    ))
+}
+
+/// Revoke one of the current user's personal access tokens
+pub async fn revoke_token(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(token_id): Path<Uuid>,
+) -> Result<Json<MessageResponse>> {
+    state
+        .personal_token_service
+        .revoke(user_id, token_id)
+        .await?;
+
+    Ok(Json(MessageResponse {
+        message: "Token revoked.".to_string(),
+    }))
+}
@@ -0,0 +1,66 @@
+use crate::{
+    error::Result,
+    models::{MessageResponse, RolesResponse, UserRolesResponse},
+    state::AppState,
+};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use uuid::Uuid;
+
+/// List the roles and the permissions each grants
+pub async fn list_roles(State(state): State<AppState>) -> Result<Json<RolesResponse>> {
+    let roles = state.role_service.list_roles().await?;
+
+    Ok(Json(RolesResponse { roles }))
+}
+
+/// A user's roles and the permissions they add up to
+pub async fn get_user_roles(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<UserRolesResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    let access = state.role_service.access_for(user.id).await?;
+
+    Ok(Json(UserRolesResponse {
+        roles: access.roles,
+        permissions: access.permissions,
+    }))
+}
+
+/// Give a user a role - it takes effect when their access token is next refreshed
+pub async fn grant_role(
+    State(state): State<AppState>,
+    Path((user_id, role)): Path<(Uuid, String)>,
+) -> Result<Json<MessageResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    state.role_service.grant_role(user.id, &role).await?;
+
+    Ok(Json(MessageResponse {
+        message: format!("Granted {}.", role),
+    }))
+}
+
+/// Take a role away from a user and end their sessions, so no access token
+/// still carries it
+pub async fn revoke_role(
+    State(state): State<AppState>,
+    Path((user_id, role)): Path<(Uuid, String)>,
+) -> Result<Json<MessageResponse>> {
+    let user = state.user_service.get_user_by_id(user_id).await?;
+    if state.role_service.revoke_role(user.id, &role).await? {
+        let sessions = state.token_service.revoke_all_sessions(user.id).await?;
+        tracing::info!(
+            "Ended {} sessions of user {} after revoking {}",
+            sessions,
+            user.id,
+            role
+        );
+    }
+
+    Ok(Json(MessageResponse {
+        message: format!("Revoked {}.", role),
+    }))
+}
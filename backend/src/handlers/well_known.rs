@@ -0,0 +1,127 @@
+use crate::{
+    config::Config, models::OpenIdConfiguration, services::oauth_server::DEVICE_CODE_GRANT,
+    state::AppState,
+};
+use axum::{extract::State, http::header, response::IntoResponse, Json};
+
+/// Publish the public signing keys so other services can verify our tokens
+pub async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(state.jwt_service.jwks()),
+    )
+}
+
+/// OpenID Connect discovery, so off-the-shelf client libraries can configure themselves
+pub async fn openid_configuration(State(state): State<AppState>) -> impl IntoResponse {
+    let configuration = discovery_document(&state.config, state.jwt_service.public_algorithms());
+
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(configuration),
+    )
+}
+
+/// The discovery document for our endpoints and the algorithms of the published keys
+fn discovery_document(config: &Config, signing_algorithms: Vec<String>) -> OpenIdConfiguration {
+    let base = config.public_url.trim_end_matches('/');
+
+    OpenIdConfiguration {
+        issuer: config.jwt_issuer.clone(),
+        authorization_endpoint: format!("{}/oauth/authorize", base),
+        token_endpoint: format!("{}/oauth/token", base),
+        userinfo_endpoint: format!("{}/oauth/userinfo", base),
+        introspection_endpoint: format!("{}/oauth/introspect", base),
+        revocation_endpoint: format!("{}/oauth/revoke", base),
+        device_authorization_endpoint: format!("{}/oauth/device_authorization", base),
+        jwks_uri: format!("{}/.well-known/jwks.json", base),
+        scopes_supported: vec!["openid", "profile", "email"],
+        response_types_supported: vec!["code"],
+        response_modes_supported: vec!["query"],
+        grant_types_supported: vec![
+            "authorization_code",
+            "refresh_token",
+            "client_credentials",
+            DEVICE_CODE_GRANT,
+        ],
+        subject_types_supported: vec!["public"],
+        id_token_signing_alg_values_supported: signing_algorithms,
+        token_endpoint_auth_methods_supported: vec![
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ],
+        code_challenge_methods_supported: vec!["S256"],
+        claims_supported: vec![
+            "iss",
+            "sub",
+            "aud",
+            "exp",
+            "iat",
+            "auth_time",
+            "nonce",
+            "email",
+            "email_verified",
+        ],
+        authorization_response_iss_parameter_supported: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn discovery_names_our_issuer_endpoints_and_capabilities() {
+        let mut config = Config::for_tests();
+        config.public_url = "https://auth.example.com/".to_string();
+        config.jwt_issuer = "https://auth.example.com".to_string();
+
+        let document =
+            serde_json::to_value(discovery_document(&config, vec!["EdDSA".to_string()])).unwrap();
+        let list = |key: &str| -> Vec<&str> {
+            document[key]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(Value::as_str)
+                .collect()
+        };
+
+        assert_eq!(document["issuer"], "https://auth.example.com");
+        assert_eq!(
+            document["authorization_endpoint"],
+            "https://auth.example.com/oauth/authorize"
+        );
+        assert_eq!(
+            document["token_endpoint"],
+            "https://auth.example.com/oauth/token"
+        );
+        assert_eq!(
+            document["userinfo_endpoint"],
+            "https://auth.example.com/oauth/userinfo"
+        );
+        assert_eq!(
+            document["jwks_uri"],
+            "https://auth.example.com/.well-known/jwks.json"
+        );
+
+        assert_eq!(list("response_types_supported"), ["code"]);
+        assert_eq!(list("code_challenge_methods_supported"), ["S256"]);
+        assert_eq!(list("id_token_signing_alg_values_supported"), ["EdDSA"]);
+        assert!(list("scopes_supported").contains(&"openid"));
+        assert!(list("grant_types_supported").contains(&DEVICE_CODE_GRANT));
+        for claim in ["sub", "nonce", "auth_time", "email", "email_verified"] {
+            assert!(
+                list("claims_supported").contains(&claim),
+                "{} missing",
+                claim
+            );
+        }
+        assert_eq!(
+            document["authorization_response_iss_parameter_supported"],
+            true
+        );
+    }
+}
@@ -1,10 +1,23 @@
+mod cli;
 mod config;
 mod error;
 mod handlers {
+    pub mod admin;
     pub mod auth;
+    pub mod identities;
+    pub mod invitations;
+    pub mod mfa;
+    pub mod oauth;
+    pub mod oauth_server;
+    pub mod organizations;
+    pub mod passkeys;
+    pub mod personal_tokens;
+    pub mod roles;
+    pub mod well_known;
 }
 mod middleware;
 mod models;
+mod rate_limit;
 mod routes;
 mod services {
     pub mod jwt;
@@ -13,12 +26,28 @@ mod services {
     pub mod users;
     pub mod email;
     pub mod verification;
+    pub mod mfa;
+    pub mod webauthn;
+    pub mod security_events;
+    pub mod signing_keys;
+    pub mod login_attempts;
+    pub mod oauth;
+    pub mod identities;
+    pub mod invitations;
+    pub mod oauth_clients;
+    pub mod oauth_server;
+    pub mod organizations;
+    pub mod personal_tokens;
+    pub mod roles;
 }
 mod state;
+#[cfg(test)]
+mod stub_idp;
 mod tasks;
 
 use config::Config;
 use redis::aio::ConnectionManager;
+use rate_limit::RateLimiter;
 use routes::create_router;
 use services::{
     jwt::JwtService,
@@ -26,6 +55,19 @@ use services::{
     users::UserService,
     email::EmailService,
     verification::VerificationService,
+    mfa::MfaService,
+    webauthn::WebAuthnService,
+    security_events::SecurityEventService,
+    signing_keys::SigningKeyStore,
+    login_attempts::LoginAttemptService,
+    oauth::OAuthService,
+    identities::IdentityService,
+    invitations::InvitationService,
+    oauth_clients::OAuthClientStore,
+    oauth_server::OAuthServerService,
+    organizations::OrganizationService,
+    personal_tokens::PersonalTokenService,
+    roles::RoleService,
 };
 use sqlx::postgres::PgPoolOptions;
 use state::AppState;
@@ -38,6 +80,12 @@ async fn main() -> anyhow::Result<()> {
     // Load configuration first to determine environment
     let config = Config::from_env()?;
 
+    // Admin commands (e.g. `backend keys promote <kid>`) run and exit without starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&config, &args).await;
+    }
+
     // Initialize tracing with environment-specific log level
     tracing_subscriber::registry()
         .with(
@@ -65,6 +113,7 @@ async fn main() -> anyhow::Result<()> {
         tracing::debug!("Redis URL: {}", mask_connection_string(&config.redis_url));
         tracing::debug!("JWT Issuer: {}", config.jwt_issuer);
         tracing::debug!("JWT Audience: {}", config.jwt_audience);
+        tracing::debug!("JWT Algorithm: {:?}", config.jwt_algorithm);
     }
 
     // Setup database connection pool with environment-specific settings
@@ -88,18 +137,52 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Redis connection established");
 
     // Initialize services
-    let jwt_service = JwtService::new(config.clone());
+    let jwt_service = JwtService::new(config.clone())?;
+    let signing_key_store = SigningKeyStore::new(db_pool.clone());
+    jwt_service.seed_key_store(&signing_key_store).await?;
+    jwt_service.reload_keys(&signing_key_store).await?;
     let user_service = UserService::new(db_pool.clone());
-    let token_service = TokenService::new(db_pool.clone(), redis_conn, config.clone());
+    let security_event_service = SecurityEventService::new(db_pool.clone());
+    let token_service = TokenService::new(
+        db_pool.clone(),
+        redis_conn.clone(),
+        config.clone(),
+        security_event_service.clone(),
+    );
 
     // New email & verification services
     let email_service = EmailService::new(&config.clone())?;
     let verification_service = VerificationService::new(db_pool.clone(), config.clone());
+    let mfa_service = MfaService::new(db_pool.clone(), redis_conn.clone(), config.clone())?;
+    let webauthn_service =
+        WebAuthnService::new(db_pool.clone(), redis_conn.clone(), config.clone());
+    let login_attempt_service = LoginAttemptService::new(redis_conn.clone(), config.clone());
+    let oauth_service = OAuthService::new(redis_conn.clone(), config.clone())?;
+    let identity_service = IdentityService::new(db_pool.clone(), redis_conn.clone());
+    let oauth_server_service = OAuthServerService::new(
+        OAuthClientStore::new(db_pool.clone()),
+        redis_conn.clone(),
+        config.clone(),
+    );
+    let personal_token_service = PersonalTokenService::new(db_pool.clone());
+    let role_service = RoleService::new(db_pool.clone());
+    let organization_service = OrganizationService::new(db_pool.clone());
+    let invitation_service = InvitationService::new(db_pool.clone());
+    let rate_limiter = RateLimiter::new(
+        &config.rate_limit_backend,
+        redis_conn,
+        config.trust_proxy_headers,
+    );
 
     // Start background cleanup task - ADD THIS SECTION
     tasks::cleanup_expired_tokens::start_token_cleanup_task(token_service.clone());
     tracing::info!("Background token cleanup task initialized");
 
+    tasks::refresh_signing_keys::start_signing_key_refresh_task(
+        jwt_service.clone(),
+        signing_key_store,
+    );
+
     // Create application state
     let app_state = AppState {
         config: config.clone(),
@@ -108,6 +191,18 @@ async fn main() -> anyhow::Result<()> {
         user_service,
         email_service,
         verification_service,
+        mfa_service,
+        webauthn_service,
+        login_attempt_service,
+        oauth_service,
+        identity_service,
+        oauth_server_service,
+        personal_token_service,
+        role_service,
+        organization_service,
+        invitation_service,
+        security_event_service,
+        rate_limiter,
     };
 
     // Environment-specific CORS configuration
@@ -115,14 +210,14 @@ async fn main() -> anyhow::Result<()> {
         tracing::info!("Configuring strict CORS for production");
         CorsLayer::new()
             .allow_origin(config.frontend_url.parse::<axum::http::HeaderValue>()?)
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
             .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
             .allow_credentials(true)
     } else {
         tracing::info!("Configuring permissive CORS for development");
         CorsLayer::new()
             .allow_origin(config.frontend_url.parse::<axum::http::HeaderValue>()?)
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE, Method::OPTIONS])
             .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
             .allow_credentials(true)
     };
@@ -152,6 +247,18 @@ async fn main() -> anyhow::Result<()> {
 
     if config.is_production() {
         tracing::warn!("Running in PRODUCTION mode - ensure all security measures are in place");
+
+        // OpenID Connect clients fetch discovery from the issuer and verify ID tokens with our JWKS
+        if config.jwt_issuer != config.public_url {
+            tracing::warn!(
+                "JWT_ISSUER ({}) differs from PUBLIC_URL ({}) - OpenID Connect discovery won't work",
+                config.jwt_issuer,
+                config.public_url
+            );
+        }
+        if config.jwt_algorithm == jsonwebtoken::Algorithm::HS256 {
+            tracing::warn!("Signing with HS256 - ID tokens need an RS256 or EdDSA key");
+        }
     } else {
         tracing::info!("Running in DEVELOPMENT mode - debug features enabled");
     }
@@ -171,7 +278,12 @@ async fn main() -> anyhow::Result<()> {
         tracing::info!("  - Token cleanup runs every hour in background");  // Add this line
     }
 
-    axum::serve(listener, app).await?;
+    // Connection info lets handlers see the client's IP address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
@@ -2,17 +2,70 @@
 
 use crate::{
     error::{AppError, Result},
+    models::{AccessTokenClaims, ApiAccessTokenClaims, OrgRole},
+    services::personal_tokens::TOKEN_PREFIX,
     state::AppState,
 };
 use axum::{
-    extract::{Request, State},
-    http::{header},
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, OriginalUri, Request, State},
+    http::{header, request::Parts, Extensions, HeaderMap},
     middleware::Next,
-    response::{Response},
+    response::{IntoResponse, Response},
 };
+use std::{
+    convert::Infallible,
+    future::{ready, Future},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
 use uuid::Uuid;
 
-/// Extract user ID from Authorization header
+/// Who a request to `/api` acts for
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// A signed-in user
+    User(Uuid),
+    /// A user's script holding a personal access token
+    PersonalToken(Uuid),
+    /// A backend service holding a client credentials token
+    Service {
+        client_id: String,
+        scopes: Vec<String>,
+    },
+}
+
+impl Principal {
+    /// Services need `scope` granted; users are checked by the handler
+    pub fn require_service_scope(&self, scope: &str) -> Result<()> {
+        match self {
+            Principal::Service { scopes, .. } if !scopes.iter().any(|s| s == scope) => {
+                Err(AppError::Forbidden)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Principal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or(AppError::Unauthorized)
+    }
+}
+
+/// Authenticate a user or a service from the Authorization header
 pub async fn auth_middleware(
     State(state): State<AppState>,
     mut req: Request,
@@ -20,28 +73,196 @@ pub async fn auth_middleware(
 ) -> Result<Response> {
     let token = extract_token_from_header(&req)?;
 
+    if token.starts_with(TOKEN_PREFIX) {
+        let ip_address = client_ip(
+            req.headers(),
+            req.extensions(),
+            state.config.trust_proxy_headers,
+        );
+        let personal_token = state
+            .personal_token_service
+            .authenticate(&token, Some(ip_address))
+            .await?
+            .ok_or(AppError::Unauthorized)?;
+
+        if !state.user_service.is_user_active(personal_token.user_id).await? {
+            return Err(AppError::Unauthorized);
+        }
+
+        // `read` tokens may only look, `write` tokens may change things
+        let needed = if req.method().is_safe() { "read" } else { "write" };
+        if !personal_token.scopes.iter().any(|s| s == needed) {
+            return Err(AppError::Forbidden);
+        }
+
+        req.extensions_mut().insert(personal_token.user_id);
+        req.extensions_mut()
+            .insert(Principal::PersonalToken(personal_token.user_id));
+
+        return Ok(next.run(req).await);
+    }
+
     // Check if token is blacklisted
     if state.token_service.is_token_blacklisted(&token).await? {
         return Err(AppError::TokenRevoked);
     }
 
     // Verify token
-    let claims = state.jwt_service.verify_access_token(&token)?;
+    let principal = match state.jwt_service.verify_api_access_token(&token)? {
+        ApiAccessTokenClaims::User(claims) => {
+            // Check if its session family was revoked (e.g. refresh token reuse)
+            if state.token_service.is_token_id_blacklisted(&claims.jti).await? {
+                return Err(AppError::TokenRevoked);
+            }
 
-    // Parse user ID
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidToken)?;
+            // Parse user ID
+            let user_id = Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidToken)?;
 
-    // Check if user is active
-    if !state.user_service.is_user_active(user_id).await? {
-        return Err(AppError::Unauthorized);
-    }
+            // Check if user is active
+            if !state.user_service.is_user_active(user_id).await? {
+                return Err(AppError::Unauthorized);
+            }
+
+            // An impersonation ends with the admin's own access, and every request is on record
+            if let Some(actor) = &claims.act {
+                let actor_id = Uuid::parse_str(&actor.sub).map_err(|_| AppError::InvalidToken)?;
+                match state.user_service.is_user_active(actor_id).await {
+                    Ok(true) => {}
+                    Ok(false) | Err(AppError::UserNotFound) => return Err(AppError::Unauthorized),
+                    Err(e) => return Err(e),
+                }
+
+                // Nested routers see the path without their prefix
+                let path = req
+                    .extensions()
+                    .get::<OriginalUri>()
+                    .map_or(req.uri().path(), |OriginalUri(uri)| uri.path());
+                tracing::info!(
+                    "Impersonated request {} {} by {} acting as {}",
+                    req.method(),
+                    path,
+                    actor_id,
+                    user_id
+                );
+            }
 
-    // Insert user_id into request extensions for handlers to use
-    req.extensions_mut().insert(user_id);
+            // Insert user_id (and the claims, for handlers that need the session) into request extensions
+            req.extensions_mut().insert(user_id);
+            req.extensions_mut().insert(claims);
+
+            Principal::User(user_id)
+        }
+        ApiAccessTokenClaims::Service(claims) => {
+            if state.token_service.is_token_id_blacklisted(&claims.jti).await? {
+                return Err(AppError::TokenRevoked);
+            }
+
+            // A removed or deactivated client loses access right away
+            let client = state
+                .oauth_server_service
+                .find_client(&claims.client_id)
+                .await?
+                .filter(|client| client.allows_grant("client_credentials"))
+                .ok_or(AppError::Unauthorized)?;
+
+            Principal::Service {
+                client_id: client.client_id,
+                scopes: claims.scope.split_whitespace().map(str::to_string).collect(),
+            }
+        }
+    };
+
+    req.extensions_mut().insert(principal);
 
     Ok(next.run(req).await)
 }
 
+/// For routes that act on the signed-in user's own account - services get 403
+pub async fn require_user(req: Request, next: Next) -> Result<Response> {
+    match req.extensions().get::<Principal>() {
+        Some(Principal::User(_) | Principal::PersonalToken(_)) => Ok(next.run(req).await),
+        Some(Principal::Service { .. }) => Err(AppError::Forbidden),
+        None => Err(AppError::Unauthorized),
+    }
+}
+
+/// For routes that manage how the account signs in and what can act for it -
+/// personal access tokens and impersonation sessions get 403 too, so a leaked
+/// key can't entrench itself and support staff can't change the account's security
+pub async fn require_session(req: Request, next: Next) -> Result<Response> {
+    let impersonated = req
+        .extensions()
+        .get::<AccessTokenClaims>()
+        .is_some_and(|claims| claims.act.is_some());
+
+    match req.extensions().get::<Principal>() {
+        Some(Principal::User(_)) if !impersonated => Ok(next.run(req).await),
+        Some(_) => Err(AppError::Forbidden),
+        None => Err(AppError::Unauthorized),
+    }
+}
+
+/// Layer letting through only principals holding `permission` - users through
+/// their roles, services through a scope of the same name. Personal access
+/// tokens never carry permissions. Goes inside `auth_middleware`, e.g.
+/// `.route_layer(RequirePermission("users:write"))` before it.
+#[derive(Debug, Clone, Copy)]
+pub struct RequirePermission(pub &'static str);
+
+impl<S> Layer<S> for RequirePermission {
+    type Service = RequirePermissionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequirePermissionService {
+            inner,
+            permission: self.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequirePermissionService<S> {
+    inner: S,
+    permission: &'static str,
+}
+
+impl<S> Service<Request> for RequirePermissionService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        match check_permission(req.extensions(), self.permission) {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(e) => Box::pin(ready(Ok(e.into_response()))),
+        }
+    }
+}
+
+fn check_permission(extensions: &Extensions, permission: &str) -> Result<()> {
+    let granted = match extensions.get::<Principal>().ok_or(AppError::Unauthorized)? {
+        Principal::User(_) => extensions
+            .get::<AccessTokenClaims>()
+            .is_some_and(|claims| claims.permissions.iter().any(|p| p == permission)),
+        Principal::Service { scopes, .. } => scopes.iter().any(|s| s == permission),
+        Principal::PersonalToken(_) => false,
+    };
+
+    if !granted {
+        return Err(AppError::Forbidden);
+    }
+
+    Ok(())
+}
+
 /// Extract bearer token from Authorization header
 fn extract_token_from_header(req: &Request) -> Result<String> {
     let auth_header = req
@@ -69,4 +290,240 @@ impl RequestExt for Request {
             .copied()
             .ok_or(AppError::Unauthorized)
     }
-}
\ No newline at end of file
+}
+
+/// The signed-in user's membership of the organization their token is working
+/// in. Checked against the database, so removed members lose access right away.
+pub struct OrgMembership {
+    pub organization_id: Uuid,
+    pub role: OrgRole,
+}
+
+impl OrgMembership {
+    /// At least `role` in the organization, e.g. `OrgRole::Admin` to manage it
+    pub fn require_role(&self, role: OrgRole) -> Result<()> {
+        if self.role < role {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for OrgMembership {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let user_id = parts
+            .extensions
+            .get::<Uuid>()
+            .copied()
+            .ok_or(AppError::Unauthorized)?;
+
+        let organization_id = parts
+            .extensions
+            .get::<AccessTokenClaims>()
+            .and_then(|claims| claims.org_id.as_deref())
+            .ok_or(AppError::NoActiveOrganization)?;
+        let organization_id =
+            Uuid::parse_str(organization_id).map_err(|_| AppError::InvalidToken)?;
+
+        let role = state
+            .organization_service
+            .member_role(organization_id, user_id)
+            .await?
+            .ok_or(AppError::Forbidden)?;
+
+        Ok(OrgMembership {
+            organization_id,
+            role,
+        })
+    }
+}
+
+/// Client IP address - the socket peer, or the address the trusted proxy
+/// appended to `X-Forwarded-For` (TRUST_PROXY_HEADERS)
+pub struct ClientIp(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(ClientIp(client_ip(
+            &parts.headers,
+            &parts.extensions,
+            state.config.trust_proxy_headers,
+        )))
+    }
+}
+
+/// Resolve the client IP address from request headers and connection info.
+/// Only the last `X-Forwarded-For` entry is used: our proxy appends the peer it
+/// saw, while everything before it came from the client and can be forged.
+pub fn client_ip(headers: &HeaderMap, extensions: &Extensions, trust_proxy_headers: bool) -> String {
+    if trust_proxy_headers {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.rsplit(',').next())
+            .map(|ip| ip.trim().to_string())
+            .filter(|ip| !ip.is_empty());
+
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ActorClaim;
+    use axum::http::HeaderValue;
+
+    fn peer() -> Extensions {
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 443))));
+        extensions
+    }
+
+    #[test]
+    fn client_ip_takes_the_entry_the_proxy_appended() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 203.0.113.7"),
+        );
+
+        assert_eq!(client_ip(&headers, &peer(), true), "203.0.113.7");
+    }
+
+    #[test]
+    fn client_ip_uses_the_last_forwarded_header() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+        headers.append("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+
+        assert_eq!(client_ip(&headers, &peer(), true), "203.0.113.7");
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_header_unless_trusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+
+        assert_eq!(client_ip(&headers, &peer(), false), "10.0.0.1");
+        assert_eq!(client_ip(&HeaderMap::new(), &peer(), true), "10.0.0.1");
+    }
+
+    fn user_claims(permissions: &[&str], act: Option<ActorClaim>) -> AccessTokenClaims {
+        AccessTokenClaims {
+            sub: Uuid::nil().to_string(),
+            jti: Uuid::new_v4().to_string(),
+            email: "user@example.com".to_string(),
+            roles: vec![],
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            org_id: None,
+            act,
+            exp: 0,
+            iat: 0,
+            iss: "issuer".to_string(),
+            aud: "audience".to_string(),
+        }
+    }
+
+    fn user_request(claims: AccessTokenClaims) -> Extensions {
+        let mut extensions = Extensions::new();
+        extensions.insert(Principal::User(Uuid::nil()));
+        extensions.insert(claims);
+        extensions
+    }
+
+    #[test]
+    fn check_permission_follows_the_user_claims() {
+        let extensions = user_request(user_claims(&["users:read", "users:write"], None));
+        assert!(check_permission(&extensions, "users:write").is_ok());
+
+        let extensions = user_request(user_claims(&["users:read"], None));
+        assert!(matches!(
+            check_permission(&extensions, "users:write"),
+            Err(AppError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn check_permission_denies_impersonation_tokens() {
+        // Impersonation tokens are issued without permissions, whatever the
+        // admin or the user could do
+        let act = ActorClaim {
+            sub: Uuid::new_v4().to_string(),
+        };
+        let extensions = user_request(user_claims(&[], Some(act)));
+
+        assert!(matches!(
+            check_permission(&extensions, "users:read"),
+            Err(AppError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn check_permission_denies_users_without_claims() {
+        let mut extensions = Extensions::new();
+        extensions.insert(Principal::User(Uuid::nil()));
+
+        assert!(matches!(
+            check_permission(&extensions, "users:read"),
+            Err(AppError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn check_permission_follows_service_scopes() {
+        let mut extensions = Extensions::new();
+        extensions.insert(Principal::Service {
+            client_id: "billing".to_string(),
+            scopes: vec!["users:read".to_string()],
+        });
+
+        assert!(check_permission(&extensions, "users:read").is_ok());
+        assert!(matches!(
+            check_permission(&extensions, "users:write"),
+            Err(AppError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn check_permission_always_denies_personal_tokens() {
+        // Even with a user's claims present, a personal token gets nothing
+        let mut extensions = user_request(user_claims(&["users:read"], None));
+        extensions.insert(Principal::PersonalToken(Uuid::nil()));
+
+        assert!(matches!(
+            check_permission(&extensions, "users:read"),
+            Err(AppError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn check_permission_needs_a_principal() {
+        assert!(matches!(
+            check_permission(&Extensions::new(), "users:read"),
+            Err(AppError::Unauthorized)
+        ));
+    }
+}
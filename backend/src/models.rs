@@ -23,6 +23,7 @@ pub struct User {
 #[derive(Debug, Clone, FromRow)]
 pub struct RefreshToken {
     pub id: Uuid,
+    pub family_id: Uuid,
     pub user_id: Uuid,
     pub token_hash: String,
     pub expires_at: DateTime<Utc>,
@@ -31,6 +32,11 @@ pub struct RefreshToken {
     pub replaced_by_token: Option<Uuid>,
     pub device_info: Option<String>,
     pub ip_address: Option<IpNetwork>,
+    /// Set for tokens issued to an OAuth client, with the scope it was granted
+    pub client_id: Option<String>,
+    pub scope: Option<String>,
+    /// The organization the session is working in
+    pub organization_id: Option<Uuid>,
 }
 
 // Request/Response DTOs
@@ -77,11 +83,91 @@ impl From<User> for UserResponse {
 }
 
 // JWT Claims
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccessTokenClaims {
     pub sub: String,
     pub jti: String,
     pub email: String,
+    /// The user's roles and the permissions they grant, as of when the token was issued
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    /// The organization the user is working in, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    /// Set on impersonation tokens: the admin acting as the user (RFC 8693)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<ActorClaim>,
+    pub exp: i64,
+    pub iat: i64,
+    pub iss: String,
+    pub aud: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActorClaim {
+    pub sub: String,
+}
+
+/// Access token issued to an OAuth client - its audience is the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientAccessTokenClaims {
+    pub sub: String,
+    pub jti: String,
+    pub client_id: String,
+    pub scope: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub iss: String,
+    pub aud: String,
+}
+
+/// Access token a backend service got with the client credentials grant -
+/// the service itself is the subject and our API the audience
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceAccessTokenClaims {
+    pub sub: String,
+    pub jti: String,
+    pub client_id: String,
+    pub scope: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub iss: String,
+    pub aud: String,
+}
+
+/// Either kind of access token `/api` accepts
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ApiAccessTokenClaims {
+    User(AccessTokenClaims),
+    Service(ServiceAccessTokenClaims),
+}
+
+/// OpenID Connect ID token issued to a client alongside its access token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub auth_time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    // Only with the `email` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MagicLinkClaims {
+    pub sub: String,
+    pub jti: String,
+    pub purpose: String,
     pub exp: i64,
     pub iat: i64,
     pub iss: String,
@@ -93,6 +179,9 @@ pub struct RefreshTokenClaims {
     pub sub: String,
     pub jti: String,
     pub token_id: String,
+    // Tokens issued before families existed don't carry one
+    #[serde(default)]
+    pub family_id: Option<String>,
     pub exp: i64,
     pub iat: i64,
     pub iss: String,
@@ -167,6 +256,17 @@ pub struct ForgotPasswordRequest {
     pub email: String,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct MagicLinkRequest {
+    #[validate(email(message = "Invalid email address"))]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConsumeMagicLinkRequest {
+    pub token: String,
+}
+
 #[derive(Debug, Deserialize, Validate)]
 pub struct ResetPasswordRequest {
     #[validate(email(message = "Invalid email address"))]
@@ -174,4 +274,759 @@ pub struct ResetPasswordRequest {
     pub code: String,
     #[validate(length(min = 8, message = "Password must be at least 8 characters"))]
     pub new_password: String,
-}
\ No newline at end of file
+}
+
+// Social login
+#[derive(Debug, Deserialize)]
+pub struct OAuthCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Token endpoint response of an external provider
+#[derive(Debug, Deserialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub id_token: Option<String>,
+}
+
+// Linked external accounts
+#[derive(Debug, Serialize)]
+pub struct IdentityResponse {
+    pub id: Uuid,
+    pub provider: String,
+    /// The provider account's email when it was linked
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdentitiesResponse {
+    pub identities: Vec<IdentityResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkIdentityResponse {
+    /// Where to send the browser to sign in with the provider
+    pub auth_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmIdentityLinkRequest {
+    pub link_token: String,
+}
+
+// Roles and permissions
+#[derive(Debug, Serialize)]
+pub struct RoleResponse {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RolesResponse {
+    pub roles: Vec<RoleResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserRolesResponse {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+// Admin user management
+#[derive(Debug, Deserialize)]
+pub struct AdminUsersQuery {
+    /// 1-based
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    /// Part of the email address to look for
+    pub search: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUserResponse {
+    pub id: Uuid,
+    pub email: String,
+    pub email_verified: bool,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<User> for AdminUserResponse {
+    fn from(user: User) -> Self {
+        AdminUserResponse {
+            id: user.id,
+            email: user.email,
+            email_verified: user.email_verified,
+            is_active: user.is_active,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminUsersResponse {
+    pub users: Vec<AdminUserResponse>,
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminSessionsResponse {
+    pub sessions: Vec<ActiveSession>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ImpersonateRequest {
+    /// Why support needs to act as the user - shown to the user afterwards
+    #[validate(length(min = 1, max = 500, message = "Reason must be 1-500 characters"))]
+    pub reason: String,
+}
+
+/// A short-lived access token for acting as the user. There is no refresh token.
+#[derive(Debug, Serialize)]
+pub struct ImpersonationResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub user: UserResponse,
+}
+
+// Security history
+#[derive(Debug, Serialize)]
+pub struct SecurityEventResponse {
+    pub event_type: String,
+    pub ip_address: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SecurityEventsResponse {
+    pub events: Vec<SecurityEventResponse>,
+}
+
+// Organizations
+/// A member's role within an organization, from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl OrgRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner",
+        }
+    }
+}
+
+impl std::str::FromStr for OrgRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(OrgRole::Member),
+            "admin" => Ok(OrgRole::Admin),
+            "owner" => Ok(OrgRole::Owner),
+            _ => Err(format!("Unknown organization role: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateOrganizationRequest {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1-100 characters"))]
+    pub name: String,
+    /// Lowercase letters, digits and hyphens
+    #[validate(length(min = 3, max = 50, message = "Slug must be 3-50 characters"))]
+    pub slug: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateOrganizationRequest {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1-100 characters"))]
+    pub name: String,
+}
+
+/// An organization, as seen by one of its members
+#[derive(Debug, Serialize)]
+pub struct OrganizationResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub role: OrgRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrganizationsResponse {
+    pub organizations: Vec<OrganizationResponse>,
+    pub active_organization_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateInvitationRequest {
+    #[validate(email(message = "Invalid email address"))]
+    pub email: String,
+    pub role: OrgRole,
+}
+
+/// A pending invitation, as seen by the organization's admins
+#[derive(Debug, Serialize)]
+pub struct InvitationResponse {
+    pub id: Uuid,
+    pub email: String,
+    pub role: OrgRole,
+    pub invited_by: Option<Uuid>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvitationsResponse {
+    pub invitations: Vec<InvitationResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvitationQuery {
+    pub token: String,
+}
+
+/// What the invitee sees before accepting
+#[derive(Debug, Serialize)]
+pub struct InvitationDetailsResponse {
+    pub organization_name: String,
+    pub email: String,
+    pub role: OrgRole,
+    pub expires_at: DateTime<Utc>,
+    /// Whether the invitee signs in to accept with their account, or sets a
+    /// password. An account whose email was never verified counts as none -
+    /// accepting replaces its password.
+    pub account_exists: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct AcceptInvitationRequest {
+    pub token: String,
+    /// Only needed when there is no account for the invited address yet
+    #[validate(length(min = 8, message = "Password must be at least 8 characters"))]
+    pub password: Option<String>,
+}
+
+/// Accept an invitation with the account you're signed in to
+#[derive(Debug, Deserialize)]
+pub struct JoinInvitationRequest {
+    pub token: String,
+}
+
+// Personal access tokens
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreatePersonalTokenRequest {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1-100 characters"))]
+    pub name: String,
+    #[validate(length(min = 1, message = "At least one scope is required"))]
+    pub scopes: Vec<String>,
+    /// Leave out for a token that never expires
+    #[validate(range(min = 1, max = 365, message = "Expiry must be 1-365 days"))]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PersonalTokenResponse {
+    pub id: Uuid,
+    pub name: String,
+    /// The start of the token - the token itself is only shown once
+    pub token_prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PersonalTokensResponse {
+    pub tokens: Vec<PersonalTokenResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedPersonalTokenResponse {
+    /// Shown only here - store it now
+    pub token: String,
+    #[serde(flatten)]
+    pub details: PersonalTokenResponse,
+}
+
+// OAuth authorization server
+#[derive(Debug, Clone)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub client_secret_hash: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OAuthClient {
+    pub fn allows_grant(&self, grant_type: &str) -> bool {
+        self.grant_types.iter().any(|g| g == grant_type)
+    }
+}
+
+/// Query of `/oauth/authorize` - everything is optional so errors can go back to the client
+#[derive(Debug, Deserialize)]
+pub struct AuthorizeQuery {
+    pub response_type: Option<String>,
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationRequestResponse {
+    pub request_id: String,
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+    /// False when the user already granted these scopes to the client
+    pub consent_required: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizationDecisionRequest {
+    pub approve: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationDecisionResponse {
+    /// Where to send the browser: the client's redirect URI with a code or an error
+    pub redirect_to: String,
+}
+
+/// Form body of `/oauth/token`
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub device_code: Option<String>,
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    /// Not issued with the client credentials grant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+    /// Only when the `openid` scope was granted with an authorization code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+/// Form body of `/oauth/device_authorization`
+#[derive(Debug, Deserialize)]
+pub struct DeviceAuthorizationRequest {
+    pub scope: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAuthorizationResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+/// A device waiting for approval, for the page where the user enters the code
+#[derive(Debug, Serialize)]
+pub struct DeviceRequestResponse {
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+}
+
+/// Form body of `/oauth/introspect`
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// Form body of `/oauth/revoke`
+#[derive(Debug, Deserialize)]
+pub struct RevocationRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// RFC 7662 response - an inactive token is `{"active": false}` and nothing else
+#[derive(Debug, Default, Serialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// `access_token` or `refresh_token`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<ActorClaim>,
+}
+
+impl From<AccessTokenClaims> for IntrospectionResponse {
+    fn from(claims: AccessTokenClaims) -> Self {
+        IntrospectionResponse {
+            act: claims.act,
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            sub: Some(claims.sub),
+            aud: Some(claims.aud),
+            iss: Some(claims.iss),
+            jti: Some(claims.jti),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ServiceAccessTokenClaims> for IntrospectionResponse {
+    fn from(claims: ServiceAccessTokenClaims) -> Self {
+        IntrospectionResponse {
+            scope: Some(claims.scope),
+            client_id: Some(claims.client_id),
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            sub: Some(claims.sub),
+            aud: Some(claims.aud),
+            iss: Some(claims.iss),
+            jti: Some(claims.jti),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ClientAccessTokenClaims> for IntrospectionResponse {
+    fn from(claims: ClientAccessTokenClaims) -> Self {
+        IntrospectionResponse {
+            scope: Some(claims.scope),
+            client_id: Some(claims.client_id),
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            sub: Some(claims.sub),
+            aud: Some(claims.aud),
+            iss: Some(claims.iss),
+            jti: Some(claims.jti),
+            ..Default::default()
+        }
+    }
+}
+
+// OpenID Connect
+/// Claims of `/oauth/userinfo`, built from the user as `/api/me` returns it
+#[derive(Debug, Serialize)]
+pub struct UserInfoResponse {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+impl UserInfoResponse {
+    /// Only `sub` unless the `email` scope was granted
+    pub fn new(user: UserResponse, scopes: &[&str]) -> Self {
+        let email_scope = scopes.contains(&"email");
+
+        UserInfoResponse {
+            sub: user.id.to_string(),
+            email: email_scope.then_some(user.email),
+            email_verified: email_scope.then_some(user.email_verified),
+        }
+    }
+}
+
+/// `/.well-known/openid-configuration`
+#[derive(Debug, Serialize)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub introspection_endpoint: String,
+    pub revocation_endpoint: String,
+    pub device_authorization_endpoint: String,
+    pub jwks_uri: String,
+    pub scopes_supported: Vec<&'static str>,
+    pub response_types_supported: Vec<&'static str>,
+    pub response_modes_supported: Vec<&'static str>,
+    pub grant_types_supported: Vec<&'static str>,
+    pub subject_types_supported: Vec<&'static str>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<&'static str>,
+    pub code_challenge_methods_supported: Vec<&'static str>,
+    pub claims_supported: Vec<&'static str>,
+    pub authorization_response_iss_parameter_supported: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UnlockAccountRequest {
+    #[validate(email(message = "Invalid email address"))]
+    pub email: String,
+    pub code: String,
+}
+
+// Two-factor authentication
+#[derive(Debug, Serialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginMfaRequest {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaStatusResponse {
+    pub totp_enabled: bool,
+    pub recovery_codes_remaining: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesStatusResponse {
+    pub remaining: i64,
+}
+
+// Passkeys (WebAuthn) - binary values travel as base64url without padding,
+// matching `PublicKeyCredential.parseCreationOptionsFromJSON()` and `toJSON()`
+#[derive(Debug, Serialize)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyUserEntity {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PubKeyCredParam {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub alg: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredentialCreationOptions {
+    pub rp: RelyingParty,
+    pub user: PasskeyUserEntity,
+    pub challenge: String,
+    pub pub_key_cred_params: Vec<PubKeyCredParam>,
+    pub timeout: u64,
+    pub attestation: String,
+    pub authenticator_selection: AuthenticatorSelection,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyCredentialRequestOptions {
+    pub challenge: String,
+    pub timeout: u64,
+    pub rp_id: String,
+    pub user_verification: String,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyCreationOptionsResponse {
+    #[serde(rename = "publicKey")]
+    pub public_key: PublicKeyCredentialCreationOptions,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyRequestOptionsResponse {
+    #[serde(rename = "publicKey")]
+    pub public_key: PublicKeyCredentialRequestOptions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationCredential {
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct FinishPasskeyRegistrationRequest {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1-100 characters"))]
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FinishPasskeyLoginRequest {
+    pub credential: AuthenticationCredential,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RenamePasskeyRequest {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1-100 characters"))]
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeyResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasskeysResponse {
+    pub passkeys: Vec<PasskeyResponse>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_response() -> UserResponse {
+        UserResponse {
+            id: Uuid::new_v4(),
+            email: "ada@example.com".to_string(),
+            created_at: Utc::now(),
+            email_verified: false,
+        }
+    }
+
+    #[test]
+    fn userinfo_has_email_only_with_the_email_scope() {
+        let user = user_response();
+        let id = user.id.to_string();
+        let info = serde_json::to_value(UserInfoResponse::new(user, &["openid", "email"])).unwrap();
+        assert_eq!(info["sub"], id);
+        assert_eq!(info["email"], "ada@example.com");
+        assert_eq!(info["email_verified"], false);
+
+        let info =
+            serde_json::to_value(UserInfoResponse::new(user_response(), &["openid"])).unwrap();
+        assert_eq!(info.as_object().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,404 @@
+//src/rate_limit.rs
+
+use crate::{
+    config::{RateLimit, RateLimitBackend},
+    error::{AppError, Result},
+    middleware::client_ip,
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+};
+use redis::aio::ConnectionManager;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+/// Largest body buffered to read the `email` field of a request
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Entries kept by the in-memory backend before expired ones are swept
+const MEMORY_SWEEP_THRESHOLD: usize = 10_000;
+
+/// What a limit is counted against
+#[derive(Debug, Clone, Copy)]
+pub enum RateLimitKey {
+    /// The client IP address
+    Ip,
+    /// The `email` field of the JSON body, falling back to the IP address
+    Email,
+    /// The signed-in user on routes behind the auth middleware, falling back
+    /// to the IP address
+    User,
+}
+
+/// Where counters live - Redis shares them across instances
+#[derive(Clone)]
+enum RateLimitStore {
+    Redis(Box<ConnectionManager>),
+    Memory(Arc<Mutex<HashMap<String, (u64, Instant)>>>),
+}
+
+/// Counter state after a request was counted
+struct RateLimitStatus {
+    count: u64,
+    reset_secs: u64,
+}
+
+/// Fixed-window rate limiter handing out a `RateLimitLayer` per route
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: RateLimitStore,
+    trust_proxy_headers: bool,
+}
+
+impl RateLimiter {
+    pub fn new(
+        backend: &RateLimitBackend,
+        redis: ConnectionManager,
+        trust_proxy_headers: bool,
+    ) -> Self {
+        let store = match backend {
+            RateLimitBackend::Redis => RateLimitStore::Redis(Box::new(redis)),
+            RateLimitBackend::Memory => RateLimitStore::Memory(Arc::default()),
+        };
+
+        Self {
+            store,
+            trust_proxy_headers,
+        }
+    }
+
+    /// Layer limiting a route to `limit`, with counters kept under `name`.
+    /// Routes that share a `name` share one budget, so give each route its own.
+    pub fn layer(&self, name: &'static str, limit: RateLimit, key: RateLimitKey) -> RateLimitLayer {
+        RateLimitLayer {
+            limiter: self.clone(),
+            name,
+            limit,
+            key,
+        }
+    }
+
+    /// Count one request against `key` and return the window's state
+    async fn hit(&self, key: &str, limit: &RateLimit) -> Result<RateLimitStatus> {
+        match &self.store {
+            RateLimitStore::Redis(manager) => {
+                let mut conn = ConnectionManager::clone(manager);
+
+                // Start the window on the first hit, then count
+                let (_, count, ttl): ((), u64, i64) = redis::pipe()
+                    .atomic()
+                    .cmd("SET")
+                    .arg(key)
+                    .arg(0)
+                    .arg("EX")
+                    .arg(limit.window_secs)
+                    .arg("NX")
+                    .ignore()
+                    .incr(key, 1)
+                    .ttl(key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(AppError::Redis)?;
+
+                Ok(RateLimitStatus {
+                    count,
+                    reset_secs: ttl.max(0) as u64,
+                })
+            }
+            RateLimitStore::Memory(counters) => {
+                let now = Instant::now();
+                let mut counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+
+                if counters.len() > MEMORY_SWEEP_THRESHOLD {
+                    counters.retain(|_, (_, reset_at)| *reset_at > now);
+                }
+
+                let entry = counters
+                    .entry(key.to_string())
+                    .or_insert((0, now + Duration::from_secs(limit.window_secs)));
+                if entry.1 <= now {
+                    *entry = (0, now + Duration::from_secs(limit.window_secs));
+                }
+                entry.0 += 1;
+
+                Ok(RateLimitStatus {
+                    count: entry.0,
+                    reset_secs: entry.1.saturating_duration_since(now).as_secs(),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+    name: &'static str,
+    limit: RateLimit,
+    key: RateLimitKey,
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::result::Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // The clone may not be ready - keep the one that was polled
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let (req, subject) = match subject_for(&layer, req).await {
+                Ok(found) => found,
+                Err(e) => return Ok(e.into_response()),
+            };
+
+            let counter_key = format!("rate_limit:{}:{}", layer.name, subject);
+
+            // Fail open: an outage of the limiter's store must not take down logins
+            let status = match layer.limiter.hit(&counter_key, &layer.limit).await {
+                Ok(status) => status,
+                Err(e) => {
+                    tracing::error!("Rate limiter unavailable for {}: {:?}", layer.name, e);
+                    return inner.call(req).await;
+                }
+            };
+
+            let mut response = if status.count > layer.limit.requests {
+                tracing::debug!("Rate limit '{}' exceeded by {}", layer.name, subject);
+                AppError::RateLimited {
+                    retry_after: status.reset_secs,
+                }
+                .into_response()
+            } else {
+                inner.call(req).await?
+            };
+
+            let headers = response.headers_mut();
+            headers.insert("ratelimit-limit", HeaderValue::from(layer.limit.requests));
+            headers.insert(
+                "ratelimit-remaining",
+                HeaderValue::from(layer.limit.requests.saturating_sub(status.count)),
+            );
+            headers.insert("ratelimit-reset", HeaderValue::from(status.reset_secs));
+
+            Ok(response)
+        })
+    }
+}
+
+/// Work out who a request is counted against, rebuilding the request when
+/// the body had to be read
+async fn subject_for(layer: &RateLimitLayer, req: Request) -> Result<(Request, String)> {
+    let ip = client_ip(
+        req.headers(),
+        req.extensions(),
+        layer.limiter.trust_proxy_headers,
+    );
+
+    match layer.key {
+        RateLimitKey::Ip => Ok((req, format!("ip:{}", ip))),
+        RateLimitKey::User => {
+            let subject = match req.extensions().get::<Uuid>() {
+                Some(user_id) => format!("user:{}", user_id),
+                None => format!("ip:{}", ip),
+            };
+            Ok((req, subject))
+        }
+        RateLimitKey::Email => {
+            let (parts, body) = req.into_parts();
+            let bytes = to_bytes(body, MAX_BODY_BYTES)
+                .await
+                .map_err(|_| AppError::BadRequest("Request body too large".to_string()))?;
+
+            let email = serde_json::from_slice::<serde_json::Value>(&bytes)
+                .ok()
+                .and_then(|body| body.get("email")?.as_str().map(|e| e.trim().to_lowercase()))
+                .filter(|email| !email.is_empty());
+
+            let subject = match email {
+                Some(email) => format!("email:{}", email),
+                None => format!("ip:{}", ip),
+            };
+
+            Ok((Request::from_parts(parts, Body::from(bytes)), subject))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::post, Router};
+    use tower::ServiceExt;
+
+    fn memory_limiter() -> RateLimiter {
+        RateLimiter {
+            store: RateLimitStore::Memory(Arc::default()),
+            trust_proxy_headers: true,
+        }
+    }
+
+    fn limit(requests: u64, window_secs: u64) -> RateLimit {
+        RateLimit {
+            requests,
+            window_secs,
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_backend_counts_within_a_window() {
+        let limiter = memory_limiter();
+        let limit = limit(2, 60);
+
+        for expected in 1..=3 {
+            let status = limiter.hit("rate_limit:test:ip:1", &limit).await.unwrap();
+            assert_eq!(status.count, expected);
+            assert!(status.reset_secs <= 60);
+        }
+
+        // Other keys have their own counters
+        let status = limiter.hit("rate_limit:test:ip:2", &limit).await.unwrap();
+        assert_eq!(status.count, 1);
+    }
+
+    #[tokio::test]
+    async fn memory_backend_starts_a_new_window_once_it_ends() {
+        let limiter = memory_limiter();
+        let limit = limit(1, 1);
+
+        limiter.hit("rate_limit:test:ip:1", &limit).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let status = limiter.hit("rate_limit:test:ip:1", &limit).await.unwrap();
+        assert_eq!(status.count, 1);
+    }
+
+    async fn send(app: &Router, ip: &str, body: &str) -> Response {
+        app.clone()
+            .oneshot(
+                Request::post("/")
+                    .header("x-forwarded-for", ip)
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    fn header(response: &Response, name: &str) -> u64 {
+        response.headers()[name].to_str().unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn layer_sets_headers_and_rejects_over_the_limit() {
+        let limiter = memory_limiter();
+        let app = Router::new().route(
+            "/",
+            post(|| async { "ok" }).layer(limiter.layer("test", limit(2, 60), RateLimitKey::Ip)),
+        );
+
+        let first = send(&app, "10.0.0.1", "{}").await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(header(&first, "ratelimit-limit"), 2);
+        assert_eq!(header(&first, "ratelimit-remaining"), 1);
+
+        let second = send(&app, "10.0.0.1", "{}").await;
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(header(&second, "ratelimit-remaining"), 0);
+
+        let third = send(&app, "10.0.0.1", "{}").await;
+        assert_eq!(third.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&third, "ratelimit-remaining"), 0);
+        assert!(third.headers().contains_key("retry-after"));
+
+        // Another client is unaffected
+        assert_eq!(send(&app, "10.0.0.2", "{}").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn email_key_counts_per_address_and_passes_the_body_on() {
+        let limiter = memory_limiter();
+        let app = Router::new().route(
+            "/",
+            post(|body: String| async move { body })
+                .layer(limiter.layer("test", limit(1, 60), RateLimitKey::Email)),
+        );
+
+        let body = r#"{"email":"Alice@Example.com"}"#;
+        let response = send(&app, "10.0.0.1", body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let echoed = to_bytes(response.into_body(), MAX_BODY_BYTES).await.unwrap();
+        assert_eq!(echoed, body.as_bytes());
+
+        // Same address from another IP, differently cased
+        let again = send(&app, "10.0.0.2", r#"{"email":"alice@example.com "}"#).await;
+        assert_eq!(again.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let other = send(&app, "10.0.0.1", r#"{"email":"bob@example.com"}"#).await;
+        assert_eq!(other.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn user_key_counts_per_signed_in_user_across_addresses() {
+        let limiter = memory_limiter();
+        let user_id = Uuid::new_v4();
+        let signed_in = move |mut req: Request, next: axum::middleware::Next| async move {
+            req.extensions_mut().insert(user_id);
+            next.run(req).await
+        };
+        let app = Router::new()
+            .route(
+                "/",
+                post(|| async { "ok" })
+                    .layer(limiter.layer("test", limit(1, 60), RateLimitKey::User)),
+            )
+            .route_layer(axum::middleware::from_fn(signed_in));
+
+        assert_eq!(send(&app, "10.0.0.1", "{}").await.status(), StatusCode::OK);
+        assert_eq!(
+            send(&app, "10.0.0.2", "{}").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
@@ -1,31 +1,303 @@
-use crate::{handlers::auth, middleware::auth_middleware, state::AppState};
+use crate::{
+    handlers::{
+        admin, auth, identities, invitations, mfa, oauth, oauth_server, organizations, passkeys,
+        personal_tokens, roles, well_known,
+    },
+    middleware::{auth_middleware, require_session, require_user, RequirePermission},
+    rate_limit::RateLimitKey::{Email, Ip, User},
+    state::AppState,
+};
 use axum::{
     middleware,
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 
 pub fn create_router(state: AppState) -> Router {
+    let limits = &state.config.rate_limits;
+    let limiter = &state.rate_limiter;
+
     let auth_routes = Router::new()
-        .route("/register", post(auth::register))
-        .route("/verify-email", post(auth::verify_email))
-        .route("/resend-code", post(auth::resend_verification_code))
-        .route("/login", post(auth::login))
-        .route("/refresh", post(auth::refresh))
-        .route("/logout", post(auth::logout))
-        .route("/forgot-password", post(auth::forgot_password))
-        .route("/reset-password", post(auth::reset_password));
+        .route(
+            "/register",
+            post(auth::register).layer(limiter.layer("register", limits.register, Ip)),
+        )
+        .route(
+            "/verify-email",
+            post(auth::verify_email).layer(limiter.layer("verify_email", limits.verify_email, Ip)),
+        )
+        .route(
+            "/resend-code",
+            post(auth::resend_verification_code)
+                .layer(limiter.layer("resend_code", limits.resend_code, Email)),
+        )
+        .route(
+            "/login",
+            post(auth::login).layer(limiter.layer("login", limits.login, Ip)),
+        )
+        .route(
+            "/login/mfa",
+            post(auth::login_mfa).layer(limiter.layer("login_mfa", limits.login_mfa, Ip)),
+        )
+        .route(
+            "/unlock",
+            post(auth::unlock_account).layer(limiter.layer("unlock", limits.unlock, Ip)),
+        )
+        .route(
+            "/passkey/login/start",
+            post(passkeys::start_login)
+                .layer(limiter.layer("passkey_login_start", limits.passkey_login, Ip)),
+        )
+        .route(
+            "/passkey/login/finish",
+            post(passkeys::finish_login)
+                .layer(limiter.layer("passkey_login_finish", limits.passkey_login, Ip)),
+        )
+        .route(
+            "/refresh",
+            post(auth::refresh).layer(limiter.layer("refresh", limits.refresh, Ip)),
+        )
+        .route(
+            "/logout",
+            post(auth::logout).layer(limiter.layer("logout", limits.logout, Ip)),
+        )
+        .route(
+            "/forgot-password",
+            post(auth::forgot_password)
+                .layer(limiter.layer("forgot_password", limits.forgot_password, Email)),
+        )
+        .route(
+            "/reset-password",
+            post(auth::reset_password)
+                .layer(limiter.layer("reset_password", limits.reset_password, Ip)),
+        )
+        .route(
+            "/magic-link",
+            post(auth::request_magic_link)
+                .layer(limiter.layer("magic_link", limits.magic_link, Email)),
+        )
+        .route(
+            "/magic-link/consume",
+            post(auth::consume_magic_link)
+                .layer(limiter.layer("magic_link_consume", limits.magic_link_consume, Ip)),
+        )
+        .route(
+            "/invitations",
+            get(invitations::get_invitation)
+                .layer(limiter.layer("invitation_lookup", limits.invitation, Ip)),
+        )
+        .route(
+            "/invitations/accept",
+            post(invitations::accept_invitation)
+                .layer(limiter.layer("invitation_accept", limits.invitation, Ip)),
+        )
+        .route(
+            "/oauth/:provider/start",
+            get(oauth::start).layer(limiter.layer("oauth_start", limits.oauth, Ip)),
+        )
+        .route(
+            "/oauth/:provider/callback",
+            get(oauth::callback).layer(limiter.layer("oauth_callback", limits.oauth, Ip)),
+        );
 
     let protected_routes = Router::new()
         .route("/me", get(auth::me))
         .route("/sessions", get(auth::get_active_sessions))
+        .route("/security-events", get(auth::get_security_events))
+        .route(
+            "/orgs",
+            get(organizations::list_organizations).post(organizations::create_organization),
+        )
+        .route(
+            "/orgs/current",
+            get(organizations::current_organization).patch(organizations::update_organization),
+        )
+        .route(
+            "/orgs/current/invitations",
+            get(invitations::list_invitations).post(invitations::create_invitation),
+        )
+        .route(
+            "/orgs/current/invitations/:id",
+            delete(invitations::revoke_invitation),
+        )
+        .route_layer(middleware::from_fn(require_user))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Account security settings - these need a signed-in session, not a personal access token
+    let session_routes = Router::new()
+        .route("/mfa", get(mfa::mfa_status))
+        .route("/mfa/totp/setup", post(mfa::setup_totp))
+        .route("/mfa/totp/confirm", post(mfa::confirm_totp))
+        .route("/mfa/totp/disable", post(mfa::disable_totp))
+        .route("/mfa/recovery-codes", get(mfa::recovery_codes_status))
+        .route("/mfa/recovery-codes/regenerate", post(mfa::regenerate_recovery_codes))
+        .route("/passkeys", get(passkeys::list_passkeys))
+        .route("/passkeys/register/start", post(passkeys::start_registration))
+        .route("/passkeys/register/finish", post(passkeys::finish_registration))
+        .route(
+            "/passkeys/:id",
+            patch(passkeys::rename_passkey).delete(passkeys::delete_passkey),
+        )
+        .route("/identities", get(identities::list_identities))
+        .route("/identities/link/:provider", post(identities::start_link))
+        .route("/identities/confirm", post(identities::confirm_link))
+        .route("/identities/:id", delete(identities::unlink_identity))
+        .route(
+            "/oauth/requests/:request_id",
+            get(oauth_server::get_authorization_request)
+                .post(oauth_server::decide_authorization_request),
+        )
+        .route(
+            "/oauth/device/:user_code",
+            get(oauth_server::get_device_request)
+                .layer(limiter.layer("oauth_device_lookup", limits.oauth_device_verify, User)),
+        )
+        .route(
+            "/oauth/device/:user_code",
+            post(oauth_server::decide_device_request)
+                .layer(limiter.layer("oauth_device_decide", limits.oauth_device_verify, User)),
+        )
+        .route(
+            "/tokens",
+            get(personal_tokens::list_tokens).post(personal_tokens::create_token),
+        )
+        .route("/tokens/:id", delete(personal_tokens::revoke_token))
+        .route("/orgs/:id/switch", post(organizations::switch_organization))
+        .route(
+            "/invitations/accept",
+            post(invitations::join_invitation)
+                .layer(limiter.layer("invitation_join", limits.invitation, User)),
+        )
+        .route_layer(middleware::from_fn(require_session))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Routes backend services can call too, with a client credentials token
+    let service_routes = Router::new()
+        .route("/users/:id", get(auth::get_user))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
         ));
 
+    // Role management, for holders of the roles permissions
+    let role_routes = Router::new()
+        .route(
+            "/roles",
+            get(roles::list_roles).layer(RequirePermission("roles:read")),
+        )
+        .route(
+            "/users/:id/roles",
+            get(roles::get_user_roles).layer(RequirePermission("roles:read")),
+        )
+        .route(
+            "/users/:id/roles/:role",
+            put(roles::grant_role)
+                .delete(roles::revoke_role)
+                .layer(RequirePermission("roles:write")),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Account administration: reading needs users:read, changing anything users:write
+    let can_read = RequirePermission("users:read");
+    let can_write = RequirePermission("users:write");
+    let admin_routes = Router::new()
+        .route("/users", get(admin::list_users).layer(can_read))
+        .route(
+            "/users/:id",
+            get(admin::get_user)
+                .layer(can_read)
+                .merge(delete(admin::delete_user).layer(can_write)),
+        )
+        .route(
+            "/users/:id/deactivate",
+            post(admin::deactivate_user).layer(can_write),
+        )
+        .route(
+            "/users/:id/reactivate",
+            post(admin::reactivate_user).layer(can_write),
+        )
+        .route(
+            "/users/:id/verify-email",
+            post(admin::verify_email).layer(can_write),
+        )
+        .route(
+            "/users/:id/password-reset",
+            post(admin::send_password_reset).layer(can_write),
+        )
+        .route(
+            "/users/:id/sessions",
+            get(admin::list_sessions)
+                .layer(can_read)
+                .merge(delete(admin::revoke_sessions).layer(can_write)),
+        )
+        .route(
+            "/users/:id/sessions/:session_id",
+            delete(admin::revoke_session).layer(can_write),
+        )
+        .route(
+            "/users/:id/impersonate",
+            post(admin::impersonate_user).layer(RequirePermission("users:impersonate")),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ));
+
+    // Authorization server endpoints for registered OAuth clients
+    let oauth_server_routes = Router::new()
+        .route(
+            "/authorize",
+            get(oauth_server::authorize)
+                .layer(limiter.layer("oauth_authorize", limits.oauth_authorize, Ip)),
+        )
+        .route(
+            "/device_authorization",
+            post(oauth_server::device_authorization)
+                .layer(limiter.layer("oauth_device", limits.oauth_device, Ip)),
+        )
+        .route(
+            "/token",
+            post(oauth_server::token)
+                .layer(limiter.layer("oauth_token", limits.oauth_token, Ip)),
+        )
+        .route(
+            "/userinfo",
+            get(oauth_server::userinfo)
+                .post(oauth_server::userinfo)
+                .layer(limiter.layer("oauth_userinfo", limits.oauth_userinfo, Ip)),
+        )
+        .route(
+            "/introspect",
+            post(oauth_server::introspect)
+                .layer(limiter.layer("oauth_introspect", limits.oauth_introspect, Ip)),
+        )
+        .route(
+            "/revoke",
+            post(oauth_server::revoke)
+                .layer(limiter.layer("oauth_revoke", limits.oauth_revoke, Ip)),
+        );
+
     Router::new()
+        .route("/.well-known/jwks.json", get(well_known::jwks))
+        .route(
+            "/.well-known/openid-configuration",
+            get(well_known::openid_configuration),
+        )
         .nest("/auth", auth_routes)
+        .nest("/oauth", oauth_server_routes)
         .nest("/api", protected_routes)
+        .nest("/api", session_routes)
+        .nest("/api", service_routes)
+        .nest("/api", role_routes)
+        .nest("/admin", admin_routes)
         .with_state(state)
 }
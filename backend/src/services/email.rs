@@ -85,6 +85,77 @@ impl EmailService {
         self.send_email(to, subject, &body_text, &body_html).await
     }
 
+    /// Send a one-time sign-in link valid for `expires_in` seconds
+    pub async fn send_magic_link_email(&self, to: &str, link: &str, expires_in: i64) -> Result<()> {
+        let subject = "Your Sign-In Link";
+        let body_text = format!(
+            "Sign In\n\nUse this link to sign in to your account:\n\n{}\n\nThe link can be used once and will expire in {}.\n\nIf you did not request this, you can safely ignore this email.",
+            link,
+            describe_duration(expires_in)
+        );
+        let body_html = format!(
+            "<h2>Sign In</h2><p>Use the link below to sign in to your account:</p><p><a href=\"{}\">Sign in</a></p><p>The link can be used once and will expire in {}.</p><p>If you did not request this, you can safely ignore this email.</p>",
+            link,
+            describe_duration(expires_in)
+        );
+
+        self.send_email(to, subject, &body_text, &body_html).await
+    }
+
+    /// Invite someone into an organization, with a link to accept
+    pub async fn send_invitation_email(
+        &self,
+        to: &str,
+        organization_name: &str,
+        role: &str,
+        link: &str,
+        expires_in: i64,
+    ) -> Result<()> {
+        let subject = format!("You've Been Invited to Join {}", organization_name);
+        let lifetime = describe_duration(expires_in);
+        let body_text = format!(
+            "You're Invited\n\nYou've been invited to join {} as {}.\n\nAccept the invitation here:\n\n{}\n\nThe link can be used once and will expire in {}.\n\nIf you weren't expecting this, you can safely ignore this email.",
+            organization_name, role, link, lifetime
+        );
+        let body_html = format!(
+            "<h2>You're Invited</h2><p>You've been invited to join <strong>{}</strong> as {}.</p><p><a href=\"{}\">Accept the invitation</a></p><p>The link can be used once and will expire in {}.</p><p>If you weren't expecting this, you can safely ignore this email.</p>",
+            escape_html(organization_name), role, link, lifetime
+        );
+
+        self.send_email(to, &subject, &body_text, &body_html).await
+    }
+
+    /// Tell the user their account was locked after repeated failed logins,
+    /// with a code to unlock it early
+    pub async fn send_account_locked_email(&self, to: &str, code: &str, minutes: i64) -> Result<()> {
+        let subject = "Your Account Has Been Temporarily Locked";
+        let body_text = format!(
+            "Account Locked\n\nWe locked your account for {} minutes after too many failed sign-in attempts.\n\nIf this was you, you can unlock it now with this code: {}\n\nIf this wasn't you, someone may be trying to guess your password. Consider changing it.",
+            minutes, code
+        );
+        let body_html = format!(
+            "<h2>Account Locked</h2><p>We locked your account for {} minutes after too many failed sign-in attempts.</p><p>If this was you, you can unlock it now with this code: <strong>{}</strong></p><p>If this wasn't you, someone may be trying to guess your password. Consider changing it.</p>",
+            minutes, code
+        );
+
+        self.send_email(to, subject, &body_text, &body_html).await
+    }
+
+    /// Notify the user that one of their two-factor recovery codes was used
+    pub async fn send_recovery_code_used_email(&self, to: &str, remaining: i64) -> Result<()> {
+        let subject = "A Recovery Code Was Used to Sign In";
+        let body_text = format!(
+            "Recovery Code Used\n\nA two-factor recovery code was just used to sign in to your account.\n\nYou have {} unused recovery codes left. You can generate a new set from your security settings.\n\nIf this wasn't you, reset your password immediately.",
+            remaining
+        );
+        let body_html = format!(
+            "<h2>Recovery Code Used</h2><p>A two-factor recovery code was just used to sign in to your account.</p><p>You have <strong>{}</strong> unused recovery codes left. You can generate a new set from your security settings.</p><p>If this wasn't you, reset your password immediately.</p>",
+            remaining
+        );
+
+        self.send_email(to, subject, &body_text, &body_html).await
+    }
+
     /// Generic email sending method
     async fn send_email(&self, to: &str, subject: &str, body_text: &str, body_html: &str) -> Result<()> {
         let from_address = format!("{} <{}>", self.sender_name, self.sender_email);
@@ -116,4 +187,40 @@ impl EmailService {
         tracing::info!("Email '{}' sent successfully to {}", subject_clone, to_clone);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// "15 minutes", "1 hour", "7 days" - the largest unit that divides `secs`
+fn describe_duration(secs: i64) -> String {
+    let (count, unit) = match secs {
+        s if s >= 86400 && s % 86400 == 0 => (s / 86400, "day"),
+        s if s >= 3600 && s % 3600 == 0 => (s / 3600, "hour"),
+        s if s >= 60 && s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// Organization names are chosen by users, so they can't go into HTML as-is
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_use_the_largest_whole_unit() {
+        assert_eq!(describe_duration(900), "15 minutes");
+        assert_eq!(describe_duration(60), "1 minute");
+        assert_eq!(describe_duration(3600), "1 hour");
+        assert_eq!(describe_duration(5400), "90 minutes");
+        assert_eq!(describe_duration(604800), "7 days");
+        assert_eq!(describe_duration(45), "45 seconds");
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{
+    error::{AppError, Result},
+    models::IdentityResponse,
+    services::{oauth::ExternalIdentity, password::PasswordService, token::TokenService},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// How long the owner of an existing account has to confirm a link, in seconds
+pub const PENDING_LINK_EXPIRY: u64 = 600;
+
+/// A provider sign-in that matched an existing account by email, waiting
+/// for that account's owner to confirm it
+#[derive(Serialize, Deserialize)]
+struct PendingLink {
+    user_id: Uuid,
+    provider: String,
+    subject: String,
+    email: String,
+}
+
+/// External sign-in accounts (provider + subject) linked to users
+#[derive(Clone)]
+pub struct IdentityService {
+    db: PgPool,
+    redis: ConnectionManager,
+}
+
+impl IdentityService {
+    pub fn new(db: PgPool, redis: ConnectionManager) -> Self {
+        Self { db, redis }
+    }
+
+    /// The user a provider account is linked to, if any - marks the link as used
+    pub async fn find_linked_user(&self, identity: &ExternalIdentity) -> Result<Option<Uuid>> {
+        let user_id = sqlx::query_scalar!(
+            r#"
+            UPDATE user_identities
+            SET last_used_at = NOW()
+            WHERE provider = $1 AND subject = $2
+            RETURNING user_id
+            "#,
+            identity.provider,
+            identity.subject
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(user_id)
+    }
+
+    /// Link a provider account to a user. A provider account belongs to one user only.
+    pub async fn link(&self, user_id: Uuid, identity: &ExternalIdentity) -> Result<IdentityResponse> {
+        let linked = sqlx::query_as!(
+            IdentityResponse,
+            r#"
+            INSERT INTO user_identities (user_id, provider, subject, email)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (provider, subject) DO NOTHING
+            RETURNING id, provider, email, created_at, last_used_at
+            "#,
+            user_id,
+            identity.provider,
+            identity.subject,
+            identity.email
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(AppError::IdentityAlreadyLinked)?;
+
+        tracing::info!("Linked {} account to user {}", identity.provider, user_id);
+
+        Ok(linked)
+    }
+
+    /// List the user's linked provider accounts
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<IdentityResponse>> {
+        let identities = sqlx::query_as!(
+            IdentityResponse,
+            r#"
+            SELECT id, provider, email, created_at, last_used_at
+            FROM user_identities
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(identities)
+    }
+
+    /// Unlink one of the user's provider accounts. Refused when it is the
+    /// last way left to sign in: no usable password, no passkey and no other
+    /// linked account.
+    pub async fn unlink(&self, user_id: Uuid, identity_id: Uuid) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        // Lock the user so two concurrent unlinks can't each leave the other as "the last one"
+        let password_hash = sqlx::query_scalar!(
+            "SELECT password_hash FROM users WHERE id = $1 FOR UPDATE",
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(AppError::UserNotFound)?;
+
+        let result = sqlx::query!(
+            "DELETE FROM user_identities WHERE id = $1 AND user_id = $2",
+            identity_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::IdentityNotFound);
+        }
+
+        if PasswordService::is_unusable(&password_hash) {
+            let remaining = sqlx::query_scalar!(
+                r#"
+                SELECT
+                    (SELECT COUNT(*) FROM user_identities WHERE user_id = $1)
+                    + (SELECT COUNT(*) FROM webauthn_credentials WHERE user_id = $1)
+                    AS "count!"
+                "#,
+                user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            if remaining == 0 {
+                return Err(AppError::LastLoginMethod);
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Park a sign-in that matched an existing account until that account's
+    /// owner confirms it. Returns the token the confirmation must present.
+    pub async fn create_pending_link(
+        &self,
+        user_id: Uuid,
+        identity: &ExternalIdentity,
+    ) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let pending = PendingLink {
+            user_id,
+            provider: identity.provider.clone(),
+            subject: identity.subject.clone(),
+            email: identity.email.clone(),
+        };
+
+        let mut conn = self.redis.clone();
+        let _: () = conn
+            .set_ex(
+                format!("oauth_link:{}", TokenService::hash_token(&token)),
+                serde_json::to_string(&pending)?,
+                PENDING_LINK_EXPIRY,
+            )
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(token)
+    }
+
+    /// Link a parked sign-in once the account's owner, now signed in, confirms it
+    pub async fn confirm_pending_link(&self, user_id: Uuid, token: &str) -> Result<IdentityResponse> {
+        let key = format!("oauth_link:{}", TokenService::hash_token(token));
+
+        let mut conn = self.redis.clone();
+        let pending: Option<String> = conn.get(&key).await.map_err(AppError::Redis)?;
+        let pending: PendingLink =
+            serde_json::from_str(&pending.ok_or(AppError::InvalidToken)?)?;
+
+        // Only the account the email matched can take the link
+        if pending.user_id != user_id {
+            return Err(AppError::InvalidToken);
+        }
+
+        let _: () = conn.del(&key).await.map_err(AppError::Redis)?;
+
+        let identity = ExternalIdentity {
+            provider: pending.provider,
+            subject: pending.subject,
+            email: pending.email,
+            email_verified: true,
+        };
+
+        self.link(user_id, &identity).await
+    }
+}
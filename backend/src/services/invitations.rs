@@ -0,0 +1,334 @@
+use crate::{
+    error::{AppError, Result},
+    models::{InvitationResponse, OrgRole, User},
+    services::token::TokenService,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// An invitation that can still be accepted
+pub struct PendingInvitation {
+    pub id: Uuid,
+    pub organization_id: Uuid,
+    pub organization_name: String,
+    pub email: String,
+    pub role: OrgRole,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Email invitations into organizations
+#[derive(Clone)]
+pub struct InvitationService {
+    db: PgPool,
+}
+
+impl InvitationService {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Invite an address into the organization, replacing any invitation it
+    /// already has open there. The token itself is returned only here.
+    pub async fn create(
+        &self,
+        organization_id: Uuid,
+        email: &str,
+        role: OrgRole,
+        invited_by: Uuid,
+        expires_in: i64,
+    ) -> Result<(String, InvitationResponse)> {
+        let already_member = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1
+                FROM organization_members
+                JOIN users ON users.id = organization_members.user_id
+                WHERE organization_members.organization_id = $1 AND users.email = $2
+            ) AS "exists!"
+            "#,
+            organization_id,
+            email
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        if already_member {
+            return Err(AppError::AlreadyOrganizationMember);
+        }
+
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+        let expires_at = Utc::now() + Duration::seconds(expires_in);
+
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM organization_invitations
+            WHERE organization_id = $1 AND email = $2 AND accepted_at IS NULL
+            "#,
+            organization_id,
+            email
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO organization_invitations
+                (organization_id, email, role, token_hash, invited_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, email, invited_by, expires_at, created_at
+            "#,
+            organization_id,
+            email,
+            role.as_str(),
+            TokenService::hash_token(&token),
+            invited_by,
+            expires_at
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!(
+            "User {} invited {} into organization {} as {}",
+            invited_by,
+            email,
+            organization_id,
+            role.as_str()
+        );
+
+        Ok((
+            token,
+            InvitationResponse {
+                id: row.id,
+                email: row.email,
+                role,
+                invited_by: row.invited_by,
+                expires_at: row.expires_at,
+                created_at: row.created_at,
+            },
+        ))
+    }
+
+    /// The organization's invitations that haven't been accepted or expired
+    pub async fn list_pending(&self, organization_id: Uuid) -> Result<Vec<InvitationResponse>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, email, role, invited_by, expires_at, created_at
+            FROM organization_invitations
+            WHERE organization_id = $1 AND accepted_at IS NULL AND expires_at > NOW()
+            ORDER BY created_at DESC
+            "#,
+            organization_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(InvitationResponse {
+                    id: row.id,
+                    email: row.email,
+                    role: parse_role(&row.role)?,
+                    invited_by: row.invited_by,
+                    expires_at: row.expires_at,
+                    created_at: row.created_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Withdraw an invitation that hasn't been accepted yet
+    pub async fn revoke(&self, organization_id: Uuid, invitation_id: Uuid) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM organization_invitations
+            WHERE id = $1 AND organization_id = $2 AND accepted_at IS NULL
+            "#,
+            invitation_id,
+            organization_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::InvitationNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Look up an invitation by its token, if it can still be accepted
+    pub async fn find_pending(&self, token: &str) -> Result<PendingInvitation> {
+        let row = sqlx::query!(
+            r#"
+            SELECT organization_invitations.id, organization_invitations.organization_id,
+                   organizations.name AS organization_name, organization_invitations.email,
+                   organization_invitations.role, organization_invitations.expires_at
+            FROM organization_invitations
+            JOIN organizations ON organizations.id = organization_invitations.organization_id
+            WHERE organization_invitations.token_hash = $1
+              AND organization_invitations.accepted_at IS NULL
+              AND organization_invitations.expires_at > NOW()
+            "#,
+            TokenService::hash_token(token)
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(AppError::InvalidInvitation)?;
+
+        Ok(PendingInvitation {
+            id: row.id,
+            organization_id: row.organization_id,
+            organization_name: row.organization_name,
+            email: row.email,
+            role: parse_role(&row.role)?,
+            expires_at: row.expires_at,
+        })
+    }
+
+    /// Use up the invitation and add the user to its organization. Someone who
+    /// has joined since keeps the role they have.
+    pub async fn accept(&self, invitation: &PendingInvitation, user_id: Uuid) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        Self::claim(&mut tx, invitation).await?;
+        Self::add_member(&mut tx, invitation, user_id).await?;
+        tx.commit().await?;
+
+        Self::log_accepted(invitation, user_id);
+        Ok(())
+    }
+
+    /// Use up the invitation for a new account with the invited address,
+    /// verified since the link was sent there. All or nothing, so a failed
+    /// accept leaves no account behind.
+    pub async fn accept_as_new_user(
+        &self,
+        invitation: &PendingInvitation,
+        password_hash: &str,
+    ) -> Result<User> {
+        let mut tx = self.db.begin().await?;
+        Self::claim(&mut tx, invitation).await?;
+
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            INSERT INTO users (email, password_hash, email_verified)
+            VALUES ($1, $2, TRUE)
+            RETURNING id, email, password_hash, created_at, updated_at, is_active, email_verified
+            "#,
+            invitation.email,
+            password_hash
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                AppError::UserAlreadyExists
+            }
+            _ => AppError::Database(e),
+        })?;
+
+        Self::add_member(&mut tx, invitation, user.id).await?;
+        tx.commit().await?;
+
+        Self::log_accepted(invitation, user.id);
+        Ok(user)
+    }
+
+    /// Use up the invitation for an account whose email was never verified.
+    /// The invitee just proved they own the address, so the account takes
+    /// their password in place of whatever its registrant chose.
+    pub async fn accept_reclaiming(
+        &self,
+        invitation: &PendingInvitation,
+        user_id: Uuid,
+        password_hash: &str,
+    ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        Self::claim(&mut tx, invitation).await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password_hash = $1, email_verified = TRUE, updated_at = NOW()
+            WHERE id = $2
+            "#,
+            password_hash,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Self::add_member(&mut tx, invitation, user_id).await?;
+        tx.commit().await?;
+
+        Self::log_accepted(invitation, user_id);
+        Ok(())
+    }
+
+    /// Mark the invitation accepted. The `accepted_at` check makes the token
+    /// single-use even when two accepts race.
+    async fn claim(
+        tx: &mut Transaction<'_, Postgres>,
+        invitation: &PendingInvitation,
+    ) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE organization_invitations
+            SET accepted_at = NOW()
+            WHERE id = $1 AND accepted_at IS NULL AND expires_at > NOW()
+            "#,
+            invitation.id
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::InvalidInvitation);
+        }
+
+        Ok(())
+    }
+
+    async fn add_member(
+        tx: &mut Transaction<'_, Postgres>,
+        invitation: &PendingInvitation,
+        user_id: Uuid,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (organization_id, user_id) DO NOTHING
+            "#,
+            invitation.organization_id,
+            user_id,
+            invitation.role.as_str()
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    fn log_accepted(invitation: &PendingInvitation, user_id: Uuid) {
+        tracing::info!(
+            "User {} accepted invitation {} into organization {}",
+            user_id,
+            invitation.id,
+            invitation.organization_id
+        );
+    }
+}
+
+fn parse_role(role: &str) -> Result<OrgRole> {
+    role.parse().map_err(AppError::InternalServerError)
+}
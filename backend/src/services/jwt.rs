@@ -1,33 +1,391 @@
 use crate::{
     config::Config,
     error::{AppError, Result},
-    models::{AccessTokenClaims, RefreshTokenClaims, User},
+    models::{
+        AccessTokenClaims, ActorClaim, ApiAccessTokenClaims, ClientAccessTokenClaims, IdTokenClaims,
+        MagicLinkClaims, RefreshTokenClaims, ServiceAccessTokenClaims, User,
+    },
+    services::{
+        roles::UserAccess,
+        signing_keys::{SigningKeyRecord, SigningKeyStore},
+    },
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock, RwLockReadGuard},
+};
 use uuid::Uuid;
 
+/// A signing key plus everything needed to verify what it signed
 #[derive(Clone)]
-pub struct JwtService {
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
     encoding_key: EncodingKey,
     decoding_key: DecodingKey,
-    config: Config,
+    /// Public half for the JWKS document (`None` for shared secrets)
+    jwk: Option<Jwk>,
 }
 
-impl JwtService {
-    pub fn new(config: Config) -> Self {
-        let encoding_key = EncodingKey::from_secret(config.jwt_secret.as_bytes());
-        let decoding_key = DecodingKey::from_secret(config.jwt_secret.as_bytes());
-
+impl SigningKey {
+    /// HS256 with the shared `JWT_SECRET` - never published
+    fn from_secret(kid: String, secret: &str) -> Self {
         Self {
+            kid,
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            jwk: None,
+        }
+    }
+
+    /// RS256 or EdDSA from a PEM private key (PKCS#8, or PKCS#1 for RSA).
+    /// Without an explicit `kid` the RFC 7638 thumbprint of the public key is used.
+    fn from_pem(algorithm: Algorithm, pem_bytes: &[u8], kid: Option<String>) -> Result<Self> {
+        let invalid = |reason: &str| {
+            AppError::InternalServerError(format!("Invalid JWT signing key: {}", reason))
+        };
+
+        let parsed = pem::parse(pem_bytes).map_err(|_| invalid("not a PEM file"))?;
+
+        let (encoding_key, decoding_key, params) = match algorithm {
+            Algorithm::RS256 => {
+                let key_pair = match parsed.tag() {
+                    "PRIVATE KEY" => RsaKeyPair::from_pkcs8(parsed.contents()),
+                    "RSA PRIVATE KEY" => RsaKeyPair::from_der(parsed.contents()),
+                    _ => return Err(invalid("expected an RSA private key")),
+                }
+                .map_err(|e| invalid(&e.to_string()))?;
+
+                let components = RsaPublicKeyComponents::<Vec<u8>>::from(key_pair.public());
+                let n = URL_SAFE_NO_PAD.encode(&components.n);
+                let e = URL_SAFE_NO_PAD.encode(&components.e);
+
+                (
+                    EncodingKey::from_rsa_pem(pem_bytes).map_err(|e| invalid(&e.to_string()))?,
+                    DecodingKey::from_rsa_components(&n, &e)
+                        .map_err(|e| invalid(&e.to_string()))?,
+                    AlgorithmParameters::RSA(RSAKeyParameters {
+                        key_type: RSAKeyType::RSA,
+                        n,
+                        e,
+                    }),
+                )
+            }
+            Algorithm::EdDSA => {
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(parsed.contents())
+                    .map_err(|e| invalid(&e.to_string()))?;
+                let x = URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref());
+
+                (
+                    EncodingKey::from_ed_pem(pem_bytes).map_err(|e| invalid(&e.to_string()))?,
+                    DecodingKey::from_ed_components(&x).map_err(|e| invalid(&e.to_string()))?,
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x,
+                    }),
+                )
+            }
+            other => return Err(invalid(&format!("unsupported algorithm {:?}", other))),
+        };
+
+        let kid = kid.unwrap_or_else(|| Self::thumbprint(&params));
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(match algorithm {
+                    Algorithm::RS256 => KeyAlgorithm::RS256,
+                    _ => KeyAlgorithm::EdDSA,
+                }),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+
+        Ok(Self {
+            kid,
+            algorithm,
             encoding_key,
             decoding_key,
+            jwk: Some(jwk),
+        })
+    }
+
+    /// RFC 7638 JWK thumbprint: SHA-256 over the required members in lexical order
+    fn thumbprint(params: &AlgorithmParameters) -> String {
+        let canonical = match params {
+            AlgorithmParameters::RSA(rsa) => {
+                format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, rsa.e, rsa.n)
+            }
+            AlgorithmParameters::OctetKeyPair(okp) => {
+                format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, okp.x)
+            }
+            _ => unreachable!("only RSA and Ed25519 keys are loaded from PEM"),
+        };
+
+        URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+    }
+}
+
+/// The active signing key plus keys that only verify (staged or rotated out)
+struct KeyRing {
+    active: SigningKey,
+    verification: Vec<SigningKey>,
+}
+
+impl KeyRing {
+    fn find(&self, kid: &str) -> Option<&SigningKey> {
+        std::iter::once(&self.active)
+            .chain(self.verification.iter())
+            .find(|key| key.kid == kid)
+    }
+
+    /// Tokens issued before we put a `kid` in the header were all HS256 with
+    /// JWT_SECRET. They verify only while an HS256 key is still in the ring, so
+    /// once it is retired JWT_SECRET can no longer mint tokens we accept.
+    fn legacy(&self) -> Option<&SigningKey> {
+        std::iter::once(&self.active)
+            .chain(self.verification.iter())
+            .find(|key| key.algorithm == Algorithm::HS256)
+    }
+}
+
+#[derive(Clone)]
+pub struct JwtService {
+    /// Shared between clones so a reload is seen by every handler
+    keys: Arc<RwLock<KeyRing>>,
+    /// `kid` of the key configured through the environment
+    configured_kid: String,
+    config: Config,
+}
+
+impl JwtService {
+    pub fn new(config: Config) -> Result<Self> {
+        let signing_key = match config.jwt_algorithm {
+            Algorithm::HS256 => SigningKey::from_secret(
+                config
+                    .jwt_key_id
+                    .clone()
+                    .unwrap_or_else(|| "hs256".to_string()),
+                &config.jwt_secret,
+            ),
+            algorithm => {
+                let path = config.jwt_private_key_path.as_ref().ok_or_else(|| {
+                    AppError::InternalServerError(format!(
+                        "JWT_PRIVATE_KEY_PATH is required for {:?}",
+                        algorithm
+                    ))
+                })?;
+                let pem_bytes = std::fs::read(path)?;
+                SigningKey::from_pem(algorithm, &pem_bytes, config.jwt_key_id.clone())?
+            }
+        };
+
+        tracing::info!(
+            "JWT signing with {:?} (kid: {})",
+            signing_key.algorithm,
+            signing_key.kid
+        );
+
+        Ok(Self {
+            configured_kid: signing_key.kid.clone(),
+            keys: Arc::new(RwLock::new(KeyRing {
+                active: signing_key,
+                verification: Vec::new(),
+            })),
             config,
+        })
+    }
+
+    /// Load a PEM key file and return its `kid`, failing if it can't be used for signing
+    pub fn inspect_key_file(algorithm: Algorithm, path: &str, kid: Option<String>) -> Result<String> {
+        let pem_bytes = std::fs::read(path)?;
+        Ok(SigningKey::from_pem(algorithm, &pem_bytes, kid)?.kid)
+    }
+
+    /// Record the configured key as the active one if there is no rotation state yet
+    pub async fn seed_key_store(&self, store: &SigningKeyStore) -> Result<()> {
+        let private_key_path = match self.config.jwt_algorithm {
+            Algorithm::HS256 => None,
+            _ => self.config.jwt_private_key_path.as_deref(),
+        };
+
+        store
+            .seed_active_key(
+                &self.configured_kid,
+                &format!("{:?}", self.config.jwt_algorithm),
+                private_key_path,
+            )
+            .await
+    }
+
+    /// Rebuild the key ring from the shared rotation state.
+    /// On error the current keys stay in place.
+    pub async fn reload_keys(&self, store: &SigningKeyStore) -> Result<()> {
+        let records = store.load_usable_keys().await?;
+
+        let mut active = None;
+        let mut verification = Vec::new();
+
+        for record in records {
+            match (self.load_record(&record), record.status.as_str()) {
+                (Ok(key), "active") => active = Some(key),
+                (Ok(key), _) => verification.push(key),
+                (Err(e), "active") => return Err(e),
+                (Err(e), _) => {
+                    tracing::warn!("Skipping JWT verification key {}: {:?}", record.kid, e);
+                }
+            }
+        }
+
+        let active = active.ok_or_else(|| {
+            AppError::InternalServerError("No active JWT signing key in key store".to_string())
+        })?;
+
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        if keys.active.kid != active.kid {
+            tracing::info!(
+                "JWT signing key changed from {} to {}",
+                keys.active.kid,
+                active.kid
+            );
+        }
+        *keys = KeyRing {
+            active,
+            verification,
+        };
+
+        Ok(())
+    }
+
+    fn load_record(&self, record: &SigningKeyRecord) -> Result<SigningKey> {
+        let algorithm = Algorithm::from_str(&record.algorithm).map_err(|_| {
+            AppError::InternalServerError(format!("Unknown JWT algorithm {}", record.algorithm))
+        })?;
+
+        match (&record.private_key_path, algorithm) {
+            (None, Algorithm::HS256) => Ok(SigningKey::from_secret(
+                record.kid.clone(),
+                &self.config.jwt_secret,
+            )),
+            (Some(path), algorithm) if algorithm != Algorithm::HS256 => {
+                let pem_bytes = std::fs::read(path)?;
+                SigningKey::from_pem(algorithm, &pem_bytes, Some(record.kid.clone()))
+            }
+            _ => Err(AppError::InternalServerError(format!(
+                "Signing key {} has no usable key material",
+                record.kid
+            ))),
+        }
+    }
+
+    fn key_ring(&self) -> RwLockReadGuard<'_, KeyRing> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Public keys for `/.well-known/jwks.json` - every key that can still verify
+    pub fn jwks(&self) -> JwkSet {
+        let keys = self.key_ring();
+
+        JwkSet {
+            keys: std::iter::once(&keys.active)
+                .chain(keys.verification.iter())
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
         }
     }
 
-    /// Generate access token with token_id reference
-    pub fn generate_access_token(&self, user: &User, token_id: Uuid) -> Result<String> {
+    /// Algorithms of the published keys, for OpenID Connect discovery
+    pub fn public_algorithms(&self) -> Vec<String> {
+        let keys = self.key_ring();
+
+        let mut algorithms: Vec<String> = Vec::new();
+        for key in std::iter::once(&keys.active).chain(keys.verification.iter()) {
+            let algorithm = format!("{:?}", key.algorithm);
+            if key.jwk.is_some() && !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+
+        algorithms
+    }
+
+    /// Whether clients can verify what we sign now, i.e. the active key is published
+    fn signs_verifiably(&self) -> bool {
+        self.key_ring().active.jwk.is_some()
+    }
+
+    fn encode_claims<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let keys = self.key_ring();
+
+        let mut header = Header::new(keys.active.algorithm);
+        header.kid = Some(keys.active.kid.clone());
+
+        encode(&header, claims, &keys.active.encoding_key)
+            .map_err(|e| AppError::JwtError(e.to_string()))
+    }
+
+    /// Pick the verification key by `kid` and validate signature, issuer and audience
+    fn decode_claims<T: DeserializeOwned>(&self, token: &str) -> Result<T> {
+        self.decode_claims_for(token, Some(&self.config.jwt_audience))
+    }
+
+    /// Like `decode_claims`, but `None` leaves the audience to the caller
+    fn decode_claims_for<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        audience: Option<&str>,
+    ) -> Result<T> {
+        let header = decode_header(token).map_err(|_| AppError::InvalidToken)?;
+
+        let (algorithm, decoding_key) = {
+            let keys = self.key_ring();
+            let key = match header.kid {
+                Some(kid) => keys.find(&kid),
+                None => keys.legacy(),
+            }
+            .ok_or(AppError::InvalidToken)?;
+            (key.algorithm, key.decoding_key.clone())
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&self.config.jwt_issuer]);
+        match audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        decode::<T>(token, &decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => AppError::TokenExpired,
+                _ => AppError::InvalidToken,
+            })
+    }
+
+    /// Generate access token with token_id reference, working in `organization_id`
+    pub fn generate_access_token(
+        &self,
+        user: &User,
+        token_id: Uuid,
+        access: &UserAccess,
+        organization_id: Option<Uuid>,
+    ) -> Result<String> {
         let now = Utc::now();
         let exp = now + Duration::seconds(self.config.access_token_expiry);
 
@@ -35,58 +393,321 @@ impl JwtService {
             sub: user.id.to_string(),
             jti: token_id.to_string(), // Add token_id reference
             email: user.email.clone(),
+            roles: access.roles.clone(),
+            permissions: access.permissions.clone(),
+            org_id: organization_id.map(|id| id.to_string()),
+            act: None,
             exp: exp.timestamp(),
             iat: now.timestamp(),
             iss: self.config.jwt_issuer.clone(),
             aud: self.config.jwt_audience.clone(),
         };
 
-        encode(&Header::default(), &claims, &self.encoding_key)
-            .map_err(|e| AppError::JwtError(e.to_string()))
+        self.encode_claims(&claims)
+    }
+
+    /// Generate a short-lived access token for `actor_id` to act as `user`. It
+    /// names the actor in `act` and carries none of the user's permissions.
+    pub fn generate_impersonation_token(
+        &self,
+        user: &User,
+        actor_id: Uuid,
+        token_id: Uuid,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let exp = now + Duration::seconds(self.config.impersonation_token_expiry);
+
+        let claims = AccessTokenClaims {
+            sub: user.id.to_string(),
+            jti: token_id.to_string(),
+            email: user.email.clone(),
+            roles: Vec::new(),
+            permissions: Vec::new(),
+            org_id: None,
+            act: Some(ActorClaim {
+                sub: actor_id.to_string(),
+            }),
+            exp: exp.timestamp(),
+            iat: now.timestamp(),
+            iss: self.config.jwt_issuer.clone(),
+            aud: self.config.jwt_audience.clone(),
+        };
+
+        self.encode_claims(&claims)
+    }
+
+    /// Generate an access token for an OAuth client acting for `user_id`. The
+    /// client is the audience, so these never pass as first-party tokens.
+    pub fn generate_client_access_token(
+        &self,
+        user_id: Uuid,
+        token_id: Uuid,
+        client_id: &str,
+        scope: &str,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let exp = now + Duration::seconds(self.config.access_token_expiry);
+
+        let claims = ClientAccessTokenClaims {
+            sub: user_id.to_string(),
+            jti: token_id.to_string(),
+            client_id: client_id.to_string(),
+            scope: scope.to_string(),
+            exp: exp.timestamp(),
+            iat: now.timestamp(),
+            iss: self.config.jwt_issuer.clone(),
+            aud: client_id.to_string(),
+        };
+
+        self.encode_claims(&claims)
     }
 
-    /// Generate refresh token with token_id
-    pub fn generate_refresh_token(&self, user_id: Uuid, token_id: Uuid) -> Result<String> {
+    /// Generate an access token for a backend service (client credentials
+    /// grant). The service is the subject; there is no user.
+    pub fn generate_service_access_token(
+        &self,
+        client_id: &str,
+        token_id: Uuid,
+        scope: &str,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let exp = now + Duration::seconds(self.config.access_token_expiry);
+
+        let claims = ServiceAccessTokenClaims {
+            sub: client_id.to_string(),
+            jti: token_id.to_string(),
+            client_id: client_id.to_string(),
+            scope: scope.to_string(),
+            exp: exp.timestamp(),
+            iat: now.timestamp(),
+            iss: self.config.jwt_issuer.clone(),
+            aud: self.config.jwt_audience.clone(),
+        };
+
+        self.encode_claims(&claims)
+    }
+
+    /// Generate an OpenID Connect ID token for `client_id`. Clients verify it
+    /// against our JWKS, so a shared-secret key can't sign it.
+    pub fn generate_id_token(
+        &self,
+        user: &User,
+        client_id: &str,
+        auth_time: i64,
+        nonce: Option<String>,
+        include_email: bool,
+    ) -> Result<String> {
+        if !self.signs_verifiably() {
+            return Err(AppError::InternalServerError(
+                "ID tokens need an RS256 or EdDSA signing key".to_string(),
+            ));
+        }
+
+        let now = Utc::now();
+        let exp = now + Duration::seconds(self.config.access_token_expiry);
+
+        let claims = IdTokenClaims {
+            iss: self.config.jwt_issuer.clone(),
+            sub: user.id.to_string(),
+            aud: client_id.to_string(),
+            exp: exp.timestamp(),
+            iat: now.timestamp(),
+            auth_time,
+            nonce,
+            email: include_email.then(|| user.email.clone()),
+            email_verified: include_email.then_some(user.email_verified),
+        };
+
+        self.encode_claims(&claims)
+    }
+
+    /// Generate refresh token with token_id and the id of its rotation family
+    pub fn generate_refresh_token(
+        &self,
+        user_id: Uuid,
+        token_id: Uuid,
+        family_id: Uuid,
+    ) -> Result<String> {
         let now = Utc::now();
         let exp = now + Duration::seconds(self.config.refresh_token_expiry);
 
         let claims = RefreshTokenClaims {
             sub: user_id.to_string(),
-            jti: token_id.to_string(),     // Add jti
+            jti: token_id.to_string(),      // Add jti
             token_id: token_id.to_string(), // Keep for backwards compatibility
+            family_id: Some(family_id.to_string()),
             exp: exp.timestamp(),
             iat: now.timestamp(),
             iss: self.config.jwt_issuer.clone(),
             aud: self.config.jwt_audience.clone(),
         };
 
-        encode(&Header::default(), &claims, &self.encoding_key)
-            .map_err(|e| AppError::JwtError(e.to_string()))
+        self.encode_claims(&claims)
+    }
+
+    /// Generate a signed magic link token wrapping a single-use verification code
+    pub fn generate_magic_link_token(&self, user_id: Uuid, code: &str) -> Result<String> {
+        let now = Utc::now();
+        let exp = now + Duration::seconds(self.config.magic_link_expiry);
+
+        let claims = MagicLinkClaims {
+            sub: user_id.to_string(),
+            jti: code.to_string(),
+            purpose: "magic_link".to_string(),
+            exp: exp.timestamp(),
+            iat: now.timestamp(),
+            iss: self.config.jwt_issuer.clone(),
+            aud: self.config.jwt_audience.clone(),
+        };
+
+        self.encode_claims(&claims)
+    }
+
+    pub fn verify_magic_link_token(&self, token: &str) -> Result<MagicLinkClaims> {
+        let claims: MagicLinkClaims = self.decode_claims(token)?;
+
+        if claims.purpose != "magic_link" {
+            return Err(AppError::InvalidToken);
+        }
+
+        Ok(claims)
     }
 
     pub fn verify_access_token(&self, token: &str) -> Result<AccessTokenClaims> {
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.set_issuer(&[&self.config.jwt_issuer]);
-        validation.set_audience(&[&self.config.jwt_audience]);
+        self.decode_claims(token)
+    }
 
-        decode::<AccessTokenClaims>(token, &self.decoding_key, &validation)
-            .map(|data| data.claims)
-            .map_err(|e| match e.kind() {
-                jsonwebtoken::errors::ErrorKind::ExpiredSignature => AppError::TokenExpired,
-                _ => AppError::InvalidToken,
-            })
+    /// Verify a user's or a service's access token for `/api`
+    pub fn verify_api_access_token(&self, token: &str) -> Result<ApiAccessTokenClaims> {
+        let claims: ApiAccessTokenClaims = self.decode_claims(token)?;
+
+        match &claims {
+            ApiAccessTokenClaims::Service(service) if service.sub != service.client_id => {
+                Err(AppError::InvalidToken)
+            }
+            _ => Ok(claims),
+        }
     }
 
     pub fn verify_refresh_token(&self, token: &str) -> Result<RefreshTokenClaims> {
-        let mut validation = Validation::new(Algorithm::HS256);
-        validation.set_issuer(&[&self.config.jwt_issuer]);
-        validation.set_audience(&[&self.config.jwt_audience]);
+        self.decode_claims(token)
+    }
 
-        decode::<RefreshTokenClaims>(token, &self.decoding_key, &validation)
-            .map(|data| data.claims)
-            .map_err(|e| match e.kind() {
-                jsonwebtoken::errors::ErrorKind::ExpiredSignature => AppError::TokenExpired,
-                _ => AppError::InvalidToken,
-            })
+    /// Verify an access token issued to an OAuth client, whichever client it is
+    pub fn verify_client_access_token(&self, token: &str) -> Result<ClientAccessTokenClaims> {
+        let claims: ClientAccessTokenClaims = self.decode_claims_for(token, None)?;
+
+        if claims.aud != claims.client_id {
+            return Err(AppError::InvalidToken);
+        }
+
+        Ok(claims)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use serde_json::{Map, Value};
+
+    /// A service signing with a fresh Ed25519 key, as configured through JWT_PRIVATE_KEY_PATH
+    fn eddsa_service() -> JwtService {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let path = std::env::temp_dir().join(format!("jwt-test-{}.pem", Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref().to_vec())),
+        )
+        .unwrap();
+
+        let mut config = Config::for_tests();
+        config.jwt_algorithm = Algorithm::EdDSA;
+        config.jwt_private_key_path = Some(path.to_string_lossy().into_owned());
+        let service = JwtService::new(config).unwrap();
+
+        std::fs::remove_file(path).unwrap();
+        service
+    }
+
+    fn user() -> User {
+        User {
+            id: Uuid::new_v4(),
+            email: "ada@example.com".to_string(),
+            password_hash: String::new(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            is_active: true,
+            email_verified: true,
+        }
+    }
+
+    /// Verify an ID token the way a client would: against the published JWKS
+    fn verify_as_client(
+        service: &JwtService,
+        id_token: &str,
+        client_id: &str,
+    ) -> Map<String, Value> {
+        let kid = decode_header(id_token).unwrap().kid.unwrap();
+        let jwks = service.jwks();
+        let jwk = jwks.find(&kid).expect("signing key is published");
+
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_audience(&[client_id]);
+        validation.set_issuer(&[&service.config.jwt_issuer]);
+
+        decode::<Map<String, Value>>(id_token, &DecodingKey::from_jwk(jwk).unwrap(), &validation)
+            .unwrap()
+            .claims
+    }
+
+    #[test]
+    fn id_tokens_verify_against_the_jwks_and_carry_nonce_and_auth_time() {
+        let service = eddsa_service();
+        let user = user();
+        let auth_time = Utc::now().timestamp() - 120;
+
+        let id_token = service
+            .generate_id_token(
+                &user,
+                "wiki",
+                auth_time,
+                Some("n-0S6_WzA2Mj".to_string()),
+                true,
+            )
+            .unwrap();
+        let claims = verify_as_client(&service, &id_token, "wiki");
+
+        assert_eq!(claims["sub"], Value::from(user.id.to_string()));
+        assert_eq!(claims["nonce"], Value::from("n-0S6_WzA2Mj"));
+        assert_eq!(claims["auth_time"], Value::from(auth_time));
+        assert!(claims["auth_time"].as_i64() <= claims["iat"].as_i64());
+        assert_eq!(claims["email"], Value::from("ada@example.com"));
+        assert_eq!(claims["email_verified"], Value::Bool(true));
+    }
+
+    #[test]
+    fn id_tokens_leave_out_what_was_not_asked_for() {
+        let service = eddsa_service();
+
+        let id_token = service
+            .generate_id_token(&user(), "wiki", Utc::now().timestamp(), None, false)
+            .unwrap();
+        let claims = verify_as_client(&service, &id_token, "wiki");
+
+        assert!(!claims.contains_key("nonce"));
+        assert!(!claims.contains_key("email"));
+        assert!(!claims.contains_key("email_verified"));
+    }
+
+    #[test]
+    fn id_tokens_need_a_published_key() {
+        let service = JwtService::new(Config::for_tests()).unwrap();
+
+        assert!(service
+            .generate_id_token(&user(), "wiki", Utc::now().timestamp(), None, true)
+            .is_err());
+        assert!(service.jwks().keys.is_empty());
+        assert!(service.public_algorithms().is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{
+    config::Config,
+    error::{AppError, Result},
+};
+use redis::{aio::ConnectionManager, AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+
+/// Failed attempts allowed on an account before each new one is delayed
+const FREE_ATTEMPTS: i64 = 3;
+
+/// Upper bound for the delay between attempts, in seconds
+const MAX_BACKOFF_SECS: i64 = 300;
+
+/// Delay before the next attempt after `failures` failed ones: none for the
+/// first `FREE_ATTEMPTS`, then doubling from 2 seconds up to `MAX_BACKOFF_SECS`
+fn backoff_secs(failures: i64) -> Option<i64> {
+    if failures <= FREE_ATTEMPTS {
+        return None;
+    }
+
+    let exponent = u32::try_from(failures - FREE_ATTEMPTS).unwrap_or(u32::MAX);
+    Some(2i64.saturating_pow(exponent).min(MAX_BACKOFF_SECS))
+}
+
+/// Outcome of recording a failed login
+pub enum LoginFailure {
+    /// Keep going - a backoff may apply to the next attempt
+    Counted,
+    /// This failure locked the account for `retry_after` seconds
+    AccountLocked { retry_after: u64 },
+}
+
+/// Tracks failed password logins per account and per IP address in Redis.
+/// Wrong second-factor codes count against the account as well.
+///
+/// An account gets an exponentially growing delay after `FREE_ATTEMPTS`
+/// failures and is locked once it reaches `max_failed_logins`. An IP address
+/// is locked once it reaches `max_failed_logins_per_ip` across all accounts.
+#[derive(Clone)]
+pub struct LoginAttemptService {
+    redis: ConnectionManager,
+    config: Config,
+}
+
+impl LoginAttemptService {
+    pub fn new(redis: ConnectionManager, config: Config) -> Self {
+        Self { redis, config }
+    }
+
+    fn account_id(email: &str) -> String {
+        email.trim().to_lowercase()
+    }
+
+    /// Reject the attempt up front if the account or IP is locked or backing off
+    pub async fn check(&self, email: &str, ip: &str) -> Result<()> {
+        let account = Self::account_id(email);
+
+        self.ensure_not_blocked(&[
+            format!("login_lockout:account:{}", account),
+            format!("login_lockout:ip:{}", ip),
+            format!("login_backoff:account:{}", account),
+        ])
+        .await
+    }
+
+    /// Reject a second-factor attempt if the account is locked or backing off
+    pub async fn check_account(&self, email: &str) -> Result<()> {
+        let account = Self::account_id(email);
+
+        self.ensure_not_blocked(&[
+            format!("login_lockout:account:{}", account),
+            format!("login_backoff:account:{}", account),
+        ])
+        .await
+    }
+
+    async fn ensure_not_blocked(&self, keys: &[String]) -> Result<()> {
+        let mut conn = self.redis.clone();
+
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.ttl(key);
+        }
+        let ttls: Vec<i64> = pipe.query_async(&mut conn).await.map_err(AppError::Redis)?;
+
+        // TTL is negative when the key doesn't exist
+        let retry_after = ttls.into_iter().max().unwrap_or(-1);
+        if retry_after > 0 {
+            return Err(AppError::AccountLocked {
+                retry_after: retry_after as u64,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Count a failed attempt against the account and the IP address
+    pub async fn record_failure(&self, email: &str, ip: &str) -> Result<LoginFailure> {
+        let window = self.config.login_lockout_duration;
+        let mut conn = self.redis.clone();
+
+        let ip_key = format!("login_failures:ip:{}", ip);
+
+        let (ip_failures, _): (i64, i64) = redis::pipe()
+            .atomic()
+            .incr(&ip_key, 1)
+            .expire(&ip_key, window)
+            .query_async(&mut conn)
+            .await
+            .map_err(AppError::Redis)?;
+
+        if ip_failures >= self.config.max_failed_logins_per_ip {
+            tracing::warn!("Locking out IP {} after {} failed logins", ip, ip_failures);
+            let _: () = conn
+                .set_ex(format!("login_lockout:ip:{}", ip), 1, window as u64)
+                .await
+                .map_err(AppError::Redis)?;
+        }
+
+        self.record_account_failure(email).await
+    }
+
+    /// Count a wrong TOTP or recovery code against the account. It shares the
+    /// password counter, so a stolen password doesn't buy unlimited guesses.
+    pub async fn record_second_factor_failure(&self, email: &str) -> Result<LoginFailure> {
+        self.record_account_failure(email).await
+    }
+
+    async fn record_account_failure(&self, email: &str) -> Result<LoginFailure> {
+        let account = Self::account_id(email);
+        let window = self.config.login_lockout_duration;
+        let mut conn = self.redis.clone();
+
+        let account_key = format!("login_failures:account:{}", account);
+
+        let (account_failures, _): (i64, i64) = redis::pipe()
+            .atomic()
+            .incr(&account_key, 1)
+            .expire(&account_key, window)
+            .query_async(&mut conn)
+            .await
+            .map_err(AppError::Redis)?;
+
+        if account_failures >= self.config.max_failed_logins {
+            // Only the attempt that crosses the threshold reports the lockout,
+            // so the user is emailed once
+            // NX and EX in one command, so the lock can never be left without a TTL
+            let locked: Option<String> = conn
+                .set_options(
+                    format!("login_lockout:account:{}", account),
+                    1,
+                    SetOptions::default()
+                        .conditional_set(ExistenceCheck::NX)
+                        .with_expiration(SetExpiry::EX(window as u64)),
+                )
+                .await
+                .map_err(AppError::Redis)?;
+            if locked.is_some() {
+                tracing::warn!(
+                    "Locking out account {} after {} failed logins",
+                    account,
+                    account_failures
+                );
+                return Ok(LoginFailure::AccountLocked {
+                    retry_after: window as u64,
+                });
+            }
+        } else if let Some(delay) = backoff_secs(account_failures) {
+            let _: () = conn
+                .set_ex(format!("login_backoff:account:{}", account), 1, delay as u64)
+                .await
+                .map_err(AppError::Redis)?;
+        }
+
+        Ok(LoginFailure::Counted)
+    }
+
+    /// Forget failed attempts on the account once the whole login, including
+    /// any second factor, has succeeded.
+    /// The IP counter is left alone so a known account can't be used to reset it.
+    pub async fn record_success(&self, email: &str) -> Result<()> {
+        let account = Self::account_id(email);
+        let mut conn = self.redis.clone();
+
+        let _: () = conn
+            .del(&[
+                format!("login_failures:account:{}", account),
+                format!("login_backoff:account:{}", account),
+            ])
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(())
+    }
+
+    /// Lift an account lockout (unlock code or password reset)
+    pub async fn unlock_account(&self, email: &str) -> Result<()> {
+        let account = Self::account_id(email);
+        let mut conn = self.redis.clone();
+
+        let _: () = conn
+            .del(&[
+                format!("login_lockout:account:{}", account),
+                format!("login_failures:account:{}", account),
+                format!("login_backoff:account:{}", account),
+            ])
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_starts_after_the_free_attempts() {
+        for failures in 0..=FREE_ATTEMPTS {
+            assert_eq!(backoff_secs(failures), None);
+        }
+
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 1), Some(2));
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 2), Some(4));
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 8), Some(256));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 9), Some(MAX_BACKOFF_SECS));
+        assert_eq!(backoff_secs(FREE_ATTEMPTS + 64), Some(MAX_BACKOFF_SECS));
+        assert_eq!(backoff_secs(i64::MAX), Some(MAX_BACKOFF_SECS));
+    }
+}
@@ -0,0 +1,589 @@
+use crate::{
+    config::Config,
+    error::{AppError, Result},
+    services::token::TokenService,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use sha1::Sha1;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// RFC 6238 parameters (the defaults every authenticator app understands)
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW_STEPS: i64 = 1;
+const TOTP_SECRET_BYTES: usize = 20;
+
+/// Wrong codes allowed against a single login challenge before it is dropped
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// Recovery codes: 10 codes of 10 characters, shown as `xxxxx-xxxxx`.
+/// The alphabet leaves out look-alike characters (0/o, 1/l/i).
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// Which second factor completed a login challenge
+pub enum MfaMethod {
+    Totp,
+    RecoveryCode { remaining: i64 },
+}
+
+#[derive(Clone)]
+pub struct MfaService {
+    db: PgPool,
+    redis: ConnectionManager,
+    cipher: Aes256Gcm,
+    config: Config,
+}
+
+impl MfaService {
+    pub fn new(db: PgPool, redis: ConnectionManager, config: Config) -> Result<Self> {
+        let key = STANDARD
+            .decode(config.mfa_encryption_key.trim())
+            .map_err(|_| {
+                AppError::InternalServerError("MFA_ENCRYPTION_KEY is not valid base64".into())
+            })?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| {
+            AppError::InternalServerError("MFA_ENCRYPTION_KEY must be 32 bytes".into())
+        })?;
+
+        Ok(Self {
+            db,
+            redis,
+            cipher,
+            config,
+        })
+    }
+
+    /// Encrypt a TOTP secret, binding the ciphertext to its owner
+    fn encrypt_secret(&self, user_id: Uuid, secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: secret,
+                    aad: user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| AppError::InternalServerError("Failed to encrypt TOTP secret".into()))?;
+
+        Ok((ciphertext, nonce.to_vec()))
+    }
+
+    fn decrypt_secret(&self, user_id: Uuid, ciphertext: &[u8], nonce: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; 12] = nonce
+            .try_into()
+            .map_err(|_| AppError::InternalServerError("Invalid TOTP secret nonce".into()))?;
+
+        self.cipher
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: user_id.as_bytes(),
+                },
+            )
+            .map_err(|_| AppError::InternalServerError("Failed to decrypt TOTP secret".into()))
+    }
+
+    /// Compute the code for a given time step (RFC 4226 dynamic truncation)
+    fn totp_code(secret: &[u8], step: i64) -> String {
+        let mut mac =
+            <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(&(step as u64).to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(TOTP_DIGITS),
+            width = TOTP_DIGITS as usize
+        )
+    }
+
+    /// Find the time step (within the allowed skew) that produced `code`
+    fn matching_step(secret: &[u8], code: &str) -> Option<i64> {
+        let code = code.trim();
+        if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let current = Utc::now().timestamp() / TOTP_STEP_SECS;
+        (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS).find(|&step| {
+            constant_time_eq(Self::totp_code(secret, step).as_bytes(), code.as_bytes())
+        })
+    }
+
+    /// Check whether the user has a confirmed authenticator
+    pub async fn is_totp_enabled(&self, user_id: Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            SELECT enabled
+            FROM user_totp
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(result.map(|row| row.enabled).unwrap_or(false))
+    }
+
+    /// Generate a new secret and store it as a pending (unconfirmed) enrollment
+    pub async fn begin_totp_enrollment(
+        &self,
+        user_id: Uuid,
+        account_name: &str,
+    ) -> Result<TotpEnrollment> {
+        if self.is_totp_enabled(user_id).await? {
+            return Err(AppError::MfaAlreadyEnabled);
+        }
+
+        let mut secret = [0u8; TOTP_SECRET_BYTES];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let (ciphertext, nonce) = self.encrypt_secret(user_id, &secret)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO user_totp (user_id, secret_ciphertext, secret_nonce)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret_ciphertext = EXCLUDED.secret_ciphertext,
+                secret_nonce = EXCLUDED.secret_nonce,
+                enabled = FALSE,
+                last_used_step = NULL,
+                created_at = NOW(),
+                confirmed_at = NULL
+            "#,
+            user_id,
+            ciphertext,
+            nonce
+        )
+        .execute(&self.db)
+        .await?;
+
+        let encoded = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &secret);
+        let issuer = &self.config.totp_issuer;
+        let otpauth_uri = format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            urlencoding::encode(issuer),
+            urlencoding::encode(account_name),
+            encoded,
+            urlencoding::encode(issuer),
+            TOTP_DIGITS,
+            TOTP_STEP_SECS
+        );
+
+        Ok(TotpEnrollment {
+            secret: encoded,
+            otpauth_uri,
+        })
+    }
+
+    /// Confirm a pending enrollment with the first code from the authenticator
+    pub async fn confirm_totp_enrollment(&self, user_id: Uuid, code: &str) -> Result<()> {
+        let row = sqlx::query!(
+            r#"
+            SELECT secret_ciphertext, secret_nonce, enabled
+            FROM user_totp
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("No pending two-factor enrollment".into()))?;
+
+        if row.enabled {
+            return Err(AppError::MfaAlreadyEnabled);
+        }
+
+        let secret = self.decrypt_secret(user_id, &row.secret_ciphertext, &row.secret_nonce)?;
+        let step = Self::matching_step(&secret, code).ok_or(AppError::InvalidMfaCode)?;
+
+        sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET enabled = TRUE, confirmed_at = NOW(), last_used_step = $2
+            WHERE user_id = $1
+            "#,
+            user_id,
+            step
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Verify a code against the user's confirmed authenticator.
+    /// A code is only accepted once, even inside its validity window.
+    pub async fn verify_totp(&self, user_id: Uuid, code: &str) -> Result<()> {
+        let row = sqlx::query!(
+            r#"
+            SELECT secret_ciphertext, secret_nonce
+            FROM user_totp
+            WHERE user_id = $1 AND enabled = TRUE
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(AppError::MfaNotEnabled)?;
+
+        let secret = self.decrypt_secret(user_id, &row.secret_ciphertext, &row.secret_nonce)?;
+        let step = Self::matching_step(&secret, code).ok_or(AppError::InvalidMfaCode)?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE user_totp
+            SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+            "#,
+            user_id,
+            step
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::InvalidMfaCode);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the user's authenticator and any recovery codes
+    pub async fn disable_totp(&self, user_id: Uuid, code: &str) -> Result<()> {
+        self.verify_totp(user_id, code).await?;
+
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM user_totp
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM mfa_recovery_codes
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Generate a random recovery code, formatted as `xxxxx-xxxxx`
+    fn generate_recovery_code() -> String {
+        let mut rng = rand::thread_rng();
+        let raw: String = (0..RECOVERY_CODE_LEN)
+            .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+            .collect();
+
+        format!(
+            "{}-{}",
+            &raw[..RECOVERY_CODE_LEN / 2],
+            &raw[RECOVERY_CODE_LEN / 2..]
+        )
+    }
+
+    /// Strip separators and case so users can type codes however they like
+    fn normalize_recovery_code(code: &str) -> String {
+        code.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Replace the user's recovery codes with a fresh set.
+    /// Returns the plaintext codes; only their hashes are stored.
+    pub async fn regenerate_recovery_codes(&self, user_id: Uuid) -> Result<Vec<String>> {
+        if !self.is_totp_enabled(user_id).await? {
+            return Err(AppError::MfaNotEnabled);
+        }
+
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| Self::generate_recovery_code())
+            .collect();
+
+        let mut tx = self.db.begin().await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM mfa_recovery_codes
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        for code in &codes {
+            let code_hash = TokenService::hash_token(&Self::normalize_recovery_code(code));
+
+            sqlx::query!(
+                r#"
+                INSERT INTO mfa_recovery_codes (user_id, code_hash)
+                VALUES ($1, $2)
+                "#,
+                user_id,
+                code_hash
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(codes)
+    }
+
+    /// Count the user's unused recovery codes
+    pub async fn remaining_recovery_codes(&self, user_id: Uuid) -> Result<i64> {
+        let result = sqlx::query!(
+            r#"
+            SELECT COUNT(*) as "count!"
+            FROM mfa_recovery_codes
+            WHERE user_id = $1 AND used_at IS NULL
+            "#,
+            user_id
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(result.count)
+    }
+
+    /// Redeem a recovery code, returning how many unused codes are left
+    pub async fn use_recovery_code(&self, user_id: Uuid, code: &str) -> Result<i64> {
+        let code_hash = TokenService::hash_token(&Self::normalize_recovery_code(code));
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE mfa_recovery_codes
+            SET used_at = NOW()
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            code_hash
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::InvalidMfaCode);
+        }
+
+        self.remaining_recovery_codes(user_id).await
+    }
+
+    /// Verify either a TOTP code or a recovery code for an enabled account
+    async fn verify_second_factor(&self, user_id: Uuid, code: &str) -> Result<MfaMethod> {
+        let code = code.trim();
+
+        // TOTP codes are always 6 digits; recovery codes never are
+        if code.len() == TOTP_DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit()) {
+            self.verify_totp(user_id, code).await?;
+            return Ok(MfaMethod::Totp);
+        }
+
+        if !self.is_totp_enabled(user_id).await? {
+            return Err(AppError::MfaNotEnabled);
+        }
+
+        let remaining = self.use_recovery_code(user_id, code).await?;
+        Ok(MfaMethod::RecoveryCode { remaining })
+    }
+
+    /// Create a short-lived "mfa pending" challenge after a successful password check
+    pub async fn create_login_challenge(&self, user_id: Uuid) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut conn = self.redis.clone();
+        let key = format!("mfa_challenge:{}", TokenService::hash_token(&token));
+
+        let _: () = conn
+            .hset_multiple(
+                &key,
+                &[("user_id", user_id.to_string()), ("attempts", "0".into())],
+            )
+            .await
+            .map_err(AppError::Redis)?;
+        let _: () = conn
+            .expire(&key, self.config.mfa_challenge_expiry)
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(token)
+    }
+
+    /// Look up which user a pending login challenge belongs to, without using it up
+    pub async fn challenge_user(&self, token: &str) -> Result<Uuid> {
+        let mut conn = self.redis.clone();
+        let key = format!("mfa_challenge:{}", TokenService::hash_token(token));
+
+        let user_id: Option<String> = conn
+            .hget(&key, "user_id")
+            .await
+            .map_err(AppError::Redis)?;
+
+        user_id
+            .and_then(|id| Uuid::parse_str(&id).ok())
+            .ok_or(AppError::InvalidToken)
+    }
+
+    /// Finish a login challenge with a TOTP or recovery code, returning the user it
+    /// was issued for. The challenge is consumed on success and dropped after too
+    /// many wrong codes.
+    pub async fn complete_login_challenge(
+        &self,
+        token: &str,
+        code: &str,
+    ) -> Result<(Uuid, MfaMethod)> {
+        let mut conn = self.redis.clone();
+        let key = format!("mfa_challenge:{}", TokenService::hash_token(token));
+
+        // Claim the challenge before checking the code, so only one request can
+        // redeem it and a replayed request can't burn another recovery code
+        let (user_id, attempts, ttl_ms, _): (Option<String>, Option<i64>, i64, i64) = redis::pipe()
+            .atomic()
+            .hget(&key, "user_id")
+            .hget(&key, "attempts")
+            .pttl(&key)
+            .del(&key)
+            .query_async(&mut conn)
+            .await
+            .map_err(AppError::Redis)?;
+        let user_id = user_id
+            .and_then(|id| Uuid::parse_str(&id).ok())
+            .ok_or(AppError::InvalidToken)?;
+
+        let method = match self.verify_second_factor(user_id, code).await {
+            Ok(method) => method,
+            Err(e) => {
+                // Put the challenge back for another try, unless that was the last one
+                let attempts = attempts.unwrap_or(0) + 1;
+                if attempts < MAX_CHALLENGE_ATTEMPTS && ttl_ms > 0 {
+                    let _: () = redis::pipe()
+                        .atomic()
+                        .hset_multiple(
+                            &key,
+                            &[("user_id", user_id.to_string()), ("attempts", attempts.to_string())],
+                        )
+                        .pexpire(&key, ttl_ms)
+                        .query_async(&mut conn)
+                        .await
+                        .map_err(AppError::Redis)?;
+                }
+                return Err(e);
+            }
+        };
+
+        Ok((user_id, method))
+    }
+}
+
+/// Compare two byte strings without short-circuiting on the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret from RFC 6238 Appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_code_matches_rfc_6238_vectors() {
+        // The RFC lists 8-digit codes; ours are their last 6 digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (time, expected) in vectors {
+            assert_eq!(
+                MfaService::totp_code(RFC_SECRET, time / TOTP_STEP_SECS),
+                expected,
+                "T = {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn matching_step_allows_one_step_of_skew() {
+        let current = Utc::now().timestamp() / TOTP_STEP_SECS;
+
+        for step in [current - 1, current, current + 1] {
+            let code = MfaService::totp_code(RFC_SECRET, step);
+            assert!(MfaService::matching_step(RFC_SECRET, &code).is_some());
+        }
+    }
+
+    #[test]
+    fn matching_step_rejects_malformed_codes() {
+        assert_eq!(MfaService::matching_step(RFC_SECRET, "12345"), None);
+        assert_eq!(MfaService::matching_step(RFC_SECRET, "1234567"), None);
+        assert_eq!(MfaService::matching_step(RFC_SECRET, "12a456"), None);
+    }
+
+    #[test]
+    fn recovery_codes_normalize_to_their_raw_characters() {
+        let code = MfaService::generate_recovery_code();
+        assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+        assert_eq!(
+            MfaService::normalize_recovery_code(&code.to_uppercase()),
+            code.replace('-', "")
+        );
+        assert_eq!(MfaService::normalize_recovery_code(" abcde fghjk "), "abcdefghjk");
+    }
+}
@@ -1,111 +1,818 @@
-// auth.rs
+// oauth.rs
 use crate::{
-    config::Config,
+    config::{Config, OAuthProviderConfig},
     error::{AppError, Result},
-    models::{GoogleTokenResponse, GoogleUserInfo},
+    models::OAuthTokenResponse,
 };
-use reqwest::Client;
-use serde_json::json;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use reqwest::{header, Client};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// How long a sign-in may take between `/start` and `/callback`, in seconds
+pub const OAUTH_STATE_EXPIRY: i64 = 600;
+
+/// How long discovery documents and provider keys are cached
+const METADATA_TTL: Duration = Duration::from_secs(3600);
 
+/// Endpoints and signing keys of a provider, from its discovery document or config
 #[derive(Clone)]
-pub struct OAuthService {
+struct ProviderMetadata {
+    issuer: Option<String>,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    /// Present for OpenID Connect providers, which return a signed ID token
+    jwks: Option<JwkSet>,
+    fetched_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+/// An entry in a provider's email list (GitHub's `/user/emails`)
+#[derive(Deserialize)]
+struct ProviderEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+/// What `/start` leaves in Redis for `/callback`
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    provider: String,
+    code_verifier: String,
+    nonce: String,
+    intent: OAuthIntent,
+}
+
+/// Why the browser was sent to the provider
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum OAuthIntent {
+    /// Sign in, creating the account on first use
+    SignIn,
+    /// Link the provider account to a user who is already signed in
+    Link { user_id: Uuid },
+}
+
+/// A sign-in that was sent off to the provider
+pub struct OAuthStart {
+    pub auth_url: String,
+    /// Also set as a cookie, so the callback only completes in the browser that started it
+    pub state: String,
+}
+
+/// The account a provider says signed in, after claim mapping
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+}
+
+/// Take the email and its verification status from the account's primary
+/// address, in place of whatever the profile showed
+fn apply_primary_email(
+    provider: &OAuthProviderConfig,
+    claims: &mut Map<String, Value>,
+    emails: &[ProviderEmail],
+) {
+    claims.remove(&provider.email_claim);
+    let Some(primary) = emails.iter().find(|email| email.primary) else {
+        return;
+    };
+
+    claims.insert(
+        provider.email_claim.clone(),
+        Value::String(primary.email.clone()),
+    );
+    if let Some(claim) = &provider.email_verified_claim {
+        claims.insert(claim.clone(), Value::Bool(primary.verified));
+    }
+}
+
+/// HMAC over the state nonce, keyed with `JWT_SECRET`
+fn state_mac(secret: &[u8], nonce: &str) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(b"oauth_state:");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+/// `<nonce>.<signature>`, so a callback can't bring a state we didn't hand out
+fn sign_state(secret: &[u8], nonce: &str) -> String {
+    let signature = URL_SAFE_NO_PAD.encode(state_mac(secret, nonce).finalize().into_bytes());
+    format!("{}.{}", nonce, signature)
+}
+
+/// Check a state's signature and return its nonce
+fn verify_state<'a>(secret: &[u8], state: &'a str) -> Result<&'a str> {
+    let (nonce, signature) = state.split_once('.').ok_or(AppError::InvalidToken)?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AppError::InvalidToken)?;
+    state_mac(secret, nonce)
+        .verify_slice(&signature)
+        .map_err(|_| AppError::InvalidToken)?;
+
+    Ok(nonce)
+}
+
+/// PKCE S256 code challenge for a verifier (RFC 7636)
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Registry of the sign-in providers from config. Talks OpenID Connect
+/// (discovery, ID tokens checked against the provider's JWKS) or plain
+/// OAuth 2.0 with a userinfo endpoint.
+#[derive(Clone)]
+struct OAuthProviders {
     client: Client,
+    providers: Arc<HashMap<String, OAuthProviderConfig>>,
+    metadata: Arc<Mutex<HashMap<String, ProviderMetadata>>>,
+}
+
+/// Social sign-in: the provider protocol, plus the `state` kept in Redis
+/// between `/start` and `/callback`
+#[derive(Clone)]
+pub struct OAuthService {
+    providers: OAuthProviders,
+    redis: ConnectionManager,
     config: Config,
 }
 
 impl OAuthService {
-    pub fn new(config: Config) -> Self {
-        Self {
-            client: Client::new(),
+    pub fn new(redis: ConnectionManager, config: Config) -> Result<Self> {
+        Ok(Self {
+            providers: OAuthProviders::new(&config.oauth_providers)?,
+            redis,
             config,
+        })
+    }
+
+    fn random_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Create a signed `state`, a PKCE verifier and an OIDC nonce (kept in
+    /// Redis) and build the provider's authorization URL
+    pub async fn begin_login(&self, provider_name: &str, intent: OAuthIntent) -> Result<OAuthStart> {
+        let provider = self.providers.provider(provider_name)?;
+
+        let state_nonce = Self::random_token();
+        let state = sign_state(self.config.jwt_secret.as_bytes(), &state_nonce);
+
+        let pending = PendingLogin {
+            provider: provider.name.clone(),
+            code_verifier: Self::random_token(),
+            nonce: Self::random_token(),
+            intent,
+        };
+        let code_challenge = pkce_challenge(&pending.code_verifier);
+
+        let auth_url = self
+            .providers
+            .authorization_url(provider, &state, &pending.nonce, &code_challenge)
+            .await?;
+
+        let mut conn = self.redis.clone();
+        let _: () = conn
+            .set_ex(
+                format!("oauth_state:{}", state_nonce),
+                serde_json::to_string(&pending)?,
+                OAUTH_STATE_EXPIRY as u64,
+            )
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(OAuthStart { auth_url, state })
+    }
+
+    /// Check the `state` signature and consume it
+    async fn consume_state(&self, state: &str) -> Result<PendingLogin> {
+        let nonce = verify_state(self.config.jwt_secret.as_bytes(), state)?;
+
+        // Single use - a replayed callback finds nothing
+        let mut conn = self.redis.clone();
+        let pending: Option<String> = conn
+            .get_del(format!("oauth_state:{}", nonce))
+            .await
+            .map_err(AppError::Redis)?;
+
+        let pending = pending.ok_or(AppError::InvalidToken)?;
+        Ok(serde_json::from_str(&pending)?)
+    }
+
+    /// Finish a sign-in on the callback: redeem the code and work out who
+    /// signed in, along with what the flow was started for
+    pub async fn complete_login(
+        &self,
+        provider_name: &str,
+        code: &str,
+        state: &str,
+    ) -> Result<(ExternalIdentity, OAuthIntent)> {
+        let provider = self.providers.provider(provider_name)?;
+        let pending = self.consume_state(state).await?;
+
+        if pending.provider != provider.name {
+            return Err(AppError::InvalidToken);
         }
+
+        let identity = self
+            .providers
+            .identify(provider, code, &pending.code_verifier, &pending.nonce)
+            .await?;
+
+        Ok((identity, pending.intent))
     }
+}
 
-    /// Generate Google OAuth authorization URL
-    pub fn get_google_auth_url(&self, state: &str) -> String {
-        let scope = "openid email profile";
-        
-        format!(
-            "https://accounts.google.com/o/oauth2/v2/auth?\
-             client_id={}&\
-             redirect_uri={}&\
-             response_type=code&\
-             scope={}&\
-             state={}&\
-             access_type=offline&\
-             prompt=consent",
-            self.config.google_client_id,
-            urlencoding::encode(&self.config.google_redirect_uri),
-            urlencoding::encode(scope),
-            state
-        )
+impl OAuthProviders {
+    fn new(providers: &[OAuthProviderConfig]) -> Result<Self> {
+        let client = Client::builder()
+            .user_agent(concat!("auth-backend/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| AppError::InternalServerError(e.to_string()))?;
+
+        let providers = providers
+            .iter()
+            .map(|provider| (provider.name.clone(), provider.clone()))
+            .collect();
+
+        Ok(Self {
+            client,
+            providers: Arc::new(providers),
+            metadata: Arc::default(),
+        })
     }
 
-    /// Exchange authorization code for access token
-    pub async fn exchange_google_code(&self, code: &str) -> Result<GoogleTokenResponse> {
-        let token_url = "https://oauth2.googleapis.com/token";
-        
-        let params = json!({
-            "code": code,
-            "client_id": self.config.google_client_id,
-            "client_secret": self.config.google_client_secret,
-            "redirect_uri": self.config.google_redirect_uri,
-            "grant_type": "authorization_code"
-        });
+    fn provider(&self, name: &str) -> Result<&OAuthProviderConfig> {
+        self.providers
+            .get(name)
+            .ok_or(AppError::OAuthProviderNotFound)
+    }
 
+    async fn fetch_json<T: DeserializeOwned>(&self, url: &str, what: &str) -> Result<T> {
         let response = self
             .client
-            .post(token_url)
-            .json(&params)
+            .get(url)
             .send()
             .await
-            .map_err(|e| {
-                tracing::error!("Failed to exchange Google code: {:?}", e);
-                AppError::InternalServerError("OAuth exchange failed".to_string())
-            })?;
+            .map_err(|e| AppError::ExternalAuthFailed(format!("{} unreachable: {}", what, e)))?;
 
         if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            tracing::error!("Google token exchange failed: {}", error_text);
-            return Err(AppError::BadRequest("Invalid authorization code".to_string()));
+            return Err(AppError::ExternalAuthFailed(format!(
+                "{} returned {}",
+                what,
+                response.status()
+            )));
         }
 
         response
-            .json::<GoogleTokenResponse>()
+            .json::<T>()
             .await
-            .map_err(|e| {
-                tracing::error!("Failed to parse Google token response: {:?}", e);
-                AppError::InternalServerError("OAuth parsing failed".to_string())
-            })
+            .map_err(|e| AppError::ExternalAuthFailed(format!("{} unreadable: {}", what, e)))
+    }
+
+    /// Endpoints and keys for a provider, from cache unless stale or `refresh` is set
+    async fn metadata(&self, provider: &OAuthProviderConfig, refresh: bool) -> Result<ProviderMetadata> {
+        if !refresh {
+            let cache = self.metadata.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(metadata) = cache.get(&provider.name) {
+                if metadata.fetched_at.elapsed() < METADATA_TTL {
+                    return Ok(metadata.clone());
+                }
+            }
+        }
+
+        let metadata = match &provider.issuer {
+            Some(issuer) => {
+                let discovery_url = format!(
+                    "{}/.well-known/openid-configuration",
+                    issuer.trim_end_matches('/')
+                );
+                let document: DiscoveryDocument = self
+                    .fetch_json(&discovery_url, "Discovery document")
+                    .await?;
+                let jwks: JwkSet = self.fetch_json(&document.jwks_uri, "Provider JWKS").await?;
+
+                // Explicitly configured endpoints win over discovered ones
+                ProviderMetadata {
+                    issuer: Some(document.issuer),
+                    authorization_endpoint: provider
+                        .auth_url
+                        .clone()
+                        .unwrap_or(document.authorization_endpoint),
+                    token_endpoint: provider.token_url.clone().unwrap_or(document.token_endpoint),
+                    userinfo_endpoint: provider.userinfo_url.clone().or(document.userinfo_endpoint),
+                    jwks: Some(jwks),
+                    fetched_at: Instant::now(),
+                }
+            }
+            None => ProviderMetadata {
+                issuer: None,
+                authorization_endpoint: provider.auth_url.clone().unwrap_or_default(),
+                token_endpoint: provider.token_url.clone().unwrap_or_default(),
+                userinfo_endpoint: provider.userinfo_url.clone(),
+                jwks: None,
+                fetched_at: Instant::now(),
+            },
+        };
+
+        self.metadata
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(provider.name.clone(), metadata.clone());
+
+        Ok(metadata)
+    }
+
+    /// The provider's authorization URL for a signed `state`, OIDC nonce and
+    /// PKCE challenge
+    async fn authorization_url(
+        &self,
+        provider: &OAuthProviderConfig,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String> {
+        let metadata = self.metadata(provider, false).await?;
+
+        Ok(format!(
+            "{}{}client_id={}&redirect_uri={}&response_type=code&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+            metadata.authorization_endpoint,
+            if metadata.authorization_endpoint.contains('?') { '&' } else { '?' },
+            urlencoding::encode(&provider.client_id),
+            urlencoding::encode(&provider.redirect_uri),
+            urlencoding::encode(&provider.scopes),
+            state,
+            nonce,
+            code_challenge
+        ))
+    }
+
+    /// Redeem an authorization code and work out who signed in
+    async fn identify(
+        &self,
+        provider: &OAuthProviderConfig,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<ExternalIdentity> {
+        let metadata = self.metadata(provider, false).await?;
+        let tokens = self
+            .exchange_code(provider, &metadata, code, code_verifier)
+            .await?;
+
+        let claims = if metadata.jwks.is_some() {
+            let id_token = tokens.id_token.as_deref().ok_or_else(|| {
+                AppError::ExternalAuthFailed("no ID token in token response".to_string())
+            })?;
+            let mut claims = self
+                .verify_id_token(provider, metadata.clone(), id_token, nonce)
+                .await?;
+
+            // Some providers leave the email out of the ID token
+            if !claims.contains_key(&provider.email_claim) {
+                if let Some(userinfo_url) = &metadata.userinfo_endpoint {
+                    let userinfo: Map<String, Value> = self
+                        .fetch_with_token(userinfo_url, &tokens.access_token, "userinfo")
+                        .await?;
+                    if userinfo.get("sub") == claims.get("sub") {
+                        for (key, value) in userinfo {
+                            claims.entry(key).or_insert(value);
+                        }
+                    }
+                }
+            }
+
+            claims
+        } else {
+            let userinfo_url = metadata.userinfo_endpoint.as_deref().unwrap_or_default();
+            let mut claims: Map<String, Value> = self
+                .fetch_with_token(userinfo_url, &tokens.access_token, "userinfo")
+                .await?;
+
+            // A GitHub profile shows whichever address the user picked, verified
+            // or not - the primary address and its status come from the list
+            if let Some(emails_url) = &provider.emails_url {
+                let emails: Vec<ProviderEmail> = self
+                    .fetch_with_token(emails_url, &tokens.access_token, "email list")
+                    .await?;
+                apply_primary_email(provider, &mut claims, &emails);
+            }
+
+            claims
+        };
+
+        Self::map_claims(provider, &claims)
     }
 
-    /// Get Google user info using access token
-    pub async fn get_google_user_info(&self, access_token: &str) -> Result<GoogleUserInfo> {
-        let user_info_url = "https://www.googleapis.com/oauth2/v2/userinfo";
+    async fn exchange_code(
+        &self,
+        provider: &OAuthProviderConfig,
+        metadata: &ProviderMetadata,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<OAuthTokenResponse> {
+        let mut params = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if !provider.client_secret.is_empty() {
+            params.push(("client_secret", provider.client_secret.as_str()));
+        }
 
         let response = self
             .client
-            .get(user_info_url)
-            .bearer_auth(access_token)
+            .post(&metadata.token_endpoint)
+            // GitHub answers form-encoded unless asked for JSON
+            .header(header::ACCEPT, "application/json")
+            .form(&params)
             .send()
             .await
             .map_err(|e| {
-                tracing::error!("Failed to fetch Google user info: {:?}", e);
-                AppError::InternalServerError("Failed to fetch user info".to_string())
+                tracing::error!("Failed to exchange {} code: {:?}", provider.name, e);
+                AppError::InternalServerError("OAuth exchange failed".to_string())
             })?;
 
         if !response.status().is_success() {
             let error_text = response.text().await.unwrap_or_default();
-            tracing::error!("Google user info fetch failed: {}", error_text);
-            return Err(AppError::BadRequest("Failed to get user info".to_string()));
+            tracing::error!("{} token exchange failed: {}", provider.name, error_text);
+            return Err(AppError::BadRequest("Invalid authorization code".to_string()));
+        }
+
+        response.json::<OAuthTokenResponse>().await.map_err(|e| {
+            AppError::ExternalAuthFailed(format!("unexpected token response: {}", e))
+        })
+    }
+
+    /// GET a resource with the user's access token
+    async fn fetch_with_token<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        access_token: &str,
+        what: &str,
+    ) -> Result<T> {
+        let response = self
+            .client
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(|e| AppError::ExternalAuthFailed(format!("{} unreachable: {}", what, e)))?;
+
+        if !response.status().is_success() {
+            return Err(AppError::ExternalAuthFailed(format!(
+                "{} returned {}",
+                what,
+                response.status()
+            )));
         }
 
         response
-            .json::<GoogleUserInfo>()
+            .json::<T>()
             .await
-            .map_err(|e| {
-                tracing::error!("Failed to parse Google user info: {:?}", e);
-                AppError::InternalServerError("User info parsing failed".to_string())
-            })
+            .map_err(|e| AppError::ExternalAuthFailed(format!("{} unreadable: {}", what, e)))
     }
-}
\ No newline at end of file
+
+    /// Verify an ID token's signature against the provider's JWKS, then its
+    /// issuer, audience, expiry and nonce
+    async fn verify_id_token(
+        &self,
+        provider: &OAuthProviderConfig,
+        mut metadata: ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Map<String, Value>> {
+        let failed = |reason: &str| AppError::ExternalAuthFailed(format!("ID token {}", reason));
+
+        let header = decode_header(id_token).map_err(|_| failed("is malformed"))?;
+
+        // Only public-key algorithms - a shared secret would be the client secret
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(failed("uses a symmetric algorithm"));
+        }
+
+        let find_key = |metadata: &ProviderMetadata| {
+            let jwks = metadata.jwks.as_ref()?;
+            match &header.kid {
+                Some(kid) => jwks.find(kid).cloned(),
+                None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+                None => None,
+            }
+        };
+
+        let jwk = match find_key(&metadata) {
+            Some(jwk) => jwk,
+            None => {
+                // The provider may have rotated its keys since we cached them
+                metadata = self.metadata(provider, true).await?;
+                find_key(&metadata).ok_or_else(|| failed("is signed with an unknown key"))?
+            }
+        };
+        let decoding_key = DecodingKey::from_jwk(&jwk).map_err(|_| failed("key is unusable"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = decode::<Map<String, Value>>(id_token, &decoding_key, &validation)
+            .map_err(|e| failed(&format!("is invalid: {}", e)))?
+            .claims;
+
+        // Multi-tenant issuers (Microsoft's `common`) name the tenant in the token
+        let expected_issuer = metadata.issuer.as_deref().map(|issuer| {
+            match claims.get("tid").and_then(Value::as_str) {
+                Some(tid) => issuer.replace("{tenantid}", tid),
+                None => issuer.to_string(),
+            }
+        });
+        if claims.get("iss").and_then(Value::as_str) != expected_issuer.as_deref() {
+            return Err(failed("has the wrong issuer"));
+        }
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(failed("has the wrong nonce"));
+        }
+
+        Ok(claims)
+    }
+
+    /// Map provider claims onto subject, email and email verification
+    fn map_claims(provider: &OAuthProviderConfig, claims: &Map<String, Value>) -> Result<ExternalIdentity> {
+        let text = |claim: &str| match claims.get(claim) {
+            Some(Value::String(value)) if !value.is_empty() => Some(value.clone()),
+            Some(Value::Number(value)) => Some(value.to_string()),
+            _ => None,
+        };
+
+        let subject = text(&provider.subject_claim).ok_or_else(|| {
+            AppError::ExternalAuthFailed(format!("{} sent no subject", provider.name))
+        })?;
+        let email = text(&provider.email_claim).ok_or_else(|| {
+            AppError::ExternalAuthFailed(format!("{} shared no email address", provider.name))
+        })?;
+
+        let email_verified = match &provider.email_verified_claim {
+            None => true,
+            Some(claim) => match claims.get(claim) {
+                Some(Value::Bool(verified)) => *verified,
+                Some(Value::String(verified)) => verified == "true",
+                _ => false,
+            },
+        };
+
+        Ok(ExternalIdentity {
+            provider: provider.name.clone(),
+            subject,
+            email,
+            email_verified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_idp::{self, Stub};
+
+    const SECRET: &[u8] = b"test-secret";
+
+    #[test]
+    fn pkce_challenge_matches_rfc_7636_example() {
+        // RFC 7636 Appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn signed_state_round_trips_to_its_nonce() {
+        let nonce = OAuthService::random_token();
+        let state = sign_state(SECRET, &nonce);
+
+        assert_eq!(verify_state(SECRET, &state).unwrap(), nonce);
+    }
+
+    #[test]
+    fn tampered_or_foreign_states_are_rejected() {
+        let state = sign_state(SECRET, "nonce-1");
+        let (_, signature) = state.split_once('.').unwrap();
+
+        // Another nonce under the same signature
+        assert!(verify_state(SECRET, &format!("nonce-2.{}", signature)).is_err());
+        // Signed with a different secret
+        assert!(verify_state(b"other-secret", &state).is_err());
+        // Unsigned or mangled
+        assert!(verify_state(SECRET, "nonce-1").is_err());
+        assert!(verify_state(SECRET, "nonce-1.not*base64").is_err());
+        assert!(verify_state(SECRET, "nonce-1.").is_err());
+    }
+
+    async fn spawn_stub_idp(email_verified: bool) -> OAuthProviderConfig {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let stub = Stub::new(
+            issuer.clone(),
+            "stub-client".to_string(),
+            "stub.user@example.com".to_string(),
+            email_verified,
+        )
+        .unwrap();
+        tokio::spawn(async move { axum::serve(listener, stub_idp::router(stub)).await });
+
+        OAuthProviderConfig {
+            name: "stub".to_string(),
+            client_id: "stub-client".to_string(),
+            client_secret: String::new(),
+            redirect_uri: "http://localhost:8000/api/auth/oauth/stub/callback".to_string(),
+            scopes: "openid email".to_string(),
+            issuer: Some(issuer),
+            auth_url: None,
+            token_url: None,
+            userinfo_url: None,
+            emails_url: None,
+            subject_claim: "sub".to_string(),
+            email_claim: "email".to_string(),
+            email_verified_claim: Some("email_verified".to_string()),
+        }
+    }
+
+    /// Follow the authorization URL to the provider's redirect and take the code from it
+    async fn authorize(
+        providers: &OAuthProviders,
+        provider: &OAuthProviderConfig,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> String {
+        let state = sign_state(SECRET, "state-nonce");
+        let auth_url = providers
+            .authorization_url(provider, &state, nonce, &pkce_challenge(code_verifier))
+            .await
+            .unwrap();
+
+        let client = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = client.get(&auth_url).send().await.unwrap();
+        assert!(response.status().is_redirection());
+
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        let redirect = reqwest::Url::parse(location).unwrap();
+        assert!(location.starts_with(&provider.redirect_uri));
+
+        let param = |name: &str| {
+            redirect
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        assert_eq!(param("state").as_deref(), Some(state.as_str()));
+        param("code").unwrap()
+    }
+
+    #[tokio::test]
+    async fn signs_in_through_a_stub_provider() {
+        let provider = spawn_stub_idp(true).await;
+        let providers = OAuthProviders::new(std::slice::from_ref(&provider)).unwrap();
+
+        let code_verifier = OAuthService::random_token();
+        let nonce = OAuthService::random_token();
+        let code = authorize(&providers, &provider, &code_verifier, &nonce).await;
+
+        let identity = providers
+            .identify(&provider, &code, &code_verifier, &nonce)
+            .await
+            .unwrap();
+        assert_eq!(identity.provider, "stub");
+        assert_eq!(identity.email, "stub.user@example.com");
+        assert!(identity.email_verified);
+        assert!(!identity.subject.is_empty());
+
+        // Codes are single use
+        assert!(providers
+            .identify(&provider, &code, &code_verifier, &nonce)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn stub_provider_rejects_a_mismatched_verifier_or_nonce() {
+        let provider = spawn_stub_idp(true).await;
+        let providers = OAuthProviders::new(std::slice::from_ref(&provider)).unwrap();
+        let nonce = OAuthService::random_token();
+
+        // PKCE: the token endpoint refuses a verifier that doesn't match the challenge
+        let code = authorize(&providers, &provider, "verifier-one", &nonce).await;
+        assert!(providers
+            .identify(&provider, &code, "verifier-two", &nonce)
+            .await
+            .is_err());
+
+        // The ID token carries the nonce from the authorization request
+        let code = authorize(&providers, &provider, "verifier-one", &nonce).await;
+        assert!(matches!(
+            providers
+                .identify(&provider, &code, "verifier-one", "another-nonce")
+                .await,
+            Err(AppError::ExternalAuthFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stub_provider_reports_unverified_emails() {
+        let provider = spawn_stub_idp(false).await;
+        let providers = OAuthProviders::new(std::slice::from_ref(&provider)).unwrap();
+        let nonce = OAuthService::random_token();
+
+        let code = authorize(&providers, &provider, "verifier", &nonce).await;
+        let identity = providers
+            .identify(&provider, &code, "verifier", &nonce)
+            .await
+            .unwrap();
+        assert!(!identity.email_verified);
+    }
+
+    #[test]
+    fn primary_email_and_its_status_replace_the_profile_email() {
+        let provider = OAuthProviderConfig::preset("github", None);
+
+        let emails = |list: &[(&str, bool, bool)]| {
+            list.iter()
+                .map(|&(email, primary, verified)| ProviderEmail {
+                    email: email.to_string(),
+                    primary,
+                    verified,
+                })
+                .collect::<Vec<_>>()
+        };
+        let profile = || {
+            let mut claims = Map::new();
+            claims.insert("id".to_string(), Value::from(42));
+            claims.insert("email".to_string(), Value::from("shown@example.com"));
+            claims
+        };
+
+        let mut claims = profile();
+        apply_primary_email(
+            &provider,
+            &mut claims,
+            &emails(&[
+                ("shown@example.com", false, false),
+                ("primary@example.com", true, true),
+            ]),
+        );
+        let identity = OAuthProviders::map_claims(&provider, &claims).unwrap();
+        assert_eq!(identity.email, "primary@example.com");
+        assert!(identity.email_verified);
+
+        // An unverified primary address is reported as unverified
+        let mut claims = profile();
+        apply_primary_email(
+            &provider,
+            &mut claims,
+            &emails(&[("primary@example.com", true, false)]),
+        );
+        let identity = OAuthProviders::map_claims(&provider, &claims).unwrap();
+        assert!(!identity.email_verified);
+
+        // No primary address, no email - never the profile's unchecked one
+        let mut claims = profile();
+        apply_primary_email(&provider, &mut claims, &[]);
+        assert!(OAuthProviders::map_claims(&provider, &claims).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{
+    error::{AppError, Result},
+    models::OAuthClient,
+    services::password::PasswordService,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Registered OAuth clients and the consent users gave them
+#[derive(Clone)]
+pub struct OAuthClientStore {
+    db: PgPool,
+}
+
+impl OAuthClientStore {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Register a client. Confidential clients get a secret, returned only here.
+    pub async fn create_client(
+        &self,
+        client_id: &str,
+        name: &str,
+        redirect_uris: &[String],
+        allowed_scopes: &[String],
+        grant_types: &[String],
+        confidential: bool,
+    ) -> Result<(OAuthClient, Option<String>)> {
+        let allows = |grant_type: &str| grant_types.iter().any(|g| g == grant_type);
+        if allows("client_credentials") && !confidential {
+            return Err(AppError::BadRequest(
+                "The client credentials grant needs a confidential client".to_string(),
+            ));
+        }
+        if allows("authorization_code") && redirect_uris.is_empty() {
+            return Err(AppError::BadRequest(
+                "At least one redirect URI is required".to_string(),
+            ));
+        }
+
+        for uri in redirect_uris {
+            let parsed = reqwest::Url::parse(uri)
+                .map_err(|_| AppError::BadRequest(format!("Invalid redirect URI: {}", uri)))?;
+            if parsed.fragment().is_some() {
+                return Err(AppError::BadRequest(format!(
+                    "Redirect URI must not have a fragment: {}",
+                    uri
+                )));
+            }
+        }
+
+        let client_secret = confidential.then(|| {
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            URL_SAFE_NO_PAD.encode(bytes)
+        });
+        let client_secret_hash = client_secret
+            .as_deref()
+            .map(PasswordService::hash_password)
+            .transpose()?;
+
+        let client = sqlx::query_as!(
+            OAuthClient,
+            r#"
+            INSERT INTO oauth_clients
+                (client_id, client_secret_hash, name, redirect_uris, allowed_scopes, grant_types)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING client_id, client_secret_hash, name, redirect_uris, allowed_scopes,
+                      grant_types, is_active, created_at
+            "#,
+            client_id,
+            client_secret_hash,
+            name,
+            redirect_uris,
+            allowed_scopes,
+            grant_types
+        )
+        .fetch_one(&self.db)
+        .await
+        .map_err(|e| {
+            if let sqlx::Error::Database(db_err) = &e {
+                if db_err.is_unique_violation() {
+                    return AppError::BadRequest(format!("Client {} already exists", client_id));
+                }
+            }
+            AppError::Database(e)
+        })?;
+
+        Ok((client, client_secret))
+    }
+
+    pub async fn list_clients(&self) -> Result<Vec<OAuthClient>> {
+        let clients = sqlx::query_as!(
+            OAuthClient,
+            r#"
+            SELECT client_id, client_secret_hash, name, redirect_uris, allowed_scopes,
+                   grant_types, is_active, created_at
+            FROM oauth_clients
+            ORDER BY created_at
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(clients)
+    }
+
+    /// Remove a client along with its consents and refresh tokens
+    pub async fn delete_client(&self, client_id: &str) -> Result<()> {
+        let result = sqlx::query!("DELETE FROM oauth_clients WHERE client_id = $1", client_id)
+            .execute(&self.db)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::BadRequest(format!("Client {} not found", client_id)));
+        }
+
+        Ok(())
+    }
+
+    /// An active client by its public id
+    pub async fn find_client(&self, client_id: &str) -> Result<Option<OAuthClient>> {
+        let client = sqlx::query_as!(
+            OAuthClient,
+            r#"
+            SELECT client_id, client_secret_hash, name, redirect_uris, allowed_scopes,
+                   grant_types, is_active, created_at
+            FROM oauth_clients
+            WHERE client_id = $1 AND is_active
+            "#,
+            client_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(client)
+    }
+
+    /// Check a client's credentials at the token endpoint. Public clients
+    /// present no secret; confidential ones must.
+    pub async fn authenticate_client(
+        &self,
+        client_id: &str,
+        client_secret: Option<&str>,
+    ) -> Result<OAuthClient> {
+        let invalid_client = || AppError::oauth("invalid_client", "Client authentication failed");
+
+        let client = self
+            .find_client(client_id)
+            .await?
+            .ok_or_else(invalid_client)?;
+
+        match (&client.client_secret_hash, client_secret) {
+            (None, None) => Ok(client),
+            (Some(hash), Some(secret)) if PasswordService::verify_password(secret, hash)? => {
+                Ok(client)
+            }
+            _ => Err(invalid_client()),
+        }
+    }
+
+    /// Scopes the user has already granted the client
+    pub async fn granted_scopes(&self, user_id: Uuid, client_id: &str) -> Result<Vec<String>> {
+        let scopes = sqlx::query_scalar!(
+            "SELECT scopes FROM oauth_consents WHERE user_id = $1 AND client_id = $2",
+            user_id,
+            client_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(scopes.unwrap_or_default())
+    }
+
+    /// Remember that the user granted the client these scopes, on top of earlier grants
+    pub async fn record_consent(
+        &self,
+        user_id: Uuid,
+        client_id: &str,
+        scopes: &[String],
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_consents (user_id, client_id, scopes)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, client_id) DO UPDATE
+            SET scopes = ARRAY(
+                    SELECT DISTINCT unnest(oauth_consents.scopes || EXCLUDED.scopes)
+                ),
+                granted_at = NOW()
+            "#,
+            user_id,
+            client_id,
+            scopes
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+}
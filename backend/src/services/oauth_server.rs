@@ -0,0 +1,629 @@
+use crate::{
+    config::Config,
+    error::{AppError, Result},
+    models::{AuthorizationRequestResponse, AuthorizeQuery, DeviceRequestResponse, OAuthClient},
+    services::{oauth_clients::OAuthClientStore, token::TokenService},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{Rng, RngCore};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// How long the user has to decide on the consent page, in seconds
+const AUTHORIZATION_REQUEST_EXPIRY: u64 = 600;
+
+/// How long an authorization code can be redeemed, in seconds
+const AUTHORIZATION_CODE_EXPIRY: u64 = 60;
+
+/// How long a device has for the user to approve it, in seconds
+const DEVICE_CODE_EXPIRY: u64 = 600;
+
+/// Seconds a device waits between polls, raised by `slow_down`
+const DEVICE_POLL_INTERVAL: u64 = 5;
+
+/// Grant type of the device flow at the token endpoint
+pub const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// User codes avoid vowels (no words) and look-alike characters
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// A validated `/oauth/authorize` request waiting for the user's decision
+#[derive(Serialize, Deserialize)]
+struct AuthorizationRequest {
+    client_id: String,
+    redirect_uri: String,
+    /// Whether the client named `redirect_uri` itself rather than relying on
+    /// its only registered one - the token request must then repeat it
+    #[serde(default)]
+    redirect_uri_sent: bool,
+    scopes: Vec<String>,
+    state: Option<String>,
+    code_challenge: String,
+    nonce: Option<String>,
+}
+
+/// What an authorization code stands for once the user approved
+#[derive(Serialize, Deserialize)]
+pub struct AuthorizationGrant {
+    pub client_id: String,
+    pub user_id: Uuid,
+    pub redirect_uri: String,
+    #[serde(default)]
+    redirect_uri_sent: bool,
+    pub scopes: Vec<String>,
+    code_challenge: String,
+    /// Echoed in the ID token so the client can tie it to its request
+    pub nonce: Option<String>,
+    /// When the user signed in, as a Unix timestamp
+    pub auth_time: i64,
+}
+
+/// A device waiting for a user to enter its user code (RFC 8628)
+#[derive(Serialize, Deserialize)]
+struct DeviceAuthorization {
+    client_id: String,
+    scopes: Vec<String>,
+    interval: u64,
+    decision: DeviceDecision,
+}
+
+#[derive(Serialize, Deserialize)]
+enum DeviceDecision {
+    Pending,
+    Approved { user_id: Uuid },
+    Denied,
+}
+
+/// What a device gets to sign in as once its user code was approved
+pub struct DeviceGrant {
+    pub user_id: Uuid,
+    pub scopes: Vec<String>,
+}
+
+/// Returned to the device when it starts the flow
+pub struct DeviceCodes {
+    pub device_code: String,
+    pub user_code: String,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+/// OAuth 2.0 authorization server for registered clients: the authorization
+/// code flow with PKCE. Requests and codes live in Redis; tokens are issued
+/// by the token endpoint handler.
+#[derive(Clone)]
+pub struct OAuthServerService {
+    clients: OAuthClientStore,
+    redis: ConnectionManager,
+    config: Config,
+}
+
+impl OAuthServerService {
+    pub fn new(clients: OAuthClientStore, redis: ConnectionManager, config: Config) -> Self {
+        Self {
+            clients,
+            redis,
+            config,
+        }
+    }
+
+    fn random_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Split a space-separated `scope` and check it against what the client may
+    /// ask for. No `scope` means everything the client is allowed.
+    pub fn resolve_scopes(client: &OAuthClient, scope: Option<&str>) -> Result<Vec<String>> {
+        let Some(scope) = scope else {
+            return Ok(client.allowed_scopes.clone());
+        };
+
+        let mut scopes: Vec<String> = Vec::new();
+        for requested in scope.split_whitespace() {
+            if !client.allowed_scopes.iter().any(|allowed| allowed == requested) {
+                return Err(AppError::oauth(
+                    "invalid_scope",
+                    format!("Scope {} is not allowed for this client", requested),
+                ));
+            }
+            if !scopes.iter().any(|s| s == requested) {
+                scopes.push(requested.to_string());
+            }
+        }
+
+        Ok(scopes)
+    }
+
+    /// Find the client and the redirect URI to answer on. Until both check out,
+    /// errors go to the user, never to an unverified redirect URI.
+    pub async fn resolve_redirect(&self, query: &AuthorizeQuery) -> Result<(OAuthClient, String)> {
+        let client_id = query
+            .client_id
+            .as_deref()
+            .ok_or_else(|| AppError::oauth("invalid_request", "Missing client_id"))?;
+        let client = self
+            .clients
+            .find_client(client_id)
+            .await?
+            .ok_or_else(|| AppError::oauth("invalid_request", "Unknown client"))?;
+
+        // Exact match only - no prefix or wildcard matching
+        let redirect_uri = match &query.redirect_uri {
+            Some(uri) if client.redirect_uris.contains(uri) => uri.clone(),
+            Some(_) => {
+                return Err(AppError::oauth(
+                    "invalid_request",
+                    "redirect_uri is not registered for this client",
+                ))
+            }
+            None if client.redirect_uris.len() == 1 => client.redirect_uris[0].clone(),
+            None => return Err(AppError::oauth("invalid_request", "Missing redirect_uri")),
+        };
+
+        Ok((client, redirect_uri))
+    }
+
+    /// Validate the rest of an authorization request and park it for the
+    /// consent page. Returns the id the consent page works with.
+    pub async fn create_authorization_request(
+        &self,
+        client: &OAuthClient,
+        redirect_uri: &str,
+        query: &AuthorizeQuery,
+    ) -> Result<String> {
+        if !client.allows_grant("authorization_code") {
+            return Err(AppError::oauth(
+                "unauthorized_client",
+                "This client may not use the authorization code flow",
+            ));
+        }
+        if query.response_type.as_deref() != Some("code") {
+            return Err(AppError::oauth(
+                "unsupported_response_type",
+                "Only response_type=code is supported",
+            ));
+        }
+
+        // PKCE is required of every client, confidential ones included
+        let code_challenge = query
+            .code_challenge
+            .clone()
+            .ok_or_else(|| AppError::oauth("invalid_request", "Missing code_challenge"))?;
+        if query.code_challenge_method.as_deref() != Some("S256") {
+            return Err(AppError::oauth(
+                "invalid_request",
+                "code_challenge_method must be S256",
+            ));
+        }
+
+        let request = AuthorizationRequest {
+            client_id: client.client_id.clone(),
+            redirect_uri: redirect_uri.to_string(),
+            redirect_uri_sent: query.redirect_uri.is_some(),
+            scopes: Self::resolve_scopes(client, query.scope.as_deref())?,
+            state: query.state.clone(),
+            code_challenge,
+            nonce: query.nonce.clone(),
+        };
+
+        let request_id = Self::random_token();
+        let mut conn = self.redis.clone();
+        let _: () = conn
+            .set_ex(
+                format!("oauth_authz:{}", request_id),
+                serde_json::to_string(&request)?,
+                AUTHORIZATION_REQUEST_EXPIRY,
+            )
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(request_id)
+    }
+
+    async fn load_request(&self, request_id: &str, consume: bool) -> Result<AuthorizationRequest> {
+        let key = format!("oauth_authz:{}", request_id);
+        let mut conn = self.redis.clone();
+
+        let request: Option<String> = if consume {
+            conn.get_del(&key).await
+        } else {
+            conn.get(&key).await
+        }
+        .map_err(AppError::Redis)?;
+
+        let request = request.ok_or_else(|| {
+            AppError::BadRequest("Authorization request not found or expired".to_string())
+        })?;
+        Ok(serde_json::from_str(&request)?)
+    }
+
+    /// What the consent page shows the signed-in user
+    pub async fn describe_request(
+        &self,
+        user_id: Uuid,
+        request_id: &str,
+    ) -> Result<AuthorizationRequestResponse> {
+        let request = self.load_request(request_id, false).await?;
+        let client = self
+            .clients
+            .find_client(&request.client_id)
+            .await?
+            .ok_or_else(|| AppError::oauth("invalid_request", "Unknown client"))?;
+
+        let granted = self.clients.granted_scopes(user_id, &client.client_id).await?;
+        let consent_required = request.scopes.iter().any(|scope| !granted.contains(scope));
+
+        Ok(AuthorizationRequestResponse {
+            request_id: request_id.to_string(),
+            client_id: client.client_id,
+            client_name: client.name,
+            scopes: request.scopes,
+            consent_required,
+        })
+    }
+
+    /// Record the user's decision and build the redirect back to the client:
+    /// a code when approved, `access_denied` otherwise
+    pub async fn decide(
+        &self,
+        user_id: Uuid,
+        auth_time: i64,
+        request_id: &str,
+        approve: bool,
+    ) -> Result<String> {
+        let request = self.load_request(request_id, true).await?;
+
+        if !approve {
+            return Ok(Self::error_redirect(
+                &request.redirect_uri,
+                "access_denied",
+                "The user denied the request",
+                request.state.as_deref(),
+            ));
+        }
+
+        self.clients
+            .record_consent(user_id, &request.client_id, &request.scopes)
+            .await?;
+
+        let code = Self::random_token();
+        let grant = AuthorizationGrant {
+            client_id: request.client_id,
+            user_id,
+            redirect_uri: request.redirect_uri,
+            redirect_uri_sent: request.redirect_uri_sent,
+            scopes: request.scopes,
+            code_challenge: request.code_challenge,
+            nonce: request.nonce,
+            auth_time,
+        };
+
+        let mut conn = self.redis.clone();
+        let _: () = conn
+            .set_ex(
+                format!("oauth_code:{}", TokenService::hash_token(&code)),
+                serde_json::to_string(&grant)?,
+                AUTHORIZATION_CODE_EXPIRY,
+            )
+            .await
+            .map_err(AppError::Redis)?;
+
+        let mut url = reqwest::Url::parse(&grant.redirect_uri)
+            .map_err(|_| AppError::InternalServerError("Invalid redirect URI".to_string()))?;
+        url.query_pairs_mut().append_pair("code", &code);
+        if let Some(state) = &request.state {
+            url.query_pairs_mut().append_pair("state", state);
+        }
+        url.query_pairs_mut()
+            .append_pair("iss", &self.config.jwt_issuer);
+
+        Ok(url.into())
+    }
+
+    /// Redeem an authorization code at the token endpoint. Codes are single use.
+    pub async fn redeem_code(
+        &self,
+        client: &OAuthClient,
+        code: &str,
+        redirect_uri: Option<&str>,
+        code_verifier: Option<&str>,
+    ) -> Result<AuthorizationGrant> {
+        let invalid_grant = |description: &str| AppError::oauth("invalid_grant", description);
+
+        let mut conn = self.redis.clone();
+        let grant: Option<String> = conn
+            .get_del(format!("oauth_code:{}", TokenService::hash_token(code)))
+            .await
+            .map_err(AppError::Redis)?;
+        let grant: AuthorizationGrant = serde_json::from_str(
+            &grant.ok_or_else(|| invalid_grant("Invalid or expired authorization code"))?,
+        )?;
+
+        if grant.client_id != client.client_id {
+            return Err(invalid_grant("Code was issued to another client"));
+        }
+        // RFC 6749 section 4.1.3: required, and identical, when the authorization
+        // request carried one
+        match redirect_uri {
+            Some(uri) if uri != grant.redirect_uri => {
+                return Err(invalid_grant("redirect_uri does not match"));
+            }
+            None if grant.redirect_uri_sent => {
+                return Err(AppError::oauth("invalid_request", "Missing redirect_uri"));
+            }
+            _ => {}
+        }
+
+        let code_verifier = code_verifier
+            .ok_or_else(|| AppError::oauth("invalid_request", "Missing code_verifier"))?;
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        if challenge != grant.code_challenge {
+            return Err(invalid_grant("PKCE verification failed"));
+        }
+
+        Ok(grant)
+    }
+
+    pub async fn find_client(&self, client_id: &str) -> Result<Option<OAuthClient>> {
+        self.clients.find_client(client_id).await
+    }
+
+    pub async fn authenticate_client(
+        &self,
+        client_id: &str,
+        client_secret: Option<&str>,
+    ) -> Result<OAuthClient> {
+        self.clients.authenticate_client(client_id, client_secret).await
+    }
+
+    /// Start the device flow: a device code the client polls with and a short
+    /// user code the user types in on another device
+    pub async fn create_device_authorization(
+        &self,
+        client: &OAuthClient,
+        scope: Option<&str>,
+    ) -> Result<DeviceCodes> {
+        if !client.allows_grant(DEVICE_CODE_GRANT) {
+            return Err(AppError::oauth(
+                "unauthorized_client",
+                "This client may not use the device flow",
+            ));
+        }
+
+        let authorization = DeviceAuthorization {
+            client_id: client.client_id.clone(),
+            scopes: Self::resolve_scopes(client, scope)?,
+            interval: DEVICE_POLL_INTERVAL,
+            decision: DeviceDecision::Pending,
+        };
+
+        let device_code = Self::random_token();
+        let device_key = format!("oauth_device:{}", TokenService::hash_token(&device_code));
+        let mut conn = self.redis.clone();
+
+        // Retry on the rare clash with a user code already in use
+        let user_code = loop {
+            let user_code = Self::random_user_code();
+            let claimed: bool = redis::cmd("SET")
+                .arg(format!("oauth_device_user:{}", user_code))
+                .arg(&device_key)
+                .arg("NX")
+                .arg("EX")
+                .arg(DEVICE_CODE_EXPIRY)
+                .query_async::<Option<String>>(&mut conn)
+                .await
+                .map_err(AppError::Redis)?
+                .is_some();
+            if claimed {
+                break user_code;
+            }
+        };
+
+        let _: () = conn
+            .set_ex(&device_key, serde_json::to_string(&authorization)?, DEVICE_CODE_EXPIRY)
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(DeviceCodes {
+            device_code,
+            user_code: format!("{}-{}", &user_code[..4], &user_code[4..]),
+            expires_in: DEVICE_CODE_EXPIRY,
+            interval: DEVICE_POLL_INTERVAL,
+        })
+    }
+
+    /// Eight letters drawn uniformly from the alphabet
+    fn random_user_code() -> String {
+        let mut rng = rand::thread_rng();
+        (0..8)
+            .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+            .collect()
+    }
+
+    /// The device authorization a user code stands for, with its Redis key.
+    /// Users may type the code in lowercase, without the dash or with spaces.
+    async fn load_device_by_user_code(
+        &self,
+        user_code: &str,
+    ) -> Result<(String, DeviceAuthorization)> {
+        let user_code: String = user_code
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        let not_found = || AppError::BadRequest("Invalid or expired code".to_string());
+
+        let mut conn = self.redis.clone();
+        let device_key: Option<String> = conn
+            .get(format!("oauth_device_user:{}", user_code))
+            .await
+            .map_err(AppError::Redis)?;
+        let device_key = device_key.ok_or_else(not_found)?;
+
+        let authorization: Option<String> =
+            conn.get(&device_key).await.map_err(AppError::Redis)?;
+        let authorization: DeviceAuthorization =
+            serde_json::from_str(&authorization.ok_or_else(not_found)?)?;
+
+        Ok((device_key, authorization))
+    }
+
+    /// What the device approval page shows the signed-in user
+    pub async fn describe_device_request(
+        &self,
+        user_code: &str,
+    ) -> Result<DeviceRequestResponse> {
+        let (_, authorization) = self.load_device_by_user_code(user_code).await?;
+        if !matches!(authorization.decision, DeviceDecision::Pending) {
+            return Err(AppError::BadRequest("Invalid or expired code".to_string()));
+        }
+
+        let client = self
+            .clients
+            .find_client(&authorization.client_id)
+            .await?
+            .ok_or_else(|| AppError::oauth("invalid_request", "Unknown client"))?;
+
+        Ok(DeviceRequestResponse {
+            client_id: client.client_id,
+            client_name: client.name,
+            scopes: authorization.scopes,
+        })
+    }
+
+    /// Approve or deny a device for the signed-in user. A user code can be used once.
+    pub async fn decide_device_request(
+        &self,
+        user_id: Uuid,
+        user_code: &str,
+        approve: bool,
+    ) -> Result<()> {
+        let (device_key, mut authorization) = self.load_device_by_user_code(user_code).await?;
+        if !matches!(authorization.decision, DeviceDecision::Pending) {
+            return Err(AppError::BadRequest("Invalid or expired code".to_string()));
+        }
+
+        if approve {
+            self.clients
+                .record_consent(user_id, &authorization.client_id, &authorization.scopes)
+                .await?;
+            authorization.decision = DeviceDecision::Approved { user_id };
+        } else {
+            authorization.decision = DeviceDecision::Denied;
+        }
+
+        // KEEPTTL: the device code still expires when it would have
+        let mut conn = self.redis.clone();
+        let _: () = redis::cmd("SET")
+            .arg(&device_key)
+            .arg(serde_json::to_string(&authorization)?)
+            .arg("KEEPTTL")
+            .query_async(&mut conn)
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(())
+    }
+
+    /// A device polling the token endpoint. Errors tell it to keep waiting
+    /// (`authorization_pending`), to back off (`slow_down`) or to give up.
+    pub async fn poll_device_code(
+        &self,
+        client: &OAuthClient,
+        device_code: &str,
+    ) -> Result<DeviceGrant> {
+        let device_key = format!("oauth_device:{}", TokenService::hash_token(device_code));
+        let mut conn = self.redis.clone();
+
+        let authorization: Option<String> =
+            conn.get(&device_key).await.map_err(AppError::Redis)?;
+        let mut authorization: DeviceAuthorization = serde_json::from_str(
+            &authorization
+                .ok_or_else(|| AppError::oauth("expired_token", "The device code has expired"))?,
+        )?;
+        if authorization.client_id != client.client_id {
+            return Err(AppError::oauth(
+                "invalid_grant",
+                "Device code was issued to another client",
+            ));
+        }
+
+        match authorization.decision {
+            DeviceDecision::Pending => {
+                // One poll per interval, with a second of slack for timing jitter;
+                // polling faster adds 5 seconds (RFC 8628 section 3.5)
+                let on_time: bool = redis::cmd("SET")
+                    .arg(format!("{}:poll", device_key))
+                    .arg("1")
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(authorization.interval.saturating_sub(1).max(1))
+                    .query_async::<Option<String>>(&mut conn)
+                    .await
+                    .map_err(AppError::Redis)?
+                    .is_some();
+                if on_time {
+                    return Err(AppError::oauth(
+                        "authorization_pending",
+                        "The user has not approved the device yet",
+                    ));
+                }
+
+                authorization.interval += DEVICE_POLL_INTERVAL;
+                let _: () = redis::cmd("SET")
+                    .arg(&device_key)
+                    .arg(serde_json::to_string(&authorization)?)
+                    .arg("KEEPTTL")
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(AppError::Redis)?;
+
+                Err(AppError::oauth(
+                    "slow_down",
+                    format!("Poll at most every {} seconds", authorization.interval),
+                ))
+            }
+            DeviceDecision::Denied => {
+                let _: () = conn.del(&device_key).await.map_err(AppError::Redis)?;
+                Err(AppError::oauth("access_denied", "The user denied the device"))
+            }
+            DeviceDecision::Approved { user_id } => {
+                // Single use: only the poll that deletes the record gets tokens
+                let deleted: u64 = conn.del(&device_key).await.map_err(AppError::Redis)?;
+                if deleted == 0 {
+                    return Err(AppError::oauth("expired_token", "The device code has expired"));
+                }
+
+                Ok(DeviceGrant {
+                    user_id,
+                    scopes: authorization.scopes,
+                })
+            }
+        }
+    }
+
+    /// The client's redirect URI carrying an OAuth error
+    pub fn error_redirect(
+        redirect_uri: &str,
+        error: &str,
+        description: &str,
+        state: Option<&str>,
+    ) -> String {
+        let Ok(mut url) = reqwest::Url::parse(redirect_uri) else {
+            return redirect_uri.to_string();
+        };
+
+        url.query_pairs_mut()
+            .append_pair("error", error)
+            .append_pair("error_description", description);
+        if let Some(state) = state {
+            url.query_pairs_mut().append_pair("state", state);
+        }
+
+        url.into()
+    }
+}
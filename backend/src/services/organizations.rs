@@ -0,0 +1,194 @@
+use crate::{
+    error::{AppError, Result},
+    models::{OrgRole, OrganizationResponse},
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Organizations (tenants) and the users who belong to them
+#[derive(Clone)]
+pub struct OrganizationService {
+    db: PgPool,
+}
+
+impl OrganizationService {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Create an organization with the user as its owner
+    pub async fn create(&self, user_id: Uuid, name: &str, slug: &str) -> Result<OrganizationResponse> {
+        let valid_slug = slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && !slug.starts_with('-')
+            && !slug.ends_with('-');
+        if !valid_slug {
+            return Err(AppError::Validation(
+                "Slug may only contain lowercase letters, digits and inner hyphens".to_string(),
+            ));
+        }
+
+        let mut tx = self.db.begin().await?;
+
+        let organization = sqlx::query!(
+            r#"
+            INSERT INTO organizations (name, slug)
+            VALUES ($1, $2)
+            RETURNING id, name, slug, created_at
+            "#,
+            name,
+            slug
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            if let sqlx::Error::Database(db_err) = &e {
+                if db_err.is_unique_violation() {
+                    return AppError::OrganizationSlugTaken;
+                }
+            }
+            AppError::Database(e)
+        })?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO organization_members (organization_id, user_id, role)
+            VALUES ($1, $2, $3)
+            "#,
+            organization.id,
+            user_id,
+            OrgRole::Owner.as_str()
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        tracing::info!("User {} created organization {}", user_id, organization.id);
+
+        Ok(OrganizationResponse {
+            id: organization.id,
+            name: organization.name,
+            slug: organization.slug,
+            role: OrgRole::Owner,
+            created_at: organization.created_at,
+        })
+    }
+
+    /// The organizations the user belongs to, in the order they joined
+    pub async fn list_for_user(&self, user_id: Uuid) -> Result<Vec<OrganizationResponse>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT organizations.id, organizations.name, organizations.slug,
+                   organization_members.role, organizations.created_at
+            FROM organization_members
+            JOIN organizations ON organizations.id = organization_members.organization_id
+            WHERE organization_members.user_id = $1
+            ORDER BY organization_members.joined_at, organizations.id
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(OrganizationResponse {
+                    id: row.id,
+                    name: row.name,
+                    slug: row.slug,
+                    role: parse_role(&row.role)?,
+                    created_at: row.created_at,
+                })
+            })
+            .collect()
+    }
+
+    /// The organization, if the user is a member of it
+    pub async fn find_for_member(
+        &self,
+        organization_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<OrganizationResponse>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT organizations.id, organizations.name, organizations.slug,
+                   organization_members.role, organizations.created_at
+            FROM organization_members
+            JOIN organizations ON organizations.id = organization_members.organization_id
+            WHERE organization_members.organization_id = $1 AND organization_members.user_id = $2
+            "#,
+            organization_id,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        row.map(|row| {
+            Ok(OrganizationResponse {
+                id: row.id,
+                name: row.name,
+                slug: row.slug,
+                role: parse_role(&row.role)?,
+                created_at: row.created_at,
+            })
+        })
+        .transpose()
+    }
+
+    /// The user's role in the organization, or `None` if they don't belong to it
+    pub async fn member_role(&self, organization_id: Uuid, user_id: Uuid) -> Result<Option<OrgRole>> {
+        let role = sqlx::query_scalar!(
+            r#"
+            SELECT role
+            FROM organization_members
+            WHERE organization_id = $1 AND user_id = $2
+            "#,
+            organization_id,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        role.as_deref().map(parse_role).transpose()
+    }
+
+    pub async fn rename(&self, organization_id: Uuid, name: &str) -> Result<()> {
+        let result = sqlx::query!(
+            "UPDATE organizations SET name = $1 WHERE id = $2",
+            name,
+            organization_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::OrganizationNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Where a new session starts: the first organization the user joined
+    pub async fn default_organization(&self, user_id: Uuid) -> Result<Option<Uuid>> {
+        let organization_id = sqlx::query_scalar!(
+            r#"
+            SELECT organization_id
+            FROM organization_members
+            WHERE user_id = $1
+            ORDER BY joined_at, organization_id
+            LIMIT 1
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(organization_id)
+    }
+}
+
+fn parse_role(role: &str) -> Result<OrgRole> {
+    role.parse().map_err(AppError::InternalServerError)
+}
@@ -4,9 +4,21 @@ use argon2::{
     Argon2,
 };
 
+/// Stored for accounts created through a sign-in provider; no password matches it
+const UNUSABLE_PASSWORD_HASH: &str = "!";
+
 pub struct PasswordService;
 
 impl PasswordService {
+    pub fn unusable_password_hash() -> String {
+        UNUSABLE_PASSWORD_HASH.to_string()
+    }
+
+    /// Whether the account has no password it can sign in with
+    pub fn is_unusable(hash: &str) -> bool {
+        hash == UNUSABLE_PASSWORD_HASH
+    }
+
     pub fn hash_password(password: &str) -> Result<String> {
         let salt = SaltString::generate(&mut OsRng);
         let argon2 = Argon2::default();
@@ -18,6 +30,10 @@ impl PasswordService {
     }
 
     pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
+        if Self::is_unusable(hash) {
+            return Ok(false);
+        }
+
         let parsed_hash = PasswordHash::new(hash).map_err(|_| AppError::PasswordHashError)?;
         let argon2 = Argon2::default();
 
@@ -0,0 +1,148 @@
+use crate::{
+    error::{AppError, Result},
+    models::PersonalTokenResponse,
+    services::token::TokenService,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use ipnetwork::IpNetwork;
+use rand::RngCore;
+use sqlx::PgPool;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Personal access tokens start with this, so `auth_middleware` can tell them from JWTs
+pub const TOKEN_PREFIX: &str = "pat_";
+
+/// Scopes a personal access token can have: `read` allows GET requests, `write` the rest
+pub const PERSONAL_TOKEN_SCOPES: &[&str] = &["read", "write"];
+
+/// How much of a token is kept in the clear to tell tokens apart
+const SHOWN_PREFIX_LEN: usize = 12;
+
+/// A personal access token that authenticated a request
+pub struct AuthenticatedToken {
+    pub user_id: Uuid,
+    pub scopes: Vec<String>,
+}
+
+/// Long-lived API keys users create for their scripts
+#[derive(Clone)]
+pub struct PersonalTokenService {
+    db: PgPool,
+}
+
+impl PersonalTokenService {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Create a token for the user. The token itself is returned only here.
+    pub async fn create(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        scopes: &[String],
+        expires_in_days: Option<i64>,
+    ) -> Result<(String, PersonalTokenResponse)> {
+        if let Some(scope) = scopes
+            .iter()
+            .find(|scope| !PERSONAL_TOKEN_SCOPES.contains(&scope.as_str()))
+        {
+            return Err(AppError::Validation(format!("Unknown scope: {}", scope)));
+        }
+
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = format!("{}{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+        let expires_at = expires_in_days.map(|days| Utc::now() + Duration::days(days));
+
+        let created = sqlx::query_as!(
+            PersonalTokenResponse,
+            r#"
+            INSERT INTO personal_access_tokens
+                (user_id, name, token_prefix, token_hash, scopes, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, token_prefix, scopes, expires_at, created_at, last_used_at,
+                      host(last_used_ip) AS last_used_ip
+            "#,
+            user_id,
+            name,
+            &token[..SHOWN_PREFIX_LEN],
+            TokenService::hash_token(&token),
+            scopes,
+            expires_at
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        tracing::info!("Created personal access token {} for user {}", created.id, user_id);
+
+        Ok((token, created))
+    }
+
+    /// List the user's tokens
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<PersonalTokenResponse>> {
+        let tokens = sqlx::query_as!(
+            PersonalTokenResponse,
+            r#"
+            SELECT id, name, token_prefix, scopes, expires_at, created_at, last_used_at,
+                   host(last_used_ip) AS last_used_ip
+            FROM personal_access_tokens
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    /// Revoke one of the user's tokens - it stops working right away
+    pub async fn revoke(&self, user_id: Uuid, token_id: Uuid) -> Result<()> {
+        let result = sqlx::query!(
+            "DELETE FROM personal_access_tokens WHERE id = $1 AND user_id = $2",
+            token_id,
+            user_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::PersonalTokenNotFound);
+        }
+
+        tracing::info!("Revoked personal access token {} of user {}", token_id, user_id);
+
+        Ok(())
+    }
+
+    /// The unexpired token matching `token`, if any - records when and where it was used
+    pub async fn authenticate(
+        &self,
+        token: &str,
+        ip_address: Option<String>,
+    ) -> Result<Option<AuthenticatedToken>> {
+        let ip_network = ip_address
+            .as_ref()
+            .and_then(|ip| IpNetwork::from_str(ip).ok());
+
+        let authenticated = sqlx::query_as!(
+            AuthenticatedToken,
+            r#"
+            UPDATE personal_access_tokens
+            SET last_used_at = NOW(), last_used_ip = $2
+            WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING user_id, scopes
+            "#,
+            TokenService::hash_token(token),
+            ip_network as Option<IpNetwork>
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(authenticated)
+    }
+}
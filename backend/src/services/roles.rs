@@ -0,0 +1,161 @@
+use crate::{
+    error::{AppError, Result},
+    models::RoleResponse,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// The role that administers users and roles - someone active must always hold it
+const ADMIN_ROLE: &str = "admin";
+
+/// A user's roles and everything they grant
+#[derive(Debug, Clone, Default)]
+pub struct UserAccess {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+/// Roles, the permissions they bundle, and who holds them
+#[derive(Clone)]
+pub struct RoleService {
+    db: PgPool,
+}
+
+impl RoleService {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// The user's roles and permissions, for the claims of a new access token
+    pub async fn access_for(&self, user_id: Uuid) -> Result<UserAccess> {
+        let roles = sqlx::query_scalar!(
+            r#"
+            SELECT roles.name
+            FROM user_roles
+            JOIN roles ON roles.id = user_roles.role_id
+            WHERE user_roles.user_id = $1
+            ORDER BY roles.name
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let permissions = sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT role_permissions.permission
+            FROM user_roles
+            JOIN role_permissions ON role_permissions.role_id = user_roles.role_id
+            WHERE user_roles.user_id = $1
+            ORDER BY role_permissions.permission
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(UserAccess { roles, permissions })
+    }
+
+    pub async fn list_roles(&self) -> Result<Vec<RoleResponse>> {
+        let roles = sqlx::query_as!(
+            RoleResponse,
+            r#"
+            SELECT roles.name, roles.description,
+                   ARRAY_REMOVE(ARRAY_AGG(role_permissions.permission ORDER BY role_permissions.permission), NULL)
+                       AS "permissions!"
+            FROM roles
+            LEFT JOIN role_permissions ON role_permissions.role_id = roles.id
+            GROUP BY roles.id
+            ORDER BY roles.name
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(roles)
+    }
+
+    /// Give the user a role. It shows up in their next access token.
+    pub async fn grant_role(&self, user_id: Uuid, role: &str) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO user_roles (user_id, role_id)
+            SELECT $1, id FROM roles WHERE name = $2
+            ON CONFLICT (user_id, role_id) DO NOTHING
+            "#,
+            user_id,
+            role
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            tracing::info!("Granted role {} to user {}", role, user_id);
+        } else if !self.role_exists(role).await? {
+            return Err(AppError::RoleNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Take a role away from the user, unless that leaves no active admin.
+    /// Returns whether they held it - access tokens carry roles, so callers
+    /// then end the user's sessions too.
+    pub async fn revoke_role(&self, user_id: Uuid, role: &str) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+
+        // Locking the role serializes revocations, so two admins can't remove each other at once
+        let role_id = sqlx::query_scalar!("SELECT id FROM roles WHERE name = $1 FOR UPDATE", role)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(AppError::RoleNotFound)?;
+
+        let result = sqlx::query!(
+            "DELETE FROM user_roles WHERE role_id = $1 AND user_id = $2",
+            role_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        if role == ADMIN_ROLE {
+            let admins_left = sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) AS "count!"
+                FROM user_roles
+                JOIN users ON users.id = user_roles.user_id
+                WHERE user_roles.role_id = $1 AND users.is_active
+                "#,
+                role_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            if admins_left == 0 {
+                return Err(AppError::LastAdmin);
+            }
+        }
+
+        tx.commit().await?;
+
+        tracing::info!("Revoked role {} from user {}", role, user_id);
+
+        Ok(true)
+    }
+
+    async fn role_exists(&self, role: &str) -> Result<bool> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM roles WHERE name = $1) AS "exists!""#,
+            role
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(exists)
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{error::Result, models::SecurityEventResponse};
+use ipnetwork::IpNetwork;
+use sqlx::PgPool;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub enum SecurityEventType {
+    RefreshTokenReuse,
+    /// An admin started acting as the user
+    Impersonation,
+}
+
+impl SecurityEventType {
+    fn as_str(&self) -> &str {
+        match self {
+            SecurityEventType::RefreshTokenReuse => "refresh_token_reuse",
+            SecurityEventType::Impersonation => "impersonation",
+        }
+    }
+}
+
+/// Security events shown to the user, newest first
+const SECURITY_HISTORY_LIMIT: i64 = 100;
+
+#[derive(Clone)]
+pub struct SecurityEventService {
+    db: PgPool,
+}
+
+impl SecurityEventService {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Record a security event against a user's account
+    pub async fn record(
+        &self,
+        user_id: Uuid,
+        event_type: SecurityEventType,
+        ip_address: Option<String>,
+        details: serde_json::Value,
+    ) -> Result<()> {
+        let ip_network = ip_address
+            .as_ref()
+            .and_then(|ip| IpNetwork::from_str(ip).ok());
+
+        tracing::warn!(
+            "Security event '{}' for user {}: {}",
+            event_type.as_str(),
+            user_id,
+            details
+        );
+
+        sqlx::query!(
+            r#"
+            INSERT INTO security_events (user_id, event_type, ip_address, details)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            user_id,
+            event_type.as_str(),
+            ip_network as Option<IpNetwork>,
+            details
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The user's recent security events, newest first
+    pub async fn list_for_user(&self, user_id: Uuid) -> Result<Vec<SecurityEventResponse>> {
+        let events = sqlx::query_as!(
+            SecurityEventResponse,
+            r#"
+            SELECT event_type, host(ip_address) AS ip_address, details, created_at
+            FROM security_events
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#,
+            user_id,
+            SECURITY_HISTORY_LIMIT
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(events)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::error::{AppError, Result};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+
+/// How often every instance reloads the key ring from `jwt_signing_keys`
+pub const KEY_REFRESH_INTERVAL_SECS: i64 = 60;
+
+/// A row of `jwt_signing_keys` - the rotation state every instance loads
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SigningKeyRecord {
+    pub kid: String,
+    pub algorithm: String,
+    /// `None` means the shared `JWT_SECRET` (HS256)
+    pub private_key_path: Option<String>,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub activated_at: Option<DateTime<Utc>>,
+    pub deactivated_at: Option<DateTime<Utc>>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone)]
+pub struct SigningKeyStore {
+    db: PgPool,
+}
+
+impl SigningKeyStore {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    /// Keys that can still verify tokens (active plus verification-only)
+    pub async fn load_usable_keys(&self) -> Result<Vec<SigningKeyRecord>> {
+        let keys = sqlx::query_as!(
+            SigningKeyRecord,
+            r#"
+            SELECT kid, algorithm, private_key_path, status, created_at,
+                   activated_at, deactivated_at, retired_at
+            FROM jwt_signing_keys
+            WHERE status IN ('active', 'verify')
+            ORDER BY created_at
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(keys)
+    }
+
+    pub async fn list_keys(&self) -> Result<Vec<SigningKeyRecord>> {
+        let keys = sqlx::query_as!(
+            SigningKeyRecord,
+            r#"
+            SELECT kid, algorithm, private_key_path, status, created_at,
+                   activated_at, deactivated_at, retired_at
+            FROM jwt_signing_keys
+            ORDER BY created_at
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(keys)
+    }
+
+    /// Record the key from the environment as the active one on first boot.
+    /// Does nothing once rotation state exists.
+    pub async fn seed_active_key(
+        &self,
+        kid: &str,
+        algorithm: &str,
+        private_key_path: Option<&str>,
+    ) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO jwt_signing_keys (kid, algorithm, private_key_path, status, activated_at)
+            SELECT $1, $2, $3, 'active', NOW()
+            WHERE NOT EXISTS (SELECT 1 FROM jwt_signing_keys)
+            ON CONFLICT DO NOTHING
+            "#,
+            kid,
+            algorithm,
+            private_key_path
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            tracing::info!("Seeded JWT signing key rotation state with kid {}", kid);
+        }
+
+        Ok(())
+    }
+
+    /// Add a verification-only key. It is published in the JWKS straight away
+    /// so relying parties can cache it before it starts signing.
+    pub async fn add_key(&self, kid: &str, algorithm: &str, private_key_path: &str) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO jwt_signing_keys (kid, algorithm, private_key_path, status)
+            VALUES ($1, $2, $3, 'verify')
+            ON CONFLICT (kid) DO NOTHING
+            "#,
+            kid,
+            algorithm,
+            private_key_path
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::BadRequest(format!(
+                "A signing key with kid '{}' already exists",
+                kid
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Make `kid` the signing key. The previous active key stays around for verification.
+    /// A key must have been added at least one refresh interval ago, so every
+    /// instance can already verify the tokens it signs.
+    pub async fn promote_key(&self, kid: &str) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        let key = sqlx::query!(
+            "SELECT status, created_at FROM jwt_signing_keys WHERE kid = $1 FOR UPDATE",
+            kid
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("Signing key '{}' not found", kid)))?;
+
+        let trusted_at = key.created_at + Duration::seconds(KEY_REFRESH_INTERVAL_SECS);
+        if key.status == "verify" && trusted_at > Utc::now() {
+            return Err(AppError::BadRequest(format!(
+                "Signing key '{}' was added too recently for every instance to trust it. \
+                 Try again in {} seconds.",
+                kid,
+                (trusted_at - Utc::now()).num_seconds() + 1
+            )));
+        }
+
+        match key.status.as_str() {
+            "verify" => {}
+            "active" => {
+                return Err(AppError::BadRequest(format!(
+                    "Signing key '{}' is already active",
+                    kid
+                )))
+            }
+            _ => {
+                return Err(AppError::BadRequest(format!(
+                    "Signing key '{}' has been retired",
+                    kid
+                )))
+            }
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE jwt_signing_keys
+            SET status = 'verify', deactivated_at = NOW()
+            WHERE status = 'active'
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE jwt_signing_keys
+            SET status = 'active', activated_at = NOW(), deactivated_at = NULL
+            WHERE kid = $1
+            "#,
+            kid
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Retire previously active keys whose tokens have all expired, i.e. keys
+    /// that stopped signing more than `max_token_lifetime` seconds ago.
+    /// Keys that were added but never promoted are left alone.
+    pub async fn retire_expired_keys(&self, max_token_lifetime: i64) -> Result<Vec<String>> {
+        let cutoff = Utc::now() - Duration::seconds(max_token_lifetime);
+
+        let retired = sqlx::query_scalar!(
+            r#"
+            UPDATE jwt_signing_keys
+            SET status = 'retired', retired_at = NOW()
+            WHERE status = 'verify'
+              AND deactivated_at IS NOT NULL
+              AND deactivated_at < $1
+            RETURNING kid
+            "#,
+            cutoff
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(retired)
+    }
+}
@@ -3,10 +3,12 @@ use crate::{
     config::Config,
     error::{AppError, Result},
     models::{ActiveSession, RefreshToken},
+    services::security_events::{SecurityEventService, SecurityEventType},
 };
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use ipnetwork::IpNetwork;
 use redis::{aio::ConnectionManager, AsyncCommands};
+use serde_json::json;
 use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 use std::str::FromStr;
@@ -17,24 +19,36 @@ pub struct TokenService {
     db: PgPool,
     redis: ConnectionManager,
     config: Config,
+    security_events: SecurityEventService,
 }
 
 impl TokenService {
-    pub fn new(db: PgPool, redis: ConnectionManager, config: Config) -> Self {
-        Self { db, redis, config }
+    pub fn new(
+        db: PgPool,
+        redis: ConnectionManager,
+        config: Config,
+        security_events: SecurityEventService,
+    ) -> Self {
+        Self {
+            db,
+            redis,
+            config,
+            security_events,
+        }
     }
 
     /// Hash a refresh token for storage
-    fn hash_token(token: &str) -> String {
+    pub(crate) fn hash_token(token: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.update(token.as_bytes());
         format!("{:x}", hasher.finalize())
     }
 
-    /// Store refresh token in database
+    /// Store refresh token in database. A fresh login starts a new token family.
     pub async fn store_refresh_token(
         &self,
         token_id: Uuid,
+        family_id: Uuid,
         user_id: Uuid,
         token: &str,
         device_info: Option<String>,
@@ -50,10 +64,11 @@ impl TokenService {
 
         sqlx::query!(
             r#"
-            INSERT INTO refresh_tokens (id, user_id, token_hash, expires_at, device_info, ip_address)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            INSERT INTO refresh_tokens (id, family_id, user_id, token_hash, expires_at, device_info, ip_address)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             "#,
             token_id,
+            family_id,
             user_id,
             token_hash,
             expires_at,
@@ -66,37 +81,106 @@ impl TokenService {
         Ok(())
     }
 
-    /// Verify and retrieve refresh token from database
-    pub async fn verify_refresh_token(&self, token: &str) -> Result<RefreshToken> {
+    /// Store a refresh token issued to an OAuth client for the scope the user granted it
+    pub async fn store_client_refresh_token(
+        &self,
+        token_id: Uuid,
+        family_id: Uuid,
+        user_id: Uuid,
+        client_id: &str,
+        scope: &str,
+        token: &str,
+    ) -> Result<()> {
         let token_hash = Self::hash_token(token);
+        let expires_at = Utc::now() + Duration::seconds(self.config.refresh_token_expiry);
 
-        // Update last_used timestamp
         sqlx::query!(
             r#"
-            UPDATE refresh_tokens
-            SET last_used = $1
-            WHERE token_hash = $2
+            INSERT INTO refresh_tokens (id, family_id, user_id, token_hash, expires_at, client_id, scope)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             "#,
-            Utc::now(),
-            token_hash
+            token_id,
+            family_id,
+            user_id,
+            token_hash,
+            expires_at,
+            client_id,
+            scope
         )
         .execute(&self.db)
         .await?;
 
+        Ok(())
+    }
+
+    /// Look up a refresh token as stored, whatever its state - no side effects
+    pub async fn find_refresh_token(&self, token: &str) -> Result<Option<RefreshToken>> {
         let refresh_token = sqlx::query_as!(
             RefreshToken,
             r#"
-            SELECT id, user_id, token_hash, expires_at, created_at, 
+            SELECT id, family_id, user_id, token_hash, expires_at, created_at, 
                    revoked_at, replaced_by_token, device_info, 
-                   ip_address as "ip_address: _"
+                   ip_address as "ip_address: _", client_id, scope, organization_id
             FROM refresh_tokens
             WHERE token_hash = $1
             "#,
-            token_hash
+            Self::hash_token(token)
         )
         .fetch_optional(&self.db)
-        .await?
-        .ok_or(AppError::InvalidToken)?;
+        .await?;
+
+        Ok(refresh_token)
+    }
+
+    /// Whether the session behind an access token's `jti` was revoked (rather
+    /// than rotated, which leaves the access token usable until it expires)
+    pub async fn is_session_revoked(&self, token_id: Uuid) -> Result<bool> {
+        let revoked = sqlx::query_scalar!(
+            r#"
+            SELECT revoked_at IS NOT NULL AND replaced_by_token IS NULL AS "revoked!"
+            FROM refresh_tokens
+            WHERE id = $1
+            "#,
+            token_id
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(revoked.unwrap_or(false))
+    }
+
+    /// Verify and retrieve refresh token from database
+    pub async fn verify_refresh_token(&self, token: &str) -> Result<RefreshToken> {
+        let token_hash = Self::hash_token(token);
+
+        // Update last_used timestamp
+        sqlx::query!(
+            r#"
+            UPDATE refresh_tokens
+            SET last_used = $1
+            WHERE token_hash = $2
+            "#,
+            Utc::now(),
+            token_hash
+        )
+        .execute(&self.db)
+        .await?;
+
+        let refresh_token = self
+            .find_refresh_token(token)
+            .await?
+            .ok_or(AppError::InvalidToken)?;
+
+        // A rotated token coming back means it was copied - treat as theft
+        if refresh_token.replaced_by_token.is_some() {
+            self.handle_refresh_token_reuse(
+                refresh_token.user_id,
+                refresh_token.family_id,
+                refresh_token.id,
+            )
+            .await?;
+            return Err(AppError::TokenRevoked);
+        }
 
         // Check if token is revoked
         if refresh_token.revoked_at.is_some() {
@@ -111,7 +195,7 @@ impl TokenService {
         Ok(refresh_token)
     }
 
-    /// Rotate refresh token (revoke old, create new)
+    /// Rotate refresh token (revoke old, create new in the same family)
     pub async fn rotate_refresh_token(
         &self,
         old_token: &str,
@@ -133,9 +217,9 @@ impl TokenService {
         let old_refresh_token = sqlx::query_as!(
             RefreshToken,
             r#"
-            SELECT id, user_id, token_hash, expires_at, created_at, 
+            SELECT id, family_id, user_id, token_hash, expires_at, created_at, 
                    revoked_at, replaced_by_token, device_info, 
-                   ip_address as "ip_address: _"
+                   ip_address as "ip_address: _", client_id, scope, organization_id
             FROM refresh_tokens
             WHERE token_hash = $1
             "#,
@@ -148,12 +232,12 @@ impl TokenService {
         // Start transaction
         let mut tx = self.db.begin().await?;
 
-        // Revoke old token
-        sqlx::query!(
+        // Revoke old token - only if nobody else rotated it first
+        let revoked = sqlx::query!(
             r#"
             UPDATE refresh_tokens
             SET revoked_at = $1, replaced_by_token = $2
-            WHERE token_hash = $3
+            WHERE token_hash = $3 AND revoked_at IS NULL
             "#,
             Utc::now(),
             new_token_id,
@@ -162,18 +246,48 @@ impl TokenService {
         .execute(&mut *tx)
         .await?;
 
+        if revoked.rows_affected() == 0 {
+            tx.rollback().await?;
+
+            // If another request rotated this token first, it was presented twice
+            let current = sqlx::query!(
+                r#"
+                SELECT replaced_by_token
+                FROM refresh_tokens
+                WHERE token_hash = $1
+                "#,
+                old_token_hash
+            )
+            .fetch_optional(&self.db)
+            .await?;
+
+            if current.and_then(|row| row.replaced_by_token).is_some() {
+                self.handle_refresh_token_reuse(
+                    old_refresh_token.user_id,
+                    old_refresh_token.family_id,
+                    old_refresh_token.id,
+                )
+                .await?;
+            }
+            return Err(AppError::TokenRevoked);
+        }
+
         // Create new token
         sqlx::query!(
             r#"
-            INSERT INTO refresh_tokens (id, user_id, token_hash, expires_at, device_info, ip_address)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            INSERT INTO refresh_tokens (id, family_id, user_id, token_hash, expires_at, device_info, ip_address, client_id, scope, organization_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
             "#,
             new_token_id,
+            old_refresh_token.family_id,
             old_refresh_token.user_id,
             new_token_hash,
             expires_at,
             device_info,
-            ip_network as Option<IpNetwork>
+            ip_network as Option<IpNetwork>,
+            old_refresh_token.client_id,
+            old_refresh_token.scope,
+            old_refresh_token.organization_id
         )
         .execute(&mut *tx)
         .await?;
@@ -183,11 +297,113 @@ impl TokenService {
         Ok(())
     }
 
+    /// Revoke every refresh token in a family and blacklist the access tokens
+    /// issued alongside them. Returns the number of refresh tokens revoked.
+    pub async fn revoke_token_family(&self, family_id: Uuid) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = $1
+            WHERE family_id = $2 AND revoked_at IS NULL
+            "#,
+            Utc::now(),
+            family_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        // Access tokens carry their refresh token's id as `jti`
+        let token_ids = sqlx::query!(
+            r#"
+            SELECT id
+            FROM refresh_tokens
+            WHERE family_id = $1 AND created_at > $2
+            "#,
+            family_id,
+            Utc::now() - Duration::seconds(self.config.access_token_expiry)
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        for row in token_ids {
+            self.blacklist_token_id(row.id, self.config.access_token_expiry)
+                .await?;
+        }
+
+        Ok(result.rows_affected())
+    }
+
+    /// Respond to a replayed refresh token: kill the whole family and record it
+    async fn handle_refresh_token_reuse(
+        &self,
+        user_id: Uuid,
+        family_id: Uuid,
+        token_id: Uuid,
+    ) -> Result<()> {
+        let sessions_revoked = self.revoke_token_family(family_id).await?;
+
+        self.security_events
+            .record(
+                user_id,
+                SecurityEventType::RefreshTokenReuse,
+                None,
+                json!({
+                    "family_id": family_id,
+                    "token_id": token_id,
+                    "sessions_revoked": sessions_revoked,
+                }),
+            )
+            .await
+    }
+
+    /// Move the session whose current refresh token is `token_id` to another
+    /// organization. Tokens rotated from it stay there.
+    pub async fn set_session_organization(
+        &self,
+        token_id: Uuid,
+        organization_id: Option<Uuid>,
+    ) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE refresh_tokens
+            SET organization_id = $2
+            WHERE id = $1 AND revoked_at IS NULL AND client_id IS NULL
+            "#,
+            token_id,
+            organization_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::SessionNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// When the user signed in to start the session a token belongs to: the
+    /// oldest token left in its rotation family
+    pub async fn session_started_at(&self, token_id: Uuid) -> Result<Option<DateTime<Utc>>> {
+        let started_at = sqlx::query_scalar!(
+            r#"
+            SELECT MIN(created_at)
+            FROM refresh_tokens
+            WHERE family_id = (SELECT family_id FROM refresh_tokens WHERE id = $1)
+            "#,
+            token_id
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(started_at)
+    }
+
     /// Get all active sessions for a user
     pub async fn get_active_sessions(
         &self,
         user_id: Uuid,
-        current_token_id: Uuid,
+        current_token_id: Option<Uuid>,
     ) -> Result<Vec<ActiveSession>> {
         let sessions = sqlx::query!(
             r#"
@@ -215,7 +431,7 @@ impl TokenService {
             ip_address: row.ip_address.map(|ip| ip.to_string()),
             created_at: row.created_at,
             last_used: row.last_used,
-            is_current: row.id == current_token_id,
+            is_current: Some(row.id) == current_token_id,
         })
         .collect();
 
@@ -239,6 +455,47 @@ impl TokenService {
         Ok(result.rows_affected())
     }
 
+    /// End one of the user's sessions, along with its access tokens
+    pub async fn revoke_session(&self, user_id: Uuid, token_id: Uuid) -> Result<()> {
+        let family_id = sqlx::query_scalar!(
+            r#"
+            SELECT family_id
+            FROM refresh_tokens
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+            token_id,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(AppError::SessionNotFound)?;
+
+        self.revoke_token_family(family_id).await?;
+
+        Ok(())
+    }
+
+    /// End all of the user's sessions, along with their access tokens. Returns
+    /// the number of sessions ended.
+    pub async fn revoke_all_sessions(&self, user_id: Uuid) -> Result<u64> {
+        let family_ids = sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT family_id
+            FROM refresh_tokens
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        for family_id in &family_ids {
+            self.revoke_token_family(*family_id).await?;
+        }
+
+        Ok(family_ids.len() as u64)
+    }
+
     /// Revoke specific refresh token
     pub async fn revoke_token(&self, token: &str) -> Result<()> {
         let token_hash = Self::hash_token(token);
@@ -286,6 +543,28 @@ impl TokenService {
         Ok(())
     }
 
+    /// Blacklist every access token issued with a given `jti` (for family revocation)
+    pub async fn blacklist_token_id(&self, token_id: Uuid, expiry_secs: i64) -> Result<()> {
+        let mut conn = self.redis.clone();
+        let key = format!("blacklist:jti:{}", token_id);
+
+        let _: () = conn.set_ex(&key, "1", expiry_secs as u64)
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(())
+    }
+
+    /// Check if access tokens with this `jti` have been blacklisted
+    pub async fn is_token_id_blacklisted(&self, token_id: &str) -> Result<bool> {
+        let mut conn = self.redis.clone();
+        let key = format!("blacklist:jti:{}", token_id);
+
+        let exists: bool = conn.exists(&key).await.map_err(AppError::Redis)?;
+
+        Ok(exists)
+    }
+
     /// Check if access token is blacklisted
     pub async fn is_token_blacklisted(&self, token: &str) -> Result<bool> {
         let mut conn = self.redis.clone();
@@ -125,4 +125,81 @@ impl UserService {
 
         Ok(())
     }
+
+    /// A page of users, newest first, with the total matching. `search`
+    /// matches any part of the email address.
+    pub async fn list_users(
+        &self,
+        search: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<User>, i64)> {
+        // Match the search term literally, not as a LIKE pattern
+        let pattern = search.map(|term| {
+            let escaped = term
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let users = sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, email, password_hash, created_at, updated_at, is_active, email_verified
+            FROM users
+            WHERE $1::TEXT IS NULL OR email ILIKE $1
+            ORDER BY created_at DESC, id
+            LIMIT $2 OFFSET $3
+            "#,
+            pattern,
+            limit,
+            offset
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM users WHERE $1::TEXT IS NULL OR email ILIKE $1"#,
+            pattern
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok((users, total))
+    }
+
+    /// Deactivate or reactivate a user. Inactive users can't sign in or use their tokens.
+    pub async fn set_active(&self, user_id: Uuid, is_active: bool) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE users
+            SET is_active = $1, updated_at = NOW()
+            WHERE id = $2
+            "#,
+            is_active,
+            user_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::UserNotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Delete a user along with everything that belongs to them
+    pub async fn delete_user(&self, user_id: Uuid) -> Result<()> {
+        let result = sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&self.db)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::UserNotFound);
+        }
+
+        Ok(())
+    }
 }
@@ -3,25 +3,42 @@ use crate::{
     error::{AppError, Result},
 };
 use chrono::{Duration, Utc};
-use rand::Rng;
-use sqlx::PgPool;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub enum CodeType {
     EmailVerification,
     PasswordReset,
-    }
+    MagicLink,
+    AccountUnlock,
+}
 
 impl CodeType {
     fn as_str(&self) -> &str {
         match self {
             CodeType::EmailVerification => "email_verification",
             CodeType::PasswordReset => "password_reset",
+            CodeType::MagicLink => "magic_link",
+            CodeType::AccountUnlock => "account_unlock",
         }
     }
 }
 
+/// HMAC-SHA256 of a code, keyed with `JWT_SECRET` and hex encoded. A 6-digit
+/// code has only a million values, so a plain hash of it is easy to reverse.
+fn code_hash(secret: &[u8], code: &str) -> String {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(b"verification_code:");
+    mac.update(code.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
 #[derive(Clone)]
 pub struct VerificationService {
     db: PgPool,
@@ -33,20 +50,41 @@ impl VerificationService {
         Self { db, config }
     }
 
-    /// Generate a 6-digit verification code
-    fn generate_code() -> String {
+    /// Generate a 6-digit verification code, or a long random token for
+    /// magic links (which are clicked, not typed)
+    fn generate_code(code_type: &CodeType) -> String {
         let mut rng = rand::thread_rng();
-        format!("{:06}", rng.gen_range(0..1000000))
+        match code_type {
+            CodeType::MagicLink => {
+                let mut bytes = [0u8; 32];
+                rng.fill_bytes(&mut bytes);
+                URL_SAFE_NO_PAD.encode(bytes)
+            }
+            _ => format!("{:06}", rng.gen_range(0..1000000)),
+        }
     }
 
-    /// Create and store a verification code
+    fn expiry_seconds(&self, code_type: &CodeType) -> i64 {
+        match code_type {
+            CodeType::MagicLink => self.config.magic_link_expiry,
+            _ => self.config.verification_code_expiry,
+        }
+    }
+
+    fn hash_code(&self, code: &str) -> String {
+        code_hash(self.config.jwt_secret.as_bytes(), code)
+    }
+
+    /// Create and store a verification code. Only its keyed hash is stored;
+    /// the plaintext is returned for sending to the user.
     pub async fn create_verification_code(
         &self,
         user_id: Uuid,
         code_type: CodeType,
     ) -> Result<String> {
-        let code = Self::generate_code();
-        let expires_at = Utc::now() + Duration::seconds(self.config.verification_code_expiry);
+        let code = Self::generate_code(&code_type);
+        let code_hash = self.hash_code(&code);
+        let expires_at = Utc::now() + Duration::seconds(self.expiry_seconds(&code_type));
 
         sqlx::query!(
             r#"
@@ -54,7 +92,7 @@ impl VerificationService {
             VALUES ($1, $2, $3, $4)
             "#,
             user_id,
-            code,
+            code_hash,
             code_type.as_str(),
             expires_at
         )
@@ -64,13 +102,45 @@ impl VerificationService {
         Ok(code)
     }
 
-    /// Verify a code
+    /// Verify a code. Wrong guesses are counted per (user, code type); too many
+    /// of them invalidate every outstanding code of that type.
     pub async fn verify_code(
         &self,
         user_id: Uuid,
         code: &str,
         code_type: CodeType,
     ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        // Lock the attempt counter first, so parallel guesses queue up behind
+        // each other instead of all reading the same count. The no-op update
+        // takes the row lock, and is retried if the row is deleted meanwhile.
+        let attempts = sqlx::query!(
+            r#"
+            INSERT INTO verification_attempts (user_id, code_type, failed_attempts, last_failed_at)
+            VALUES ($1, $2, 0, NOW())
+            ON CONFLICT (user_id, code_type) DO UPDATE
+            SET failed_attempts = verification_attempts.failed_attempts
+            RETURNING failed_attempts, last_failed_at
+            "#,
+            user_id,
+            code_type.as_str()
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Misses older than the code lifetime are forgotten
+        let window = Utc::now() - Duration::seconds(self.expiry_seconds(&code_type));
+        let failed_attempts = if attempts.last_failed_at < window {
+            0
+        } else {
+            attempts.failed_attempts
+        };
+
+        if failed_attempts >= self.config.max_verification_attempts {
+            return Err(AppError::TooManyVerificationAttempts);
+        }
+
         let result = sqlx::query!(
             r#"
             SELECT id, expires_at, used_at
@@ -80,12 +150,19 @@ impl VerificationService {
             LIMIT 1
             "#,
             user_id,
-            code,
+            self.hash_code(code),
             code_type.as_str()
         )
-        .fetch_optional(&self.db)
-        .await?
-        .ok_or(AppError::InvalidVerificationCode)?;
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(result) = result else {
+            let error = self
+                .record_failed_attempt(&mut tx, user_id, &code_type, failed_attempts + 1)
+                .await?;
+            tx.commit().await?;
+            return Err(error);
+        };
 
         // Check if already used
         if result.used_at.is_some() {
@@ -97,20 +174,115 @@ impl VerificationService {
             return Err(AppError::VerificationCodeExpired);
         }
 
-        // Mark as used
-        sqlx::query!(
+        // Mark as used - only one request may redeem the code
+        let updated = sqlx::query!(
             r#"
             UPDATE verification_code
             SET used_at = $1
-            WHERE id = $2
+            WHERE id = $2 AND used_at IS NULL
             "#,
             Utc::now(),
             result.id
         )
-        .execute(&self.db)
+        .execute(&mut *tx)
+        .await?;
+
+        if updated.rows_affected() == 0 {
+            return Err(AppError::VerificationCodeAlreadyUsed);
+        }
+
+        sqlx::query!(
+            "DELETE FROM verification_attempts WHERE user_id = $1 AND code_type = $2",
+            user_id,
+            code_type.as_str()
+        )
+        .execute(&mut *tx)
         .await?;
 
+        tx.commit().await?;
+
         Ok(())
     }
 
-}
\ No newline at end of file
+    /// Store a wrong code in the locked counter and return the error to report.
+    /// On the last allowed miss every outstanding code of this type is expired
+    /// and the counter starts over.
+    async fn record_failed_attempt(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        code_type: &CodeType,
+        failed_attempts: i32,
+    ) -> Result<AppError> {
+        if failed_attempts < self.config.max_verification_attempts {
+            sqlx::query!(
+                r#"
+                UPDATE verification_attempts
+                SET failed_attempts = $3, last_failed_at = NOW()
+                WHERE user_id = $1 AND code_type = $2
+                "#,
+                user_id,
+                code_type.as_str(),
+                failed_attempts
+            )
+            .execute(&mut **tx)
+            .await?;
+
+            return Ok(AppError::InvalidVerificationCode);
+        }
+
+        tracing::warn!(
+            "Invalidating {} codes for user {} after {} wrong attempts",
+            code_type.as_str(),
+            user_id,
+            failed_attempts
+        );
+
+        sqlx::query!(
+            r#"
+            UPDATE verification_code
+            SET expires_at = NOW()
+            WHERE user_id = $1 AND code_type = $2
+              AND used_at IS NULL AND expires_at > NOW()
+            "#,
+            user_id,
+            code_type.as_str()
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM verification_attempts WHERE user_id = $1 AND code_type = $2",
+            user_id,
+            code_type.as_str()
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(AppError::TooManyVerificationAttempts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    #[test]
+    fn code_hash_depends_on_the_key() {
+        let hash = code_hash(b"secret-a", "123456");
+
+        assert_eq!(hash, code_hash(b"secret-a", "123456"));
+        assert_ne!(hash, code_hash(b"secret-b", "123456"));
+        assert_ne!(hash, code_hash(b"secret-a", "123457"));
+        assert_ne!(hash, format!("{:x}", Sha256::digest(b"123456")));
+    }
+
+    #[test]
+    fn code_hash_fits_the_code_column() {
+        let hash = code_hash(b"secret", "123456");
+
+        assert_eq!(hash.len(), 64);
+        assert!(hash.bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+}
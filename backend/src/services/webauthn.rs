@@ -0,0 +1,822 @@
+use crate::{
+    config::Config,
+    error::{AppError, Result},
+    models::{
+        AuthenticationCredential, AuthenticatorSelection, CredentialDescriptor, PasskeyResponse,
+        PasskeyUserEntity, PubKeyCredParam, PublicKeyCredentialCreationOptions,
+        PublicKeyCredentialRequestOptions, RegistrationCredential, RelyingParty,
+    },
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+use rand::RngCore;
+use redis::{aio::ConnectionManager, AsyncCommands};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// How long a started ceremony stays valid
+const CEREMONY_TIMEOUT_SECS: u64 = 300;
+
+/// COSE algorithm identifiers we accept (RFC 9053)
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_EDDSA: i64 = -8;
+const COSE_ALG_RS256: i64 = -257;
+
+/// Authenticator data flag bits
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    sign_count: u32,
+    attested_credential: Option<AttestedCredential>,
+}
+
+struct AttestedCredential {
+    credential_id: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+/// A credential public key decoded from its COSE_Key encoding
+enum CoseKey {
+    Es256 { point: Vec<u8> },
+    EdDsa { x: Vec<u8> },
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+impl CoseKey {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let value: Value = ciborium::de::from_reader(bytes)
+            .map_err(|_| verification_failed("malformed credential public key"))?;
+        let map = value
+            .as_map()
+            .ok_or_else(|| verification_failed("credential public key is not a map"))?;
+
+        let param = |label: i64| {
+            map.iter()
+                .find(|(k, _)| k.as_integer().map(i128::from) == Some(label as i128))
+                .map(|(_, v)| v)
+        };
+        let int_param = |label: i64| param(label).and_then(|v| v.as_integer()).map(i128::from);
+        let bytes_param = |label: i64| {
+            param(label)
+                .and_then(|v| v.as_bytes())
+                .cloned()
+                .ok_or_else(|| verification_failed("credential public key is missing a parameter"))
+        };
+
+        // kty = 1, alg = 3, then key-type specific parameters
+        match (int_param(1), int_param(3).map(|a| a as i64)) {
+            (Some(2), Some(COSE_ALG_ES256)) if int_param(-1) == Some(1) => {
+                let (x, y) = (bytes_param(-2)?, bytes_param(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(verification_failed("invalid P-256 coordinates"));
+                }
+                let mut point = Vec::with_capacity(65);
+                point.push(0x04);
+                point.extend_from_slice(&x);
+                point.extend_from_slice(&y);
+                Ok(CoseKey::Es256 { point })
+            }
+            (Some(1), Some(COSE_ALG_EDDSA)) if int_param(-1) == Some(6) => {
+                let x = bytes_param(-2)?;
+                if x.len() != 32 {
+                    return Err(verification_failed("invalid Ed25519 public key"));
+                }
+                Ok(CoseKey::EdDsa { x })
+            }
+            (Some(3), Some(COSE_ALG_RS256)) => Ok(CoseKey::Rs256 {
+                n: bytes_param(-1)?,
+                e: bytes_param(-2)?,
+            }),
+            _ => Err(verification_failed("unsupported credential algorithm")),
+        }
+    }
+
+    fn algorithm(&self) -> i64 {
+        match self {
+            CoseKey::Es256 { .. } => COSE_ALG_ES256,
+            CoseKey::EdDsa { .. } => COSE_ALG_EDDSA,
+            CoseKey::Rs256 { .. } => COSE_ALG_RS256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> Result<()> {
+        let result = match self {
+            CoseKey::Es256 { point } => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                    .verify(message, sig)
+            }
+            CoseKey::EdDsa { x } => {
+                UnparsedPublicKey::new(&signature::ED25519, x).verify(message, sig)
+            }
+            CoseKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                message,
+                sig,
+            ),
+        };
+
+        result.map_err(|_| verification_failed("invalid signature"))
+    }
+}
+
+fn verification_failed(reason: &str) -> AppError {
+    AppError::PasskeyVerificationFailed(reason.to_string())
+}
+
+fn decode_b64url(value: &str, what: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| AppError::BadRequest(format!("Invalid base64url in {}", what)))
+}
+
+fn random_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Check type and origin of the client data, returning the signed challenge
+fn verify_client_data(
+    client_data_json: &[u8],
+    expected_type: &str,
+    expected_origin: &str,
+) -> Result<String> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| verification_failed("malformed client data"))?;
+
+    if client_data.type_ != expected_type {
+        return Err(verification_failed("unexpected ceremony type"));
+    }
+
+    if client_data.origin.trim_end_matches('/') != expected_origin.trim_end_matches('/') {
+        return Err(verification_failed("origin mismatch"));
+    }
+
+    Ok(client_data.challenge)
+}
+
+/// Parse authenticator data and check the RP ID hash and required flags
+fn parse_authenticator_data(data: &[u8], rp_id: &str) -> Result<AuthenticatorData> {
+    if data.len() < 37 {
+        return Err(verification_failed("authenticator data too short"));
+    }
+
+    let rp_id_hash = Sha256::digest(rp_id.as_bytes());
+    if data[..32] != rp_id_hash[..] {
+        return Err(verification_failed("RP ID mismatch"));
+    }
+
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 || flags & FLAG_USER_VERIFIED == 0 {
+        return Err(verification_failed(
+            "user presence and verification are required",
+        ));
+    }
+
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        // aaguid (16) | credential id length (2) | credential id | COSE key
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(verification_failed("attested credential data too short"));
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < id_len {
+            return Err(verification_failed("credential id truncated"));
+        }
+        let (credential_id, key_and_extensions) = rest.split_at(id_len);
+
+        // The COSE key is followed by optional extensions, so measure it by decoding
+        let mut remaining = key_and_extensions;
+        let _: Value = ciborium::de::from_reader(&mut remaining)
+            .map_err(|_| verification_failed("malformed credential public key"))?;
+        let key_len = key_and_extensions.len() - remaining.len();
+
+        Some(AttestedCredential {
+            credential_id: credential_id.to_vec(),
+            public_key: key_and_extensions[..key_len].to_vec(),
+        })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        sign_count,
+        attested_credential,
+    })
+}
+
+/// Check an assertion signature, which covers authenticatorData || SHA-256(clientDataJSON)
+fn verify_assertion(
+    public_key: &[u8],
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    sig: &[u8],
+) -> Result<()> {
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    CoseKey::parse(public_key)?.verify(&signed, sig)
+}
+
+/// WebAuthn relying party: passkey registration, login and management.
+/// Attestation statements are not verified - we request `"none"` attestation,
+/// so the credential is trusted on first use like any other passkey.
+#[derive(Clone)]
+pub struct WebAuthnService {
+    db: PgPool,
+    redis: ConnectionManager,
+    config: Config,
+}
+
+impl WebAuthnService {
+    pub fn new(db: PgPool, redis: ConnectionManager, config: Config) -> Self {
+        Self { db, redis, config }
+    }
+
+    fn credential_params() -> Vec<PubKeyCredParam> {
+        [COSE_ALG_ES256, COSE_ALG_EDDSA, COSE_ALG_RS256]
+            .into_iter()
+            .map(|alg| PubKeyCredParam {
+                type_: "public-key".into(),
+                alg,
+            })
+            .collect()
+    }
+
+    /// Atomically take a pending ceremony challenge out of Redis
+    async fn take_challenge(&self, key: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.clone();
+        let value: Option<String> = conn.get_del(key).await.map_err(AppError::Redis)?;
+        Ok(value)
+    }
+
+    /// Start a registration ceremony for a signed-in user
+    pub async fn start_registration(
+        &self,
+        user_id: Uuid,
+        email: &str,
+    ) -> Result<PublicKeyCredentialCreationOptions> {
+        let challenge = random_challenge();
+
+        let mut conn = self.redis.clone();
+        let key = format!("webauthn:registration:{}", challenge);
+        let _: () = conn
+            .set_ex(&key, user_id.to_string(), CEREMONY_TIMEOUT_SECS)
+            .await
+            .map_err(AppError::Redis)?;
+
+        let existing = sqlx::query!(
+            r#"
+            SELECT credential_id
+            FROM webauthn_credentials
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(PublicKeyCredentialCreationOptions {
+            rp: RelyingParty {
+                id: self.config.webauthn_rp_id.clone(),
+                name: self.config.webauthn_rp_name.clone(),
+            },
+            user: PasskeyUserEntity {
+                id: URL_SAFE_NO_PAD.encode(user_id.as_bytes()),
+                name: email.to_string(),
+                display_name: email.to_string(),
+            },
+            challenge,
+            pub_key_cred_params: Self::credential_params(),
+            timeout: CEREMONY_TIMEOUT_SECS * 1000,
+            attestation: "none".into(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "required".into(),
+                user_verification: "required".into(),
+            },
+            exclude_credentials: existing
+                .into_iter()
+                .map(|row| CredentialDescriptor {
+                    type_: "public-key".into(),
+                    id: URL_SAFE_NO_PAD.encode(row.credential_id),
+                })
+                .collect(),
+        })
+    }
+
+    /// Finish a registration ceremony and store the new credential
+    pub async fn finish_registration(
+        &self,
+        user_id: Uuid,
+        name: Option<String>,
+        credential: &RegistrationCredential,
+    ) -> Result<PasskeyResponse> {
+        if credential.type_ != "public-key" {
+            return Err(verification_failed("unexpected credential type"));
+        }
+
+        let client_data_json =
+            decode_b64url(&credential.response.client_data_json, "clientDataJSON")?;
+        let challenge = verify_client_data(
+            &client_data_json,
+            "webauthn.create",
+            &self.config.webauthn_origin,
+        )?;
+
+        let owner = self
+            .take_challenge(&format!("webauthn:registration:{}", challenge))
+            .await?;
+        if owner != Some(user_id.to_string()) {
+            return Err(verification_failed("unknown or expired challenge"));
+        }
+
+        let attestation_object =
+            decode_b64url(&credential.response.attestation_object, "attestationObject")?;
+        let attestation: Value = ciborium::de::from_reader(attestation_object.as_slice())
+            .map_err(|_| verification_failed("malformed attestation object"))?;
+        let auth_data = attestation
+            .as_map()
+            .and_then(|map| {
+                map.iter()
+                    .find(|(k, _)| k.as_text() == Some("authData"))
+                    .and_then(|(_, v)| v.as_bytes())
+            })
+            .ok_or_else(|| verification_failed("attestation object has no authData"))?;
+
+        let auth_data = parse_authenticator_data(auth_data, &self.config.webauthn_rp_id)?;
+        let attested = auth_data
+            .attested_credential
+            .ok_or_else(|| verification_failed("no attested credential data"))?;
+
+        if attested.credential_id != decode_b64url(&credential.raw_id, "rawId")? {
+            return Err(verification_failed("credential id mismatch"));
+        }
+
+        let algorithm = CoseKey::parse(&attested.public_key)?.algorithm();
+        let name = name.unwrap_or_else(|| "Passkey".to_string());
+
+        let row = sqlx::query!(
+            r#"
+            INSERT INTO webauthn_credentials
+                (user_id, credential_id, public_key, algorithm, sign_count, name)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, created_at, last_used_at
+            "#,
+            user_id,
+            attested.credential_id,
+            attested.public_key,
+            algorithm as i32,
+            i64::from(auth_data.sign_count),
+            name
+        )
+        .fetch_one(&self.db)
+        .await
+        .map_err(|e| {
+            if let sqlx::Error::Database(db_err) = &e {
+                if db_err.is_unique_violation() {
+                    return AppError::BadRequest("Passkey already registered".into());
+                }
+            }
+            AppError::Database(e)
+        })?;
+
+        Ok(PasskeyResponse {
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        })
+    }
+
+    /// Start a login ceremony. Passkeys are discoverable, so no user is named up front.
+    pub async fn start_authentication(&self) -> Result<PublicKeyCredentialRequestOptions> {
+        let challenge = random_challenge();
+
+        let mut conn = self.redis.clone();
+        let key = format!("webauthn:authentication:{}", challenge);
+        let _: () = conn
+            .set_ex(&key, "1", CEREMONY_TIMEOUT_SECS)
+            .await
+            .map_err(AppError::Redis)?;
+
+        Ok(PublicKeyCredentialRequestOptions {
+            challenge,
+            timeout: CEREMONY_TIMEOUT_SECS * 1000,
+            rp_id: self.config.webauthn_rp_id.clone(),
+            user_verification: "required".into(),
+            allow_credentials: Vec::new(),
+        })
+    }
+
+    /// Finish a login ceremony, returning the user the passkey belongs to
+    pub async fn finish_authentication(
+        &self,
+        credential: &AuthenticationCredential,
+    ) -> Result<Uuid> {
+        if credential.type_ != "public-key" {
+            return Err(verification_failed("unexpected credential type"));
+        }
+
+        let client_data_json =
+            decode_b64url(&credential.response.client_data_json, "clientDataJSON")?;
+        let challenge = verify_client_data(
+            &client_data_json,
+            "webauthn.get",
+            &self.config.webauthn_origin,
+        )?;
+
+        if self
+            .take_challenge(&format!("webauthn:authentication:{}", challenge))
+            .await?
+            .is_none()
+        {
+            return Err(verification_failed("unknown or expired challenge"));
+        }
+
+        let credential_id = decode_b64url(&credential.raw_id, "rawId")?;
+        let stored = sqlx::query!(
+            r#"
+            SELECT id, user_id, public_key, sign_count
+            FROM webauthn_credentials
+            WHERE credential_id = $1
+            "#,
+            credential_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or_else(|| verification_failed("unknown credential"))?;
+
+        if let Some(user_handle) = &credential.response.user_handle {
+            if decode_b64url(user_handle, "userHandle")? != stored.user_id.as_bytes() {
+                return Err(verification_failed("user handle mismatch"));
+            }
+        }
+
+        let authenticator_data =
+            decode_b64url(&credential.response.authenticator_data, "authenticatorData")?;
+        let auth_data = parse_authenticator_data(&authenticator_data, &self.config.webauthn_rp_id)?;
+
+        let sig = decode_b64url(&credential.response.signature, "signature")?;
+        verify_assertion(&stored.public_key, &authenticator_data, &client_data_json, &sig)?;
+
+        // Authenticators that keep a counter must always increase it
+        let sign_count = i64::from(auth_data.sign_count);
+        if (sign_count != 0 || stored.sign_count != 0) && sign_count <= stored.sign_count {
+            return Err(verification_failed(
+                "signature counter did not increase (possible cloned authenticator)",
+            ));
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE webauthn_credentials
+            SET sign_count = $1, last_used_at = NOW()
+            WHERE id = $2
+            "#,
+            sign_count,
+            stored.id
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(stored.user_id)
+    }
+
+    /// List the user's passkeys
+    pub async fn list_credentials(&self, user_id: Uuid) -> Result<Vec<PasskeyResponse>> {
+        let passkeys = sqlx::query_as!(
+            PasskeyResponse,
+            r#"
+            SELECT id, name, created_at, last_used_at
+            FROM webauthn_credentials
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(passkeys)
+    }
+
+    /// Rename one of the user's passkeys
+    pub async fn rename_credential(
+        &self,
+        user_id: Uuid,
+        passkey_id: Uuid,
+        name: &str,
+    ) -> Result<PasskeyResponse> {
+        let passkey = sqlx::query_as!(
+            PasskeyResponse,
+            r#"
+            UPDATE webauthn_credentials
+            SET name = $1
+            WHERE id = $2 AND user_id = $3
+            RETURNING id, name, created_at, last_used_at
+            "#,
+            name,
+            passkey_id,
+            user_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .ok_or(AppError::PasskeyNotFound)?;
+
+        Ok(passkey)
+    }
+
+    /// Delete one of the user's passkeys
+    pub async fn delete_credential(&self, user_id: Uuid, passkey_id: Uuid) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM webauthn_credentials
+            WHERE id = $1 AND user_id = $2
+            "#,
+            passkey_id,
+            user_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::PasskeyNotFound);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+
+    const RP_ID: &str = "localhost";
+    const ORIGIN: &str = "http://localhost:3000";
+
+    fn cose_key(params: Vec<(i64, Value)>) -> Vec<u8> {
+        let map = params
+            .into_iter()
+            .map(|(label, value)| (Value::Integer(label.into()), value))
+            .collect();
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&Value::Map(map), &mut bytes).unwrap();
+        bytes
+    }
+
+    enum SoftwareKey {
+        Es256(EcdsaKeyPair),
+        EdDsa(Ed25519KeyPair),
+    }
+
+    /// A platform authenticator in memory: one credential, a signature counter,
+    /// and user presence and verification always asserted
+    struct SoftwareAuthenticator {
+        credential_id: Vec<u8>,
+        key: SoftwareKey,
+        sign_count: u32,
+        rng: SystemRandom,
+    }
+
+    impl SoftwareAuthenticator {
+        fn es256() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            Self::with_key(SoftwareKey::Es256(key), rng)
+        }
+
+        fn ed25519() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            Self::with_key(SoftwareKey::EdDsa(key), rng)
+        }
+
+        fn with_key(key: SoftwareKey, rng: SystemRandom) -> Self {
+            let mut credential_id = vec![0u8; 16];
+            rand::thread_rng().fill_bytes(&mut credential_id);
+            Self {
+                credential_id,
+                key,
+                sign_count: 0,
+                rng,
+            }
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            match &self.key {
+                SoftwareKey::Es256(key) => {
+                    let point = key.public_key().as_ref();
+                    cose_key(vec![
+                        (1, Value::Integer(2.into())),
+                        (3, Value::Integer(COSE_ALG_ES256.into())),
+                        (-1, Value::Integer(1.into())),
+                        (-2, Value::Bytes(point[1..33].to_vec())),
+                        (-3, Value::Bytes(point[33..].to_vec())),
+                    ])
+                }
+                SoftwareKey::EdDsa(key) => cose_key(vec![
+                    (1, Value::Integer(1.into())),
+                    (3, Value::Integer(COSE_ALG_EDDSA.into())),
+                    (-1, Value::Integer(6.into())),
+                    (-2, Value::Bytes(key.public_key().as_ref().to_vec())),
+                ]),
+            }
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8, attested: bool) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if attested {
+                data.extend_from_slice(&[0u8; 16]);
+                data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.credential_id);
+                data.extend_from_slice(&self.public_key());
+            }
+            data
+        }
+
+        /// The authenticatorData of a registration, as it sits in a "none" attestation object
+        fn register(&self) -> Vec<u8> {
+            self.authenticator_data(
+                RP_ID,
+                FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_CREDENTIAL_DATA,
+                true,
+            )
+        }
+
+        /// Sign a login, returning (authenticatorData, clientDataJSON, signature)
+        fn assert(&mut self, challenge: &str, flags: u8) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+            self.sign_count += 1;
+            let client_data = client_data("webauthn.get", challenge, ORIGIN);
+            let authenticator_data = self.authenticator_data(RP_ID, flags, false);
+
+            let mut signed = authenticator_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data));
+            let sig = match &self.key {
+                SoftwareKey::Es256(key) => key.sign(&self.rng, &signed).unwrap().as_ref().to_vec(),
+                SoftwareKey::EdDsa(key) => key.sign(&signed).as_ref().to_vec(),
+            };
+
+            (authenticator_data, client_data, sig)
+        }
+    }
+
+    fn client_data(type_: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": type_,
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        }))
+        .unwrap()
+    }
+
+    const SIGNED_IN: u8 = FLAG_USER_PRESENT | FLAG_USER_VERIFIED;
+
+    #[test]
+    fn cose_key_parses_supported_algorithms() {
+        let es256 = CoseKey::parse(&SoftwareAuthenticator::es256().public_key()).unwrap();
+        assert_eq!(es256.algorithm(), COSE_ALG_ES256);
+
+        let eddsa = CoseKey::parse(&SoftwareAuthenticator::ed25519().public_key()).unwrap();
+        assert_eq!(eddsa.algorithm(), COSE_ALG_EDDSA);
+
+        let rs256 = CoseKey::parse(&cose_key(vec![
+            (1, Value::Integer(3.into())),
+            (3, Value::Integer(COSE_ALG_RS256.into())),
+            (-1, Value::Bytes(vec![0xc5; 256])),
+            (-2, Value::Bytes(vec![0x01, 0x00, 0x01])),
+        ]))
+        .unwrap();
+        assert_eq!(rs256.algorithm(), COSE_ALG_RS256);
+    }
+
+    #[test]
+    fn cose_key_rejects_unsupported_or_malformed_keys() {
+        // ES384
+        assert!(CoseKey::parse(&cose_key(vec![
+            (1, Value::Integer(2.into())),
+            (3, Value::Integer((-35).into())),
+            (-1, Value::Integer(2.into())),
+        ]))
+        .is_err());
+
+        // ES256 on the wrong curve
+        assert!(CoseKey::parse(&cose_key(vec![
+            (1, Value::Integer(2.into())),
+            (3, Value::Integer(COSE_ALG_ES256.into())),
+            (-1, Value::Integer(2.into())),
+            (-2, Value::Bytes(vec![1; 32])),
+            (-3, Value::Bytes(vec![1; 32])),
+        ]))
+        .is_err());
+
+        // Truncated coordinates
+        assert!(CoseKey::parse(&cose_key(vec![
+            (1, Value::Integer(2.into())),
+            (3, Value::Integer(COSE_ALG_ES256.into())),
+            (-1, Value::Integer(1.into())),
+            (-2, Value::Bytes(vec![1; 31])),
+            (-3, Value::Bytes(vec![1; 32])),
+        ]))
+        .is_err());
+
+        // EdDSA without its public key
+        assert!(CoseKey::parse(&cose_key(vec![
+            (1, Value::Integer(1.into())),
+            (3, Value::Integer(COSE_ALG_EDDSA.into())),
+            (-1, Value::Integer(6.into())),
+        ]))
+        .is_err());
+
+        assert!(CoseKey::parse(b"not cbor").is_err());
+    }
+
+    #[test]
+    fn registration_yields_the_credential_id_and_key() {
+        let authenticator = SoftwareAuthenticator::es256();
+        let mut auth_data = authenticator.register();
+        // Extensions after the key must not end up in it
+        ciborium::ser::into_writer(
+            &Value::Map(vec![(Value::Text("credProtect".into()), Value::Integer(2.into()))]),
+            &mut auth_data,
+        )
+        .unwrap();
+
+        let parsed = parse_authenticator_data(&auth_data, RP_ID).unwrap();
+        let attested = parsed.attested_credential.unwrap();
+        assert_eq!(attested.credential_id, authenticator.credential_id);
+        assert_eq!(attested.public_key, authenticator.public_key());
+        assert_eq!(parsed.sign_count, 0);
+    }
+
+    #[test]
+    fn authenticator_data_must_name_our_rp_and_verify_the_user() {
+        let authenticator = SoftwareAuthenticator::ed25519();
+
+        let other_rp = authenticator.authenticator_data("evil.example", SIGNED_IN, false);
+        assert!(parse_authenticator_data(&other_rp, RP_ID).is_err());
+
+        let presence_only = authenticator.authenticator_data(RP_ID, FLAG_USER_PRESENT, false);
+        assert!(parse_authenticator_data(&presence_only, RP_ID).is_err());
+
+        assert!(parse_authenticator_data(&[0u8; 36], RP_ID).is_err());
+    }
+
+    #[test]
+    fn client_data_must_match_ceremony_and_origin() {
+        let json = client_data("webauthn.get", "abc", ORIGIN);
+        assert_eq!(verify_client_data(&json, "webauthn.get", ORIGIN).unwrap(), "abc");
+        assert!(verify_client_data(&json, "webauthn.create", ORIGIN).is_err());
+        assert!(verify_client_data(&json, "webauthn.get", "https://evil.example").is_err());
+        assert!(verify_client_data(b"{}", "webauthn.get", ORIGIN).is_err());
+    }
+
+    #[test]
+    fn assertions_from_a_software_authenticator_verify() {
+        for mut authenticator in [SoftwareAuthenticator::es256(), SoftwareAuthenticator::ed25519()] {
+            let public_key = authenticator.public_key();
+
+            let (auth_data, client_data_json, sig) = authenticator.assert("challenge-1", SIGNED_IN);
+            verify_assertion(&public_key, &auth_data, &client_data_json, &sig).unwrap();
+            assert_eq!(parse_authenticator_data(&auth_data, RP_ID).unwrap().sign_count, 1);
+
+            // The signature covers the client data, so the challenge can't be swapped
+            let swapped = client_data("webauthn.get", "challenge-2", ORIGIN);
+            assert!(verify_assertion(&public_key, &auth_data, &swapped, &sig).is_err());
+
+            // ...and the authenticator data, so the counter can't be bumped
+            let mut bumped = auth_data.clone();
+            bumped[36] += 1;
+            assert!(verify_assertion(&public_key, &bumped, &client_data_json, &sig).is_err());
+
+            // Another authenticator's key doesn't verify it
+            let other = SoftwareAuthenticator::ed25519().public_key();
+            assert!(verify_assertion(&other, &auth_data, &client_data_json, &sig).is_err());
+        }
+    }
+}
@@ -1,11 +1,23 @@
 use crate::{
     config::Config,
+    rate_limit::RateLimiter,
     services::{
         jwt::JwtService,
         token::TokenService,
         users::UserService,         // ✅ fixed: plural `users`
         email::EmailService,
         verification::VerificationService,
+        mfa::MfaService,
+        webauthn::WebAuthnService,
+        login_attempts::LoginAttemptService,
+        oauth::OAuthService,
+        identities::IdentityService,
+        invitations::InvitationService,
+        oauth_server::OAuthServerService,
+        organizations::OrganizationService,
+        personal_tokens::PersonalTokenService,
+        roles::RoleService,
+        security_events::SecurityEventService,
     },
 };
 
@@ -17,4 +29,16 @@ pub struct AppState {
     pub user_service: UserService,
     pub email_service: EmailService,
     pub verification_service: VerificationService,
+    pub mfa_service: MfaService,
+    pub webauthn_service: WebAuthnService,
+    pub login_attempt_service: LoginAttemptService,
+    pub oauth_service: OAuthService,
+    pub identity_service: IdentityService,
+    pub oauth_server_service: OAuthServerService,
+    pub personal_token_service: PersonalTokenService,
+    pub role_service: RoleService,
+    pub organization_service: OrganizationService,
+    pub invitation_service: InvitationService,
+    pub security_event_service: SecurityEventService,
+    pub rate_limiter: RateLimiter,
 }
@@ -0,0 +1,250 @@
+//! Stub OpenID Connect provider, shared by `examples/stub_idp.rs` and the
+//! sign-in tests in `services::oauth`.
+//!
+//! It serves discovery, a JWKS and Ed25519-signed ID tokens, and checks PKCE
+//! and the redirect URI the way a real IdP does. There is no login page: every
+//! authorization signs in as the configured email.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use rand::RngCore;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// An authorization waiting to be redeemed at the token endpoint
+struct PendingCode {
+    code_challenge: String,
+    redirect_uri: String,
+    nonce: Option<String>,
+}
+
+/// The provider's state: who signs in, its signing key and the codes and
+/// access tokens it has handed out
+#[derive(Clone)]
+pub struct Stub {
+    issuer: String,
+    client_id: String,
+    email: String,
+    email_verified: bool,
+    encoding_key: EncodingKey,
+    public_key: String,
+    /// Derived from the key, so a restarted stub looks like a key rotation
+    key_id: String,
+    codes: Arc<Mutex<HashMap<String, PendingCode>>>,
+    access_tokens: Arc<Mutex<HashMap<String, ()>>>,
+}
+
+impl Stub {
+    /// A provider at `issuer` that signs everyone in as `email`, with a fresh
+    /// Ed25519 key
+    pub fn new(
+        issuer: String,
+        client_id: String,
+        email: String,
+        email_verified: bool,
+    ) -> anyhow::Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("key generation failed"))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|_| anyhow::anyhow!("key generation failed"))?;
+
+        let public_key = URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref());
+
+        Ok(Self {
+            issuer,
+            client_id,
+            email,
+            email_verified,
+            encoding_key: EncodingKey::from_ed_der(pkcs8.as_ref()),
+            key_id: public_key[..12].to_string(),
+            public_key,
+            codes: Arc::default(),
+            access_tokens: Arc::default(),
+        })
+    }
+
+    /// The subject every ID token and userinfo response names
+    pub fn subject(&self) -> String {
+        format!("{:x}", Sha256::digest(self.email.as_bytes()))[..24].to_string()
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    code: String,
+    code_verifier: String,
+    redirect_uri: String,
+    client_id: String,
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn oauth_error(error: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response()
+}
+
+async fn discovery(State(stub): State<Stub>) -> Json<Value> {
+    Json(json!({
+        "issuer": stub.issuer,
+        "authorization_endpoint": format!("{}/authorize", stub.issuer),
+        "token_endpoint": format!("{}/token", stub.issuer),
+        "userinfo_endpoint": format!("{}/userinfo", stub.issuer),
+        "jwks_uri": format!("{}/jwks", stub.issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+async fn jwks(State(stub): State<Stub>) -> Json<Value> {
+    Json(json!({
+        "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": stub.public_key,
+            "kid": stub.key_id,
+            "alg": "EdDSA",
+            "use": "sig",
+        }]
+    }))
+}
+
+async fn authorize(State(stub): State<Stub>, Query(query): Query<AuthorizeQuery>) -> Response {
+    if query.client_id != stub.client_id {
+        return oauth_error("unauthorized_client");
+    }
+    if query.code_challenge_method != "S256" {
+        return oauth_error("invalid_request");
+    }
+
+    let code = random_token();
+    stub.codes.lock().unwrap().insert(
+        code.clone(),
+        PendingCode {
+            code_challenge: query.code_challenge,
+            redirect_uri: query.redirect_uri.clone(),
+            nonce: query.nonce,
+        },
+    );
+
+    Redirect::to(&format!(
+        "{}?code={}&state={}",
+        query.redirect_uri,
+        code,
+        urlencoding::encode(&query.state)
+    ))
+    .into_response()
+}
+
+async fn token(State(stub): State<Stub>, Form(form): Form<TokenForm>) -> Response {
+    let Some(pending) = stub.codes.lock().unwrap().remove(&form.code) else {
+        return oauth_error("invalid_grant");
+    };
+
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes()));
+    if challenge != pending.code_challenge
+        || form.redirect_uri != pending.redirect_uri
+        || form.client_id != stub.client_id
+    {
+        return oauth_error("invalid_grant");
+    }
+
+    let now = Utc::now().timestamp();
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some(stub.key_id.clone());
+
+    let id_token = match encode(
+        &header,
+        &json!({
+            "iss": stub.issuer,
+            "sub": stub.subject(),
+            "aud": stub.client_id,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": pending.nonce,
+            "email": stub.email,
+            "email_verified": stub.email_verified,
+        }),
+        &stub.encoding_key,
+    ) {
+        Ok(token) => token,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let access_token = random_token();
+    stub.access_tokens
+        .lock()
+        .unwrap()
+        .insert(access_token.clone(), ());
+
+    Json(json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "id_token": id_token,
+    }))
+    .into_response()
+}
+
+async fn userinfo(State(stub): State<Stub>, headers: HeaderMap) -> Response {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|token| stub.access_tokens.lock().unwrap().contains_key(token));
+
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    Json(json!({
+        "sub": stub.subject(),
+        "email": stub.email,
+        "email_verified": stub.email_verified,
+        "name": "Stub User",
+    }))
+    .into_response()
+}
+
+/// Discovery, JWKS, authorization, token and userinfo endpoints
+pub fn router(stub: Stub) -> Router {
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/userinfo", get(userinfo))
+        .with_state(stub)
+}
@@ -1 +1,2 @@
-pub mod cleanup_expired_tokens;
\ No newline at end of file
+pub mod cleanup_expired_tokens;
+pub mod refresh_signing_keys;
\ No newline at end of file
@@ -0,0 +1,22 @@
+use crate::services::{
+    jwt::JwtService,
+    signing_keys::{SigningKeyStore, KEY_REFRESH_INTERVAL_SECS},
+};
+use std::time::Duration;
+
+pub fn start_signing_key_refresh_task(jwt_service: JwtService, store: SigningKeyStore) {
+    tokio::spawn(async move {
+        // Pick up promotions made by `backend keys promote` within a minute
+        let mut interval = tokio::time::interval(Duration::from_secs(KEY_REFRESH_INTERVAL_SECS as u64));
+
+        tracing::info!("Signing key refresh task started - running every minute");
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = jwt_service.reload_keys(&store).await {
+                tracing::error!("Failed to reload JWT signing keys: {:?}", e);
+            }
+        }
+    });
+}